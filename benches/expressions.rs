@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use evalexpr::{
+    eval_number_with_context, ContextWithMutableVariables, HashMapContext,
+    Value,
+};
+use hex_sim::util::CompiledExpr;
+
+const DAYS_IN_YEAR: i64 = 365;
+const EXPR: &str = "max(math::exp(-(d - 60)^2 / 1000), \
+                    math::exp(-(d - 200)^2 / 1000)) * 10 + 1";
+
+fn eval_from_string() -> f64 {
+    (0..DAYS_IN_YEAR)
+        .map(|day| {
+            let mut context = HashMapContext::new();
+            context.set_value("d".into(), Value::from(day)).unwrap();
+            eval_number_with_context(EXPR, &context).unwrap()
+        })
+        .sum()
+}
+
+fn eval_compiled() -> f64 {
+    let expr = CompiledExpr::new(EXPR).unwrap();
+    let mut context = HashMapContext::new();
+    (0..DAYS_IN_YEAR)
+        .map(|day| {
+            expr.eval_non_negative(&mut context, &[("d", Value::from(day))])
+                .unwrap()
+        })
+        .sum()
+}
+
+fn bench_daily_multipliers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("daily_multipliers");
+    group.bench_function("from_string", |b| {
+        b.iter(|| black_box(eval_from_string()))
+    });
+    group.bench_function("compiled", |b| b.iter(|| black_box(eval_compiled())));
+    group.finish();
+}
+
+criterion_group!(benches, bench_daily_multipliers);
+criterion_main!(benches);
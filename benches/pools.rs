@@ -0,0 +1,90 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, Criterion,
+};
+use hex_sim::{AccountsPool, PoolKind, SimConfig, Transaction};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+const SHOPS_NUMBER: usize = 100;
+const BATCHES_NUMBER: usize = 100;
+const TRANSACTIONS_PER_BATCH: usize = 1000;
+
+const CONFIG: &str = r#"{
+    "simulated_shops_number": 100,
+    "simulated_regions_number": 3,
+    "simulated_years_number": 1,
+    "shop_size_distribution": {"type": "normal", "mean": 1, "std": 0.5},
+    "sales_per_year_for_each_shop": 5,
+    "sale_multiplier": 10,
+    "default_daily_multipliers": "1",
+    "default_daily_distribution": "5",
+    "price_distribution": {"type": "normal", "mean": 50, "std": 30},
+    "withdrawal_period_in_days": 30,
+    "capped_pool_max_accounts": 500
+}"#;
+
+/**
+ * Hourly batches of transactions spread uniformly over the shops.
+ */
+fn gen_batches() -> Vec<Vec<Transaction>> {
+    let mut rng = SmallRng::seed_from_u64(0);
+    (0..BATCHES_NUMBER)
+        .map(|_| {
+            (0..TRANSACTIONS_PER_BATCH)
+                .map(|_| Transaction {
+                    amount: rng.gen_range(1.0..100.0),
+                    shop_id: rng.gen_range(0..SHOPS_NUMBER),
+                    currency: 0,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn filled_pool(
+    kind: PoolKind,
+    config: &SimConfig,
+    batches: &[Vec<Transaction>],
+) -> Box<dyn AccountsPool + Send> {
+    let mut pool = kind.create(config).unwrap();
+    for batch in batches {
+        pool.process_transactions(batch).unwrap();
+    }
+    pool
+}
+
+fn bench_pools(c: &mut Criterion) {
+    let config: SimConfig = serde_json::from_str(CONFIG).unwrap();
+    let batches = gen_batches();
+
+    let mut group = c.benchmark_group("process_transactions");
+    for kind in PoolKind::ALL {
+        group.bench_function(kind.id(), |b| {
+            b.iter_batched(
+                || kind.create(&config).unwrap(),
+                |mut pool| {
+                    for batch in &batches {
+                        black_box(pool.process_transactions(batch).unwrap());
+                    }
+                    pool
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("withdraw_all");
+    for kind in PoolKind::ALL {
+        group.bench_function(kind.id(), |b| {
+            b.iter_batched(
+                || filled_pool(kind, &config, &batches),
+                |mut pool| black_box(pool.withdraw_all()),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pools);
+criterion_main!(benches);
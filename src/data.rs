@@ -1,55 +1,576 @@
-use rand_distr::Normal;
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use evalexpr::HashMapContext;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rand_distr::{Exp1, StandardNormal};
+use schemars::{
+    generate::SchemaSettings, json_schema, transform::RecursiveTransform,
+    JsonSchema, Schema, SchemaGenerator,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    hash::Hasher,
+    path::{Path, PathBuf},
+};
 
+use crate::pool::Withdrawal;
 use crate::util::{
-    deserialize_daily_multipliers, deserialize_daily_orders_distribution,
+    deserialize_daily_multipliers, deserialize_daily_orders_expr,
+    deserialize_distribution, deserialize_optional_daily_multipliers,
+    deserialize_optional_daily_orders_expr,
+    deserialize_optional_withdrawal_policy,
+    deserialize_simulated_currencies_number, deserialize_withdrawal_fee,
+    serialize_daily_multipliers, serialize_optional_daily_multipliers,
+    tidy_schema_description, CompiledExpr, StableHasher,
 };
 
 pub const HOURS_IN_DAY: usize = 24;
 pub const DAYS_IN_YEAR: usize = 365;
+pub const DAYS_IN_WEEK: usize = 7;
 
 pub type ShopId = usize;
+pub type RegionId = usize;
+pub type CurrencyId = usize;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Transaction {
     pub amount: f64,
     pub shop_id: ShopId,
+    /** Accounts only ever hold money of a single currency. */
+    pub currency: CurrencyId,
 }
 
-pub type DailyOrdersDistribution = [usize; HOURS_IN_DAY];
-pub type DailyMultipliers = [usize; DAYS_IN_YEAR];
+pub type DailyOrdersDistribution = [f64; HOURS_IN_DAY];
+pub type DailyMultipliers = [f64; DAYS_IN_YEAR];
+pub type WeekdayMultipliers = [f64; DAYS_IN_WEEK];
 
 #[derive(Clone, Copy, Debug)]
 pub struct AnnualOrdersDistribution {
     pub daily_multipliers: DailyMultipliers,
+    /**
+     * Expected number of orders of the shop at each hour, kept
+     * fractional so that the count is rounded only once per day.
+     */
     pub default_daily_distribution: DailyOrdersDistribution,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/**
+ * Probabilistic distribution of a positive quantity.
+ * Serialized as a tagged union, e.g.
+ * `{"type": "normal", "mean": 50.0, "std": 30.0}`.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Distribution {
+    /**
+     * Non-positive samples are rejected and resampled,
+     * so the mean must not be too far below zero.
+     */
+    Normal {
+        mean: f64,
+        #[serde(alias = "std_dev")]
+        std: f64,
+    },
+    /** `mu` and `sigma` are the parameters of the underlying normal. */
+    LogNormal {
+        mu: f64,
+        sigma: f64,
+    },
+    Uniform {
+        low: f64,
+        high: f64,
+    },
+    Exponential {
+        lambda: f64,
+    },
+}
+
+impl Distribution {
+    /**
+     * Checks that the parameters describe a valid distribution
+     * of positive values.
+     */
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            Distribution::Normal { mean, std } => {
+                mean.is_finite() && mean > 0.0 && std.is_finite() && std >= 0.0
+            }
+            Distribution::LogNormal { mu, sigma } => {
+                mu.is_finite() && sigma.is_finite() && sigma >= 0.0
+            }
+            Distribution::Uniform { low, high } => {
+                low.is_finite() && high.is_finite() && 0.0 <= low && low < high
+            }
+            Distribution::Exponential { lambda } => {
+                lambda.is_finite() && lambda > 0.0
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid distribution parameters: {:?}", self))
+        }
+    }
+}
+
+impl Distribution {
+    /**
+     * Draws a single value and returns it if it is positive.
+     * Unlike `sample`, never retries.
+     */
+    pub fn try_sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<f64> {
+        let value = match *self {
+            Distribution::Normal { mean, std } => {
+                let z: f64 = rng.sample(StandardNormal);
+                mean + std * z
+            }
+            Distribution::LogNormal { mu, sigma } => {
+                let z: f64 = rng.sample(StandardNormal);
+                (mu + sigma * z).exp()
+            }
+            Distribution::Uniform { low, high } => {
+                low + (high - low) * rng.gen::<f64>()
+            }
+            Distribution::Exponential { lambda } => {
+                let value: f64 = rng.sample(Exp1);
+                value / lambda
+            }
+        };
+        (value > 0.0).then_some(value)
+    }
+}
+
+impl rand_distr::Distribution<f64> for Distribution {
+    /**
+     * Non-positive values are rejected and resampled.
+     */
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        loop {
+            if let Some(value) = self.try_sample(rng) {
+                break value;
+            }
+        }
+    }
+}
+
+/**
+ * Expression of the number of orders of a shop at hour `h`.
+ *
+ * If it references `shop_size`, its value is the number of orders
+ * of a shop of that size. Otherwise it is rounded and then scaled
+ * by the shop size.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyOrdersExpr {
+    expr: CompiledExpr,
+    uses_shop_size: bool,
+}
+
+impl DailyOrdersExpr {
+    const HOUR_VAR: &'static str = "h";
+    const SHOP_SIZE_VAR: &'static str = "shop_size";
+
+    /**
+     * Fails if the expression is malformed
+     * or gives a negative number of orders for a shop of size 1.
+     */
+    pub fn new(expr: impl Into<String>) -> Result<Self, String> {
+        let expr = CompiledExpr::new(expr)?;
+        let uses_shop_size = expr.uses_variable(Self::SHOP_SIZE_VAR);
+        let daily_orders_expr = Self {
+            expr,
+            uses_shop_size,
+        };
+        daily_orders_expr.eval(1.0)?;
+        Ok(daily_orders_expr)
+    }
+
+    /**
+     * Returns the expected number of orders at each hour
+     * for a shop of the given size.
+     */
+    pub fn eval(
+        &self,
+        shop_size: f64,
+    ) -> Result<DailyOrdersDistribution, String> {
+        let mut context = HashMapContext::new();
+        let mut distribution = [0.0; HOURS_IN_DAY];
+        for (hour, orders) in distribution.iter_mut().enumerate() {
+            let hour_var = (Self::HOUR_VAR, (hour as i64).into());
+            *orders = if self.uses_shop_size {
+                self.expr.eval_non_negative(
+                    &mut context,
+                    &[hour_var, (Self::SHOP_SIZE_VAR, shop_size.into())],
+                )?
+            } else {
+                self.expr
+                    .eval_non_negative(&mut context, &[hour_var])?
+                    .round()
+                    * shop_size
+            };
+        }
+        Ok(distribution)
+    }
+}
+
+impl Serialize for DailyOrdersExpr {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(self.expr.as_str())
+    }
+}
+
+impl JsonSchema for DailyOrdersExpr {
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn schema_name() -> Cow<'static, str> {
+        "DailyOrdersExpr".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "description": "Expression of the hour `h` and, optionally, \
+                the shop size `shop_size`.",
+            "examples": [
+                "math::exp(-(h - 12)^2 / 20) * 5",
+                "shop_size * if(h >= 9 && h < 21, 2, 0)"
+            ]
+        })
+    }
+}
+
+/**
+ * Describes how the number of orders at a given hour
+ * deviates from its expected value.
+ */
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderCountNoise {
+    /** The number of orders is exactly the expected value. */
+    #[default]
+    None,
+    /** The number of orders is sampled from a Poisson distribution. */
+    Poisson,
+}
+
+/**
+ * Describes when money is withdrawn from the pools.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WithdrawalPolicy {
+    /** Money is withdrawn every `days` days. */
+    Periodic { days: usize },
+    /**
+     * Money is withdrawn from a pool at the end of a day
+     * when its total balance exceeds `amount`.
+     */
+    Threshold { amount: f64 },
+    /**
+     * Money is withdrawn from a pool `days` days after its last
+     * withdrawal or earlier, as soon as its total balance
+     * exceeds `amount`.
+     */
+    Combined { days: usize, amount: f64 },
+    /**
+     * Money is withdrawn every `hours` hours counted from the start
+     * of the simulation, also in the middle of a day.
+     */
+    Hourly { hours: usize },
+}
+
+/**
+ * Describes how much a single withdrawal of a pool costs.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WithdrawalFee {
+    /** Each withdrawal transaction costs `per_tx`. */
+    Flat { per_tx: f64 },
+    /** The given share of the withdrawn money. */
+    Percent { rate: f64 },
+    /**
+     * Pairs of a threshold and a rate, ordered by threshold.
+     * Each rate applies to the part of the withdrawn money
+     * between its threshold and the next one.
+     */
+    Tiered { tiers: Vec<(f64, f64)> },
+}
+
+impl Default for WithdrawalFee {
+    fn default() -> Self {
+        WithdrawalFee::Flat { per_tx: 0.0 }
+    }
+}
+
+impl WithdrawalFee {
+    pub fn fee(&self, withdrawal: &Withdrawal) -> f64 {
+        match self {
+            WithdrawalFee::Flat { per_tx } => {
+                withdrawal.total_transactions as f64 * per_tx
+            }
+            WithdrawalFee::Percent { rate } => withdrawal.total_money * rate,
+            WithdrawalFee::Tiered { tiers } => tiers
+                .iter()
+                .enumerate()
+                .map(|(i, &(threshold, rate))| {
+                    let upper = tiers
+                        .get(i + 1)
+                        .map_or(f64::INFINITY, |&(next, _)| next);
+                    let taxed = withdrawal.total_money.min(upper) - threshold;
+                    taxed.max(0.0) * rate
+                })
+                .sum(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let valid_number = |value: f64| value.is_finite() && value >= 0.0;
+        let valid = match self {
+            WithdrawalFee::Flat { per_tx } => valid_number(*per_tx),
+            WithdrawalFee::Percent { rate } => valid_number(*rate),
+            WithdrawalFee::Tiered { tiers } => {
+                tiers.iter().all(|&(threshold, rate)| {
+                    valid_number(threshold) && valid_number(rate)
+                }) && tiers.windows(2).all(|pair| pair[0].0 < pair[1].0)
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid withdrawal fee: {:?}", self))
+        }
+    }
+}
+
+/**
+ * Describes how shops are spread over the regions.
+ */
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq,
+)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RegionAssignment {
+    /** Shop `i` belongs to region `i % simulated_regions_number`. */
+    #[default]
+    RoundRobin,
+    /** Each shop belongs to a uniformly random region. */
+    Random { seed: u64 },
+    /**
+     * The region is the value of an expression of the shop id
+     * `shop_id`, rounded and taken modulo the number of regions.
+     */
+    ByExpression { expr: String },
+}
+
+/**
+ * Describes how the sale days of a shop are placed within a year.
+ */
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SaleTiming {
+    /**
+     * `sales_per_year_for_each_shop` uniformly random days,
+     * which may coincide.
+     */
+    #[default]
+    Uniform,
+    /**
+     * Sales are separated by gaps sampled from a geometric
+     * distribution of whole days with the given mean, starting
+     * from the beginning of the year, so there are about
+     * `DAYS_IN_YEAR / mean_gap_days` of them.
+     * `sales_per_year_for_each_shop` is ignored.
+     */
+    Spaced { mean_gap_days: f64 },
+}
+
+/**
+ * Maps the size of a shop to the size its orders are computed
+ * from. All the curves keep a shop of size 1 as it is.
+ */
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ShopSizeScaling {
+    /** Orders grow in proportion to the size. */
+    #[default]
+    Linear,
+    /**
+     * The size is raised to `exponent`. Below 1, large shops
+     * get fewer orders than their size suggests.
+     */
+    Power { exponent: f64 },
+    /** The size becomes `log2(1 + size)`. */
+    Log,
+}
+
+impl ShopSizeScaling {
+    pub fn apply(self, shop_size: f64) -> f64 {
+        match self {
+            ShopSizeScaling::Linear => shop_size,
+            ShopSizeScaling::Power { exponent } => shop_size.powf(exponent),
+            ShopSizeScaling::Log => shop_size.ln_1p() / 2f64.ln(),
+        }
+    }
+}
+
+/**
+ * A day of a year on which the demand of all shops
+ * is multiplied by the same value.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+pub struct HolidaySpec {
+    /** Day of a year, from 0 to `DAYS_IN_YEAR - 1`. */
+    pub day: usize,
+    pub multiplier: f64,
+}
+
+/**
+ * Pins some properties of a single shop instead of
+ * deriving them from the defaults shared by all shops.
+ */
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq,
+)]
+pub struct ShopOverride {
+    /** Size of the shop, used instead of a sampled one. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<f64>,
+
+    /** Orders expression used instead of `default_daily_distribution`. */
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_daily_orders_expr",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub daily_distribution: Option<DailyOrdersExpr>,
+
+    /** Multipliers used instead of `default_daily_multipliers`. */
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_daily_multipliers",
+        serialize_with = "serialize_optional_daily_multipliers",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(schema_with = "daily_multipliers_schema")]
+    pub daily_multipliers: Option<DailyMultipliers>,
+}
+
+/**
+ * Serializes back into the format it is read from, except that
+ * the daily multipliers are written out instead of their expression.
+ */
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct SimConfig {
     /** Number of shops in the simulation. */
     pub simulated_shops_number: usize,
 
+    /**
+     * Number of regions the shops are spread over
+     * as described by `region_assignment`.
+     */
+    #[serde(default = "default_simulated_regions_number")]
+    pub simulated_regions_number: usize,
+
+    /** How shops are assigned to regions. */
+    #[serde(default)]
+    pub region_assignment: RegionAssignment,
+
+    /**
+     * Number of currencies the shops sell in.
+     * Shop `i` sells in currency `i % simulated_currencies_number`,
+     * and the pools keep the accounts of each currency apart.
+     */
+    #[serde(
+        default = "default_simulated_currencies_number",
+        deserialize_with = "deserialize_simulated_currencies_number"
+    )]
+    #[schemars(range(min = 1))]
+    pub simulated_currencies_number: usize,
+
     /** Number of years for which simulation is run. */
     pub simulated_years_number: usize,
 
+    /**
+     * Stop after this many days, even in the middle of a year,
+     * if the years would run longer. Must be past the warm-up.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_days: Option<usize>,
+
+    /**
+     * Number of the first simulated years which only populate
+     * the pools and are excluded from the statistics.
+     */
+    #[serde(default)]
+    pub warmup_years: usize,
+
     /**
      * Probalistic distribution of a shop size.
      * The default daily distribution for this shop
      * is multiplied by this value.
      */
-    pub shop_size_distribution: Normal<f64>,
+    #[serde(deserialize_with = "deserialize_distribution")]
+    pub shop_size_distribution: Distribution,
+
+    /**
+     * File with the sizes of the shops to use instead of sampling
+     * them from `shop_size_distribution`, either one per line
+     * or as a JSON array. A short list is repeated from its start
+     * and a long one is truncated to `simulated_shops_number`.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shop_sizes_file: Option<PathBuf>,
+
+    /**
+     * Curve the shop sizes go through before they scale the daily
+     * distribution, also the value of `shop_size` in expressions.
+     */
+    #[serde(default)]
+    pub shop_size_scaling: ShopSizeScaling,
 
     /**
      * Number of sell-outs each shop conducts per year.
      */
     pub sales_per_year_for_each_shop: usize,
 
+    /**
+     * Leave out all the sales, whatever `sales_per_year_for_each_shop`
+     * and `sale_timing` say, to see the baseline demand alone.
+     */
+    #[serde(default)]
+    pub disable_sales: bool,
+
+    /** How the sale days of each shop are picked. */
+    #[serde(default)]
+    pub sale_timing: SaleTiming,
+
+    /**
+     * Pick the sale days once a year for all shops instead of
+     * for each shop on its own, so that all sales coincide.
+     * This is the worst case for the parallel load. For sales
+     * on a fixed day use a holiday instead.
+     */
+    #[serde(default)]
+    pub synchronize_sales: bool,
+
     /**
      * If a sale occurs at that day, we multiply
      * the daily multiplier by this value.
      */
-    pub sale_multiplier: usize,
+    pub sale_multiplier: f64,
 
     /**
      * For each day of a year, describes a number by which
@@ -59,7 +580,11 @@ pub struct SimConfig {
      * It is defined by a function that maps
      * a day number to the multiplier at that day.
      */
-    #[serde(deserialize_with = "deserialize_daily_multipliers")]
+    #[serde(
+        deserialize_with = "deserialize_daily_multipliers",
+        serialize_with = "serialize_daily_multipliers"
+    )]
+    #[schemars(schema_with = "daily_multipliers_schema")]
     pub default_daily_multipliers: DailyMultipliers,
 
     /**
@@ -69,32 +594,1672 @@ pub struct SimConfig {
      * It is defined by a function that maps
      * an hour to the number of orders.
      */
-    #[serde(deserialize_with = "deserialize_daily_orders_distribution")]
-    pub default_daily_distribution: DailyOrdersDistribution,
+    #[serde(deserialize_with = "deserialize_daily_orders_expr")]
+    pub default_daily_distribution: DailyOrdersExpr,
+
+    /**
+     * For each day of a week, describes a number by which we multiply
+     * the number of orders on top of the daily multipliers.
+     * The first simulated day is the first day of a week.
+     */
+    #[serde(default = "default_weekday_multipliers")]
+    pub weekday_multipliers: WeekdayMultipliers,
+
+    /**
+     * Days of each year with a deterministic demand multiplier
+     * shared by all shops, on top of the other multipliers.
+     */
+    #[serde(default)]
+    pub holidays: Vec<HolidaySpec>,
 
     /**
      * Probalistic distribution of a price.
      * When a transaction is issued, its amount is
      * randomly sampled from this distribution.
      */
-    pub price_distribution: Normal<f64>,
+    #[serde(deserialize_with = "deserialize_distribution")]
+    pub price_distribution: Distribution,
+
+    /**
+     * Fraction of the transactions which are refunds, taking
+     * their sampled price out of the pools instead of paying it.
+     * A shop may then owe money, see `AccountsPool::withdraw_all`.
+     */
+    #[serde(default)]
+    pub refund_rate: f64,
+
+    /**
+     * When money is withdrawn. A bare number k under the old
+     * `withdrawal_period_in_days` key means every k days.
+     * Without it, and without `withdrawal_period_in_hours`,
+     * money is withdrawn every day.
+     */
+    #[serde(
+        default,
+        alias = "withdrawal_period_in_days",
+        deserialize_with = "deserialize_optional_withdrawal_policy",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(schema_with = "withdrawal_policy_schema")]
+    pub withdrawal_policy: Option<WithdrawalPolicy>,
+
+    /**
+     * Withdraw every k hours, also in the middle of a day,
+     * instead of following `withdrawal_policy`.
+     * The two can't be given together.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withdrawal_period_in_hours: Option<usize>,
+
+    /**
+     * Day of the simulation on which the first scheduled
+     * withdrawal happens, with the next ones following it
+     * every period. Without it, the first withdrawal happens
+     * at the end of the first period. Only for periodic and
+     * hourly withdrawals.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub withdrawal_offset_days: Option<usize>,
+
+    /**
+     * Noise applied to the number of orders of each shop at each hour.
+     * The daily distribution and multipliers define the expected value.
+     */
+    #[serde(default)]
+    pub order_count_noise: OrderCountNoise,
+
+    /** Maximum number of accounts the capped pool may hold. */
+    #[serde(default = "default_capped_pool_max_accounts")]
+    pub capped_pool_max_accounts: usize,
+
+    /**
+     * Upper bound on the number of orders of a single shop
+     * at a single hour. Larger counts are clamped to it.
+     */
+    #[serde(default = "default_max_transactions_per_hour")]
+    pub max_transactions_per_hour: usize,
+
+    /**
+     * Number of bins of the histogram of transactions per hour.
+     * The bins evenly split `max_transactions_per_hour`
+     * and the last one also takes all busier hours.
+     */
+    #[serde(default = "default_histogram_bins")]
+    pub histogram_bins: usize,
+
+    /**
+     * How long a transaction takes to process. The transactions
+     * of an hour are spread evenly over it, and those whose
+     * processing overlaps count towards
+     * `peak_concurrent_transactions`. The default of a whole
+     * hour makes all transactions of an hour overlap.
+     */
+    #[serde(default = "default_transaction_duration_minutes")]
+    pub transaction_duration_minutes: f64,
+
+    /**
+     * Probability that a transaction of a withdrawal fails.
+     * Failed transactions are retried until they go through,
+     * and each failed attempt counts as a transaction.
+     */
+    #[serde(default)]
+    pub withdrawal_failure_rate: f64,
+
+    /**
+     * Number of days for which accounts emptied by a withdrawal
+     * can't be used by the single pools.
+     */
+    #[serde(default)]
+    pub settlement_days: usize,
+
+    /**
+     * Start every year with fresh pools instead of carrying
+     * their state over, so that years are simulated in parallel.
+     * Money left in the pools at the end of a year is dropped,
+     * and the numbers of accounts are the ones of the last year.
+     */
+    #[serde(default)]
+    pub independent_years: bool,
 
     /**
-     * Let this value be k. Then money will be withdrawed every k days.
+     * Stop before `simulated_years_number` once the number of
+     * withdrawal transactions of every pool changes by less than
+     * this fraction between consecutive withdrawals, for
+     * `convergence_window` withdrawals in a row. Checked at the
+     * end of each year past the warm-up, so whole years are run.
+     * Can't be used with `independent_years`.
      */
-    pub withdrawal_period_in_days: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub convergence_epsilon: Option<f64>,
+
+    /** Number of stable withdrawals `convergence_epsilon` asks for. */
+    #[serde(default = "default_convergence_window")]
+    pub convergence_window: usize,
+
+    /**
+     * Measure the wall-clock time each pool spends processing
+     * transactions and withdrawing. Doesn't change the simulation,
+     * nor the seed derived from the config.
+     */
+    #[serde(default)]
+    pub profile: bool,
+
+    /**
+     * Seed of the shop sizes and of the yearly draws, such as
+     * the sale days, instead of the one derived from the seed
+     * of the run. Fixing it keeps the same shops while
+     * the transactions vary with the seed of the run.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub world_seed: Option<u64>,
+
+    /**
+     * Seed of the hourly order counts and the prices, instead
+     * of the one derived from the seed of the run. Fixing it
+     * keeps the same draws of the transactions while the shops
+     * vary with the seed of the run.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction_seed: Option<u64>,
+
+    /**
+     * Withdrawals leave accounts with less money than this alone,
+     * so that their balances carry forward. Honored by the pool
+     * per shop and by the single and greedy single pools.
+     */
+    #[serde(default)]
+    pub dust_threshold: f64,
+
+    /**
+     * Fraction of the accounts, the ones with the most money,
+     * which withdrawals drain. The rest carry their balances
+     * forward. Honored by the same pools as `dust_threshold`.
+     */
+    #[serde(default = "default_withdrawal_fraction")]
+    pub withdrawal_fraction: f64,
+
+    /**
+     * Most money a single account may hold. A transaction which
+     * would push an account over it spills the rest into new
+     * accounts. Honored by the same pools as `dust_threshold`.
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_account_balance: Option<f64>,
+
+    /**
+     * Number of destination accounts each shop splits its
+     * withdrawn money between, each part making a transaction
+     * of its own. Honored by the same pools as `dust_threshold`.
+     */
+    #[serde(default = "default_destinations_per_shop")]
+    pub destinations_per_shop: usize,
+
+    /**
+     * Cost of a withdrawal. A bare number under the old
+     * `per_transaction_fee` key is a flat fee per transaction.
+     */
+    #[serde(
+        default,
+        alias = "per_transaction_fee",
+        deserialize_with = "deserialize_withdrawal_fee"
+    )]
+    #[schemars(schema_with = "withdrawal_fee_schema")]
+    pub withdrawal_fee: WithdrawalFee,
+
+    /**
+     * Properties of individual shops, by shop id,
+     * which differ from the defaults above.
+     */
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub shop_overrides: BTreeMap<ShopId, ShopOverride>,
 }
 
-#[derive(Serialize)]
+/**
+ * Defaults to a single shop simulated for one year with flat
+ * daily multipliers and demand, and a withdrawal every day.
+ */
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            simulated_shops_number: 1,
+            simulated_regions_number: default_simulated_regions_number(),
+            region_assignment: RegionAssignment::default(),
+            simulated_currencies_number: default_simulated_currencies_number(),
+            simulated_years_number: 1,
+            max_days: None,
+            warmup_years: 0,
+            shop_size_distribution: Distribution::Normal {
+                mean: 1.0,
+                std: 0.5,
+            },
+            shop_sizes_file: None,
+            shop_size_scaling: ShopSizeScaling::default(),
+            sales_per_year_for_each_shop: 0,
+            disable_sales: false,
+            sale_timing: SaleTiming::default(),
+            synchronize_sales: false,
+            independent_years: false,
+            convergence_epsilon: None,
+            convergence_window: default_convergence_window(),
+            profile: false,
+            world_seed: None,
+            transaction_seed: None,
+            dust_threshold: 0.0,
+            withdrawal_fraction: default_withdrawal_fraction(),
+            max_account_balance: None,
+            destinations_per_shop: default_destinations_per_shop(),
+            sale_multiplier: 1.0,
+            default_daily_multipliers: [1.0; DAYS_IN_YEAR],
+            default_daily_distribution: DailyOrdersExpr::new("1")
+                .expect("constant expression is valid"),
+            weekday_multipliers: default_weekday_multipliers(),
+            holidays: vec![],
+            price_distribution: Distribution::Normal {
+                mean: 50.0,
+                std: 30.0,
+            },
+            refund_rate: 0.0,
+            withdrawal_policy: None,
+            withdrawal_period_in_hours: None,
+            withdrawal_offset_days: None,
+            order_count_noise: OrderCountNoise::default(),
+            capped_pool_max_accounts: default_capped_pool_max_accounts(),
+            max_transactions_per_hour: default_max_transactions_per_hour(),
+            histogram_bins: default_histogram_bins(),
+            transaction_duration_minutes: default_transaction_duration_minutes(
+            ),
+            withdrawal_failure_rate: 0.0,
+            settlement_days: 0,
+            withdrawal_fee: WithdrawalFee::default(),
+            shop_overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl SimConfig {
+    pub fn builder() -> SimConfigBuilder {
+        SimConfigBuilder::default()
+    }
+
+    /**
+     * Returns the policy the withdrawals follow, which is
+     * `withdrawal_policy` if given, an hourly one if
+     * `withdrawal_period_in_hours` is given, or every day.
+     */
+    pub fn effective_withdrawal_policy(&self) -> WithdrawalPolicy {
+        match (self.withdrawal_policy, self.withdrawal_period_in_hours) {
+            (Some(policy), _) => policy,
+            (None, Some(hours)) => WithdrawalPolicy::Hourly { hours },
+            (None, None) => WithdrawalPolicy::Periodic { days: 1 },
+        }
+    }
+
+    /**
+     * Returns the number of days to simulate, which is
+     * `max_days` if it cuts the simulated years short.
+     */
+    pub fn total_days(&self) -> usize {
+        let days = self.simulated_years_number * DAYS_IN_YEAR;
+        self.max_days.map_or(days, |max_days| days.min(max_days))
+    }
+
+    /**
+     * Returns the seed the shops and the yearly draws of a run
+     * with `seed` are generated from, which is `world_seed`
+     * if given, or `seed` itself.
+     */
+    pub fn effective_world_seed(&self, seed: u64) -> u64 {
+        self.world_seed.unwrap_or(seed)
+    }
+
+    /**
+     * Returns the seed the transactions of a run with `seed`
+     * are generated from, which is `transaction_seed` if given,
+     * or one mixed from `seed` unlike those of the pools.
+     */
+    pub fn effective_transaction_seed(&self, seed: u64) -> u64 {
+        self.transaction_seed
+            .unwrap_or(seed ^ 0xD1B5_4A32_D192_ED03)
+    }
+}
+
+/**
+ * Builds a `SimConfig` starting from `SimConfig::default()`.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SimConfigBuilder {
+    config: SimConfig,
+}
+
+impl SimConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn build(self) -> SimConfig {
+        self.config
+    }
+
+    pub fn simulated_shops_number(
+        mut self,
+        simulated_shops_number: usize,
+    ) -> Self {
+        self.config.simulated_shops_number = simulated_shops_number;
+        self
+    }
+
+    pub fn simulated_regions_number(
+        mut self,
+        simulated_regions_number: usize,
+    ) -> Self {
+        self.config.simulated_regions_number = simulated_regions_number;
+        self
+    }
+
+    pub fn simulated_currencies_number(
+        mut self,
+        simulated_currencies_number: usize,
+    ) -> Self {
+        self.config.simulated_currencies_number = simulated_currencies_number;
+        self
+    }
+
+    pub fn region_assignment(
+        mut self,
+        region_assignment: RegionAssignment,
+    ) -> Self {
+        self.config.region_assignment = region_assignment;
+        self
+    }
+
+    pub fn simulated_years_number(
+        mut self,
+        simulated_years_number: usize,
+    ) -> Self {
+        self.config.simulated_years_number = simulated_years_number;
+        self
+    }
+
+    pub fn max_days(mut self, max_days: usize) -> Self {
+        self.config.max_days = Some(max_days);
+        self
+    }
+
+    pub fn warmup_years(mut self, warmup_years: usize) -> Self {
+        self.config.warmup_years = warmup_years;
+        self
+    }
+
+    pub fn shop_size_distribution(
+        mut self,
+        shop_size_distribution: Distribution,
+    ) -> Self {
+        self.config.shop_size_distribution = shop_size_distribution;
+        self
+    }
+
+    pub fn shop_sizes_file(
+        mut self,
+        shop_sizes_file: impl Into<PathBuf>,
+    ) -> Self {
+        self.config.shop_sizes_file = Some(shop_sizes_file.into());
+        self
+    }
+
+    pub fn shop_size_scaling(
+        mut self,
+        shop_size_scaling: ShopSizeScaling,
+    ) -> Self {
+        self.config.shop_size_scaling = shop_size_scaling;
+        self
+    }
+
+    pub fn sales_per_year_for_each_shop(
+        mut self,
+        sales_per_year_for_each_shop: usize,
+    ) -> Self {
+        self.config.sales_per_year_for_each_shop = sales_per_year_for_each_shop;
+        self
+    }
+
+    pub fn disable_sales(mut self, disable_sales: bool) -> Self {
+        self.config.disable_sales = disable_sales;
+        self
+    }
+
+    pub fn sale_timing(mut self, sale_timing: SaleTiming) -> Self {
+        self.config.sale_timing = sale_timing;
+        self
+    }
+
+    pub fn synchronize_sales(mut self, synchronize_sales: bool) -> Self {
+        self.config.synchronize_sales = synchronize_sales;
+        self
+    }
+
+    pub fn sale_multiplier(mut self, sale_multiplier: f64) -> Self {
+        self.config.sale_multiplier = sale_multiplier;
+        self
+    }
+
+    pub fn default_daily_multipliers(
+        mut self,
+        default_daily_multipliers: DailyMultipliers,
+    ) -> Self {
+        self.config.default_daily_multipliers = default_daily_multipliers;
+        self
+    }
+
+    pub fn default_daily_distribution(
+        mut self,
+        default_daily_distribution: DailyOrdersExpr,
+    ) -> Self {
+        self.config.default_daily_distribution = default_daily_distribution;
+        self
+    }
+
+    pub fn weekday_multipliers(
+        mut self,
+        weekday_multipliers: WeekdayMultipliers,
+    ) -> Self {
+        self.config.weekday_multipliers = weekday_multipliers;
+        self
+    }
+
+    pub fn holidays(mut self, holidays: Vec<HolidaySpec>) -> Self {
+        self.config.holidays = holidays;
+        self
+    }
+
+    pub fn price_distribution(
+        mut self,
+        price_distribution: Distribution,
+    ) -> Self {
+        self.config.price_distribution = price_distribution;
+        self
+    }
+
+    pub fn refund_rate(mut self, refund_rate: f64) -> Self {
+        self.config.refund_rate = refund_rate;
+        self
+    }
+
+    pub fn withdrawal_policy(
+        mut self,
+        withdrawal_policy: WithdrawalPolicy,
+    ) -> Self {
+        self.config.withdrawal_policy = Some(withdrawal_policy);
+        self
+    }
+
+    pub fn withdrawal_period_in_hours(
+        mut self,
+        withdrawal_period_in_hours: usize,
+    ) -> Self {
+        self.config.withdrawal_period_in_hours =
+            Some(withdrawal_period_in_hours);
+        self
+    }
+
+    pub fn order_count_noise(
+        mut self,
+        order_count_noise: OrderCountNoise,
+    ) -> Self {
+        self.config.order_count_noise = order_count_noise;
+        self
+    }
+
+    pub fn capped_pool_max_accounts(
+        mut self,
+        capped_pool_max_accounts: usize,
+    ) -> Self {
+        self.config.capped_pool_max_accounts = capped_pool_max_accounts;
+        self
+    }
+
+    pub fn max_transactions_per_hour(
+        mut self,
+        max_transactions_per_hour: usize,
+    ) -> Self {
+        self.config.max_transactions_per_hour = max_transactions_per_hour;
+        self
+    }
+
+    pub fn histogram_bins(mut self, histogram_bins: usize) -> Self {
+        self.config.histogram_bins = histogram_bins;
+        self
+    }
+
+    pub fn transaction_duration_minutes(
+        mut self,
+        transaction_duration_minutes: f64,
+    ) -> Self {
+        self.config.transaction_duration_minutes = transaction_duration_minutes;
+        self
+    }
+
+    pub fn withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.config.withdrawal_failure_rate = withdrawal_failure_rate;
+        self
+    }
+
+    pub fn settlement_days(mut self, settlement_days: usize) -> Self {
+        self.config.settlement_days = settlement_days;
+        self
+    }
+
+    pub fn independent_years(mut self, independent_years: bool) -> Self {
+        self.config.independent_years = independent_years;
+        self
+    }
+
+    pub fn convergence_epsilon(mut self, convergence_epsilon: f64) -> Self {
+        self.config.convergence_epsilon = Some(convergence_epsilon);
+        self
+    }
+
+    pub fn convergence_window(mut self, convergence_window: usize) -> Self {
+        self.config.convergence_window = convergence_window;
+        self
+    }
+
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.config.profile = profile;
+        self
+    }
+
+    pub fn world_seed(mut self, world_seed: u64) -> Self {
+        self.config.world_seed = Some(world_seed);
+        self
+    }
+
+    pub fn transaction_seed(mut self, transaction_seed: u64) -> Self {
+        self.config.transaction_seed = Some(transaction_seed);
+        self
+    }
+
+    pub fn withdrawal_offset_days(
+        mut self,
+        withdrawal_offset_days: usize,
+    ) -> Self {
+        self.config.withdrawal_offset_days = Some(withdrawal_offset_days);
+        self
+    }
+
+    pub fn dust_threshold(mut self, dust_threshold: f64) -> Self {
+        self.config.dust_threshold = dust_threshold;
+        self
+    }
+
+    pub fn withdrawal_fraction(mut self, withdrawal_fraction: f64) -> Self {
+        self.config.withdrawal_fraction = withdrawal_fraction;
+        self
+    }
+
+    pub fn max_account_balance(mut self, max_account_balance: f64) -> Self {
+        self.config.max_account_balance = Some(max_account_balance);
+        self
+    }
+
+    pub fn destinations_per_shop(
+        mut self,
+        destinations_per_shop: usize,
+    ) -> Self {
+        self.config.destinations_per_shop = destinations_per_shop;
+        self
+    }
+
+    pub fn withdrawal_fee(mut self, withdrawal_fee: WithdrawalFee) -> Self {
+        self.config.withdrawal_fee = withdrawal_fee;
+        self
+    }
+
+    pub fn shop_overrides(
+        mut self,
+        shop_overrides: BTreeMap<ShopId, ShopOverride>,
+    ) -> Self {
+        self.config.shop_overrides = shop_overrides;
+        self
+    }
+}
+
+fn default_weekday_multipliers() -> WeekdayMultipliers {
+    [1.0; DAYS_IN_WEEK]
+}
+
+fn default_simulated_regions_number() -> usize {
+    1
+}
+
+fn default_simulated_currencies_number() -> usize {
+    1
+}
+
+fn default_withdrawal_fraction() -> f64 {
+    1.0
+}
+
+fn default_destinations_per_shop() -> usize {
+    1
+}
+
+fn default_convergence_window() -> usize {
+    10
+}
+
+fn default_transaction_duration_minutes() -> f64 {
+    60.0
+}
+
+fn default_capped_pool_max_accounts() -> usize {
+    1000
+}
+
+fn default_max_transactions_per_hour() -> usize {
+    1_000_000
+}
+
+fn default_histogram_bins() -> usize {
+    10
+}
+
+/**
+ * Assigns each of the first `shop_count` shops to a region
+ * as described by the config. Fails if the assignment
+ * expression is malformed or gives a negative region.
+ */
+pub fn assign_regions(
+    config: &SimConfig,
+    shop_count: usize,
+) -> Result<Vec<RegionId>> {
+    let regions_number = config.simulated_regions_number;
+    ensure!(
+        regions_number > 0,
+        "simulated_regions_number must be positive"
+    );
+
+    match &config.region_assignment {
+        RegionAssignment::RoundRobin => Ok((0..shop_count)
+            .map(|shop_id| shop_id % regions_number)
+            .collect()),
+        RegionAssignment::Random { seed } => {
+            let mut rng = SmallRng::seed_from_u64(*seed);
+            Ok((0..shop_count)
+                .map(|_| rng.gen_range(0..regions_number))
+                .collect())
+        }
+        RegionAssignment::ByExpression { expr } => {
+            let expr = CompiledExpr::new(expr.as_str())
+                .map_err(|err| anyhow!("region_assignment: {}", err))?;
+            let mut context = HashMapContext::new();
+            (0..shop_count)
+                .map(|shop_id| {
+                    let region = expr
+                        .eval_non_negative(
+                            &mut context,
+                            &[("shop_id", (shop_id as i64).into())],
+                        )
+                        .map_err(|err| {
+                            anyhow!(
+                                "region_assignment: shop {}: {}",
+                                shop_id,
+                                err
+                            )
+                        })?;
+                    Ok(region.round() as usize % regions_number)
+                })
+                .collect()
+        }
+    }
+}
+
+/**
+ * Reads the sizes of the first `shop_count` shops from
+ * `shop_sizes_file`, if the config has one. Fails if the file
+ * can't be read or has no sizes or a size which is not positive.
+ */
+pub fn load_shop_sizes(
+    config: &SimConfig,
+    shop_count: usize,
+) -> Result<Option<Vec<f64>>> {
+    let Some(path) = &config.shop_sizes_file else {
+        return Ok(None);
+    };
+    let sizes = read_shop_sizes(path)?;
+    Ok(Some(sizes.into_iter().cycle().take(shop_count).collect()))
+}
+
+fn read_shop_sizes(path: &Path) -> Result<Vec<f64>> {
+    let text = std::fs::read_to_string(path).with_context(|| {
+        format!("failed to read shop sizes from {}", path.display())
+    })?;
+    parse_shop_sizes(&text)
+        .with_context(|| format!("malformed shop sizes in {}", path.display()))
+}
+
+/**
+ * Parses a JSON array of sizes or, otherwise,
+ * a size per line, skipping blank lines.
+ */
+pub fn parse_shop_sizes(text: &str) -> Result<Vec<f64>> {
+    let sizes: Vec<f64> = if text.trim_start().starts_with('[') {
+        serde_json::from_str(text)?
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                line.parse()
+                    .map_err(|err| anyhow!("size \"{}\": {}", line, err))
+            })
+            .collect::<Result<_>>()?
+    };
+    ensure!(!sizes.is_empty(), "there are no shop sizes");
+    if let Some(size) = sizes
+        .iter()
+        .find(|size| !(size.is_finite() && **size > 0.0))
+    {
+        bail!("shop size {} must be positive", size);
+    }
+    Ok(sizes)
+}
+
+/**
+ * Checks the config for mistakes that would otherwise
+ * only show up during, or after, a long run.
+ */
+pub fn validate_config(config: &SimConfig) -> Result<()> {
+    ensure!(
+        config.simulated_shops_number > 0,
+        "simulated_shops_number must be positive"
+    );
+    ensure!(
+        config.simulated_years_number > 0,
+        "simulated_years_number must be positive"
+    );
+    ensure!(
+        config.warmup_years < config.simulated_years_number,
+        "warmup_years must be less than simulated_years_number"
+    );
+    if let Some(max_days) = config.max_days {
+        ensure!(
+            max_days > config.warmup_years * DAYS_IN_YEAR,
+            "max_days must be greater than the days of warmup_years"
+        );
+    }
+    ensure!(
+        config.simulated_regions_number > 0,
+        "simulated_regions_number must be positive"
+    );
+    assign_regions(config, config.simulated_shops_number)?;
+    load_shop_sizes(config, config.simulated_shops_number)?;
+    ensure!(
+        config.simulated_currencies_number > 0,
+        "simulated_currencies_number must be positive"
+    );
+    ensure!(
+        config.withdrawal_policy.is_none()
+            || config.withdrawal_period_in_hours.is_none(),
+        "withdrawal_policy and withdrawal_period_in_hours \
+         are mutually exclusive"
+    );
+    match config.effective_withdrawal_policy() {
+        WithdrawalPolicy::Periodic { days } => {
+            ensure!(days > 0, "withdrawal period must be positive")
+        }
+        WithdrawalPolicy::Threshold { amount } => {
+            ensure!(amount > 0.0, "withdrawal threshold must be positive")
+        }
+        WithdrawalPolicy::Combined { days, amount } => {
+            ensure!(days > 0, "withdrawal period must be positive");
+            ensure!(amount > 0.0, "withdrawal threshold must be positive")
+        }
+        WithdrawalPolicy::Hourly { hours } => {
+            ensure!(hours > 0, "withdrawal period must be positive")
+        }
+    }
+    ensure!(
+        config.withdrawal_offset_days.is_none()
+            || matches!(
+                config.effective_withdrawal_policy(),
+                WithdrawalPolicy::Periodic { .. }
+                    | WithdrawalPolicy::Hourly { .. }
+            ),
+        "withdrawal_offset_days needs periodic or hourly withdrawals"
+    );
+    ensure!(
+        config.capped_pool_max_accounts > 0,
+        "capped_pool_max_accounts must be positive"
+    );
+    ensure!(
+        config.max_transactions_per_hour > 0,
+        "max_transactions_per_hour must be positive"
+    );
+    ensure!(config.histogram_bins > 0, "histogram_bins must be positive");
+    ensure!(
+        config.transaction_duration_minutes.is_finite()
+            && config.transaction_duration_minutes > 0.0,
+        "transaction_duration_minutes must be positive"
+    );
+    ensure!(
+        (0.0..1.0).contains(&config.withdrawal_failure_rate),
+        "withdrawal_failure_rate must be at least 0 and below 1"
+    );
+
+    config
+        .withdrawal_fee
+        .validate()
+        .map_err(|err| anyhow!("withdrawal_fee: {}", err))?;
+    ensure!(
+        config.sale_multiplier.is_finite() && config.sale_multiplier >= 0.0,
+        "sale_multiplier must be non-negative"
+    );
+    ensure!(
+        config.dust_threshold.is_finite() && config.dust_threshold >= 0.0,
+        "dust_threshold must be non-negative"
+    );
+    ensure!(
+        config.withdrawal_fraction > 0.0 && config.withdrawal_fraction <= 1.0,
+        "withdrawal_fraction must be in (0, 1]"
+    );
+    ensure!(
+        config.destinations_per_shop > 0,
+        "destinations_per_shop must be positive"
+    );
+    ensure!(
+        (0.0..=1.0).contains(&config.refund_rate),
+        "refund_rate must be in [0, 1]"
+    );
+    if let Some(convergence_epsilon) = config.convergence_epsilon {
+        ensure!(
+            convergence_epsilon.is_finite() && convergence_epsilon > 0.0,
+            "convergence_epsilon must be positive"
+        );
+        ensure!(
+            config.convergence_window > 0,
+            "convergence_window must be positive"
+        );
+        ensure!(
+            !config.independent_years,
+            "convergence_epsilon can't be used with independent_years"
+        );
+    }
+    if let Some(max_account_balance) = config.max_account_balance {
+        ensure!(
+            max_account_balance.is_finite() && max_account_balance > 0.0,
+            "max_account_balance must be positive"
+        );
+    }
+    if let ShopSizeScaling::Power { exponent } = config.shop_size_scaling {
+        ensure!(
+            exponent.is_finite() && exponent >= 0.0,
+            "shop_size_scaling exponent must be non-negative"
+        );
+    }
+    if let SaleTiming::Spaced { mean_gap_days } = config.sale_timing {
+        ensure!(
+            mean_gap_days.is_finite() && mean_gap_days >= 1.0,
+            "mean_gap_days must be at least one day"
+        );
+    }
+    ensure!(
+        config
+            .weekday_multipliers
+            .iter()
+            .all(|multiplier| multiplier.is_finite() && *multiplier >= 0.0),
+        "weekday_multipliers must be non-negative"
+    );
+
+    for holiday in &config.holidays {
+        ensure!(
+            holiday.day < DAYS_IN_YEAR,
+            "holiday day {} is out of the year",
+            holiday.day
+        );
+        ensure!(
+            holiday.multiplier.is_finite() && holiday.multiplier >= 0.0,
+            "holiday multiplier on day {} must be non-negative",
+            holiday.day
+        );
+    }
+
+    for (&shop_id, shop_override) in &config.shop_overrides {
+        ensure!(
+            shop_id < config.simulated_shops_number,
+            "shop_overrides: shop {} is out of the simulated shops",
+            shop_id
+        );
+        if let Some(size) = shop_override.size {
+            ensure!(
+                size.is_finite() && size > 0.0,
+                "shop_overrides: size of shop {} must be positive",
+                shop_id
+            );
+        }
+    }
+
+    for (name, distribution) in [
+        ("shop_size_distribution", config.shop_size_distribution),
+        ("price_distribution", config.price_distribution),
+    ] {
+        distribution
+            .validate()
+            .map_err(|err| anyhow!("{}: {}", name, err))?;
+    }
+
+    Ok(())
+}
+
+fn daily_multipliers_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            {
+                "type": "string",
+                "description": "Expression of the day `d`.",
+                "examples": [
+                    "1",
+                    "max(math::exp(-(d - 60)^2 / 1000), 0.5) * 10"
+                ]
+            },
+            {
+                "type": "array",
+                "items": { "type": "number", "minimum": 0.0 },
+                "minItems": DAYS_IN_YEAR,
+                "maxItems": DAYS_IN_YEAR
+            }
+        ]
+    })
+}
+
+fn withdrawal_policy_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "integer", "minimum": 1 },
+            generator.subschema_for::<WithdrawalPolicy>()
+        ]
+    })
+}
+
+fn withdrawal_fee_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "number", "minimum": 0.0 },
+            generator.subschema_for::<WithdrawalFee>()
+        ]
+    })
+}
+
+/**
+ * JSON Schema of the config, which editors can use
+ * to complete and check config files.
+ */
+pub fn config_schema() -> Schema {
+    SchemaSettings::default()
+        .with_transform(RecursiveTransform(tidy_schema_description))
+        .into_generator()
+        .into_root_schema_for::<SimConfig>()
+}
+
+/**
+ * Derives a seed from the contents of the config, so that
+ * the same config always reproduces the same simulation.
+ * Whether the run is profiled doesn't count.
+ *
+ * Hashes the JSON of the config, so the seed is only stable
+ * within one version: adding or renaming a config field
+ * changes the seeds of all configs.
+ */
+pub fn config_seed(config: &SimConfig) -> u64 {
+    let config = SimConfig {
+        profile: false,
+        ..config.clone()
+    };
+    let json = serde_json::to_vec(&config).expect("configs serialize");
+    let mut hasher = StableHasher::default();
+    hasher.write(&json);
+    hasher.finish()
+}
+
+/** Wall-clock time a pool spent in one of its methods. */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MethodTiming {
+    pub calls: usize,
+    pub total_seconds: f64,
+    pub average_seconds: f64,
+}
+
+/** Where a pool spent its time, measured when profiling. */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolTimings {
+    pub process_transactions: MethodTiming,
+    pub withdraw_all: MethodTiming,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct PoolResults {
-    pub pool_name: &'static str,
+    pub pool_name: String,
     pub total_number_of_transactions_during_withdrawals: usize,
+    /** Number of withdrawals that took place. */
+    pub withdrawal_events: usize,
+    /**
+     * Min, p25, median, p75 and max of the account balances
+     * just before the last withdrawal.
+     */
+    pub last_balance_percentiles: [f64; 5],
+    /**
+     * Gini coefficient of the account balances just before
+     * a withdrawal, averaged over all withdrawals.
+     */
+    pub average_gini: f64,
     pub total_number_of_accounts: usize,
+    /**
+     * Withdrawal transactions which failed and were retried,
+     * already counted in
+     * `total_number_of_transactions_during_withdrawals`.
+     */
+    pub failed_withdrawal_transactions: usize,
+    /**
+     * Largest number of accounts held at once over the run,
+     * where `total_number_of_accounts` counts the ones at its end.
+     */
+    pub peak_accounts: usize,
+    /** Number of accounts at the end of the run in each currency. */
+    pub accounts_per_currency: BTreeMap<CurrencyId, usize>,
+    /** Largest number of accounts touched by a single hourly batch. */
+    pub peak_parallel_accounts_touched: usize,
+    /**
+     * Money distributed between shops during all withdrawals.
+     * Should match the volume of withdrawn transactions.
+     */
+    pub total_money_withdrawn: f64,
+    /** Fees paid for all withdrawal transactions. */
+    pub total_fees: f64,
+    /**
+     * Transactions queued onto a busy account because
+     * the pool could not open any more accounts.
+     */
+    pub total_number_of_queued_transactions: usize,
+    /**
+     * Accounts opened while emptied accounts were unavailable
+     * because of the settlement delay.
+     */
+    pub extra_accounts_due_to_settlement: usize,
+    /**
+     * Accounts opened for the money which would have pushed
+     * an account over `max_account_balance`.
+     */
+    pub accounts_created_due_to_cap: usize,
+    /**
+     * Number of distinct shops with transactions between
+     * two withdrawals, averaged over all withdrawals.
+     */
+    pub average_active_shops_per_period: f64,
+    /** Largest number of distinct shops active between withdrawals. */
+    pub peak_active_shops_per_period: usize,
+    /**
+     * Accounts created and torn down over the whole run.
+     * Accounts emptied and recreated by a withdrawal count
+     * in both figures.
+     */
+    pub accounts_created: usize,
+    pub accounts_freed: usize,
+    /**
+     * Money left in the accounts below the dust threshold
+     * right after a withdrawal, averaged over all withdrawals.
+     */
+    pub average_dust_left: f64,
+    /**
+     * Money left in all the accounts which were not drained
+     * right after a withdrawal, averaged over all withdrawals.
+     */
+    pub average_money_left: f64,
+    /** Only measured when `profile` is set. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<PoolTimings>,
 }
 
-#[derive(Serialize)]
+/**
+ * Together with `seed` and `effective_config`,
+ * the results are enough to reproduce the run.
+ * They are read back from JSON to compare runs.
+ */
+#[derive(Serialize, Deserialize)]
 pub struct SimResults {
+    /** Seed the run was started with. */
+    pub seed: u64,
     pub total_number_of_transactions: usize,
     pub peak_parallel_transactions_number: usize,
+    /**
+     * Largest number of transactions being processed at once,
+     * given `transaction_duration_minutes`.
+     */
+    pub peak_concurrent_transactions: usize,
+    /**
+     * Number of times the orders of a shop at an hour
+     * were clamped to `max_transactions_per_hour`.
+     */
+    pub total_number_of_clamped_order_counts: usize,
+    /** Width of the bins of `transactions_per_hour_histogram`. */
+    pub transactions_per_hour_bin_width: usize,
+    /**
+     * Number of simulated hours by the number of transactions
+     * at that hour. The last bin also counts all busier hours.
+     */
+    pub transactions_per_hour_histogram: Vec<usize>,
+    /** Sum of the amounts of all transactions, net of refunds. */
+    pub total_transaction_volume: f64,
+    /** Amount statistics, all zero when there are no transactions. */
+    pub min_amount: f64,
+    pub max_amount: f64,
+    pub mean_amount: f64,
+    /** Sample variance of the amounts, zero for less than two. */
+    pub amount_variance: f64,
+    /**
+     * Years actually simulated, fewer than configured
+     * when the run stopped early on convergence.
+     * A year cut short by `max_days` counts.
+     */
+    pub simulated_years: usize,
+    /** Days actually simulated, the warm-up included. */
+    pub simulated_days: usize,
     pub pool_results: Vec<PoolResults>,
+    /** Config the run was started with, after all the overrides. */
+    pub effective_config: SimConfig,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricSummary {
+    pub mean: f64,
+    /** Sample standard deviation, zero when there is a single run. */
+    pub std_dev: f64,
+}
+
+#[derive(Serialize)]
+pub struct PoolEnsembleResults {
+    pub pool_name: String,
+    pub total_number_of_transactions_during_withdrawals: MetricSummary,
+    pub withdrawal_events: MetricSummary,
+    pub total_number_of_accounts: MetricSummary,
+    pub peak_parallel_accounts_touched: MetricSummary,
+    pub total_money_withdrawn: MetricSummary,
+    pub total_number_of_queued_transactions: MetricSummary,
+}
+
+#[derive(Serialize)]
+pub struct EnsembleResults {
+    pub runs: usize,
+    pub total_number_of_transactions: MetricSummary,
+    pub peak_parallel_transactions_number: MetricSummary,
+    pub pool_results: Vec<PoolEnsembleResults>,
+}
+
+/** Change of a metric from one run to another. */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct MetricDiff {
+    pub metric: &'static str,
+    pub before: f64,
+    pub after: f64,
+    pub delta: f64,
+    /** Relative change in percent, none if the metric was zero. */
+    pub percent_change: Option<f64>,
+}
+
+impl MetricDiff {
+    pub fn new(metric: &'static str, before: f64, after: f64) -> Self {
+        Self {
+            metric,
+            before,
+            after,
+            delta: after - before,
+            percent_change: (before != 0.0)
+                .then(|| (after - before) / before.abs() * 100.0),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PoolDiff {
+    pub pool_name: String,
+    pub metrics: Vec<MetricDiff>,
+}
+
+/** Changes between the results of two runs, made by `diff`. */
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ResultsDiff {
+    pub metrics: Vec<MetricDiff>,
+    /** Pools of both runs, matched by name. */
+    pub pool_results: Vec<PoolDiff>,
+    /** Pools simulated in only one of the runs. */
+    pub unmatched_pools: Vec<String>,
+}
+
+/**
+ * Expected figures of a run, estimated by `estimate_transactions`
+ * without simulating it.
+ */
+#[derive(Serialize)]
+pub struct TransactionEstimate {
+    /** Seed the shop sizes were sampled with. */
+    pub seed: u64,
+    /** Expected number of transactions past the warm-up. */
+    pub expected_transactions: f64,
+    /** Part of `expected_transactions` brought by the sales. */
+    pub expected_sale_transactions: f64,
+    pub expected_transactions_per_day: f64,
+    /**
+     * Rough number of bytes taken by the generated days, which
+     * are kept for the whole run, the warm-up included.
+     * Pools are not counted.
+     */
+    pub expected_memory_bytes: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "simulated_shops_number": 10,
+        "simulated_years_number": 1,
+        "shop_size_distribution": {"type": "normal", "mean": 1, "std": 0.5},
+        "sales_per_year_for_each_shop": 5,
+        "sale_multiplier": 10,
+        "default_daily_multipliers": "1",
+        "default_daily_distribution": "5",
+        "price_distribution": {"type": "normal", "mean": 50, "std": 30},
+        "withdrawal_period_in_days": 30
+    }"#;
+
+    fn parse_config(json: &str) -> SimConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_sim_config_builder() {
+        assert!(validate_config(&SimConfig::default()).is_ok());
+
+        let config = SimConfig::builder()
+            .simulated_shops_number(10)
+            .holidays(vec![HolidaySpec {
+                day: 3,
+                multiplier: 2.0,
+            }])
+            .withdrawal_policy(WithdrawalPolicy::Threshold { amount: 5.0 })
+            .build();
+        assert!(validate_config(&config).is_ok());
+        assert_eq!(config.simulated_shops_number, 10);
+        assert_eq!(config.simulated_years_number, 1);
+        assert_eq!(config.holidays.len(), 1);
+        assert_eq!(
+            config.withdrawal_policy,
+            Some(WithdrawalPolicy::Threshold { amount: 5.0 })
+        );
+        assert_eq!(
+            config.default_daily_distribution.eval(2.0).unwrap(),
+            [2.0; HOURS_IN_DAY]
+        );
+    }
+
+    #[test]
+    fn test_config_serialization_round_trip() {
+        let config = parse_config(&CONFIG.replace(
+            r#""default_daily_multipliers": "1""#,
+            r#""default_daily_multipliers": "math::sin(d / 100.0) + 1.5""#,
+        ));
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(parse_config(&json), config);
+    }
+
+    #[test]
+    fn test_withdrawal_fee() {
+        let withdrawal = Withdrawal {
+            total_transactions: 4,
+            total_money: 150.0,
+            ..Withdrawal::default()
+        };
+
+        let flat = WithdrawalFee::Flat { per_tx: 0.5 };
+        assert_eq!(flat.fee(&withdrawal), 2.0);
+        let percent = WithdrawalFee::Percent { rate: 0.01 };
+        assert_eq!(percent.fee(&withdrawal), 1.5);
+        let tiered = WithdrawalFee::Tiered {
+            tiers: vec![(0.0, 0.02), (100.0, 0.01), (200.0, 0.0)],
+        };
+        assert!((tiered.fee(&withdrawal) - 2.5).abs() < 1e-12);
+
+        let unordered = WithdrawalFee::Tiered {
+            tiers: vec![(100.0, 0.01), (0.0, 0.02)],
+        };
+        assert!(unordered.validate().is_err());
+    }
+
+    #[test]
+    fn test_withdrawal_fee_accepts_bare_number() {
+        let config = parse_config(&CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_period_in_days": 30, "per_transaction_fee": 0.5"#,
+        ));
+        assert_eq!(config.withdrawal_fee, WithdrawalFee::Flat { per_tx: 0.5 });
+
+        let config = parse_config(&CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_period_in_days": 30,
+               "withdrawal_fee": {"type": "percent", "rate": 0.01}"#,
+        ));
+        assert_eq!(
+            config.withdrawal_fee,
+            WithdrawalFee::Percent { rate: 0.01 }
+        );
+    }
+
+    #[test]
+    fn test_config_seed() {
+        let seed = config_seed(&parse_config(CONFIG));
+        assert_eq!(seed, config_seed(&parse_config(CONFIG)));
+
+        let config = parse_config(&CONFIG.replace(
+            r#""simulated_shops_number": 10"#,
+            r#""simulated_shops_number": 11"#,
+        ));
+        assert_ne!(seed, config_seed(&config));
+
+        let mut config = parse_config(CONFIG);
+        config.profile = true;
+        assert_eq!(seed, config_seed(&config));
+
+        // The seed depends on the values rather than on how the file
+        // spells them, so a config written back keeps its seed.
+        let example = parse_config(include_str!("../config.json"));
+        let written = serde_json::to_string_pretty(&example).unwrap();
+        assert_eq!(config_seed(&example), config_seed(&parse_config(&written)));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(&parse_config(CONFIG)).is_ok());
+
+        let config = parse_config(&CONFIG.replace(
+            r#""simulated_shops_number": 10"#,
+            r#""simulated_shops_number": 0"#,
+        ));
+        assert!(validate_config(&config).is_err());
+
+        let mut config = parse_config(CONFIG);
+        config.price_distribution = Distribution::Normal {
+            mean: -50.0,
+            std: 30.0,
+        };
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("price_distribution"));
+
+        let mut config = parse_config(CONFIG);
+        config.sale_timing = SaleTiming::Spaced { mean_gap_days: 0.5 };
+        assert!(validate_config(&config).is_err());
+
+        let mut config = parse_config(CONFIG);
+        config.max_account_balance = Some(0.0);
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("max_account_balance"));
+
+        let config = SimConfig::builder().destinations_per_shop(0).build();
+        assert!(validate_config(&config).is_err());
+
+        let config = SimConfig::builder()
+            .shop_size_scaling(ShopSizeScaling::Power { exponent: -1.0 })
+            .build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("shop_size_scaling"));
+
+        let config = SimConfig::builder().refund_rate(1.5).build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("refund_rate"));
+
+        let config = SimConfig::builder()
+            .withdrawal_policy(WithdrawalPolicy::Threshold { amount: 10.0 })
+            .withdrawal_offset_days(5)
+            .build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("withdrawal_offset_days"));
+        let config = SimConfig::builder().withdrawal_offset_days(5).build();
+        assert!(validate_config(&config).is_ok());
+
+        let config = SimConfig::builder()
+            .convergence_epsilon(0.01)
+            .independent_years(true)
+            .build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("independent_years"));
+        let config = SimConfig::builder()
+            .convergence_epsilon(0.01)
+            .convergence_window(0)
+            .build();
+        assert!(validate_config(&config).is_err());
+
+        let config = SimConfig::builder()
+            .simulated_years_number(2)
+            .warmup_years(1)
+            .max_days(DAYS_IN_YEAR)
+            .build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("max_days"));
+        let config = SimConfig::builder()
+            .simulated_years_number(2)
+            .warmup_years(1)
+            .max_days(DAYS_IN_YEAR + 1)
+            .build();
+        assert!(validate_config(&config).is_ok());
+
+        let config = SimConfig::builder()
+            .transaction_duration_minutes(0.0)
+            .build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("transaction_duration_minutes"));
+
+        let config = SimConfig::builder().withdrawal_failure_rate(1.0).build();
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("withdrawal_failure_rate"));
+    }
+
+    #[test]
+    fn test_negative_expressions_are_rejected() {
+        for (from, to) in [
+            (r#""default_daily_distribution": "5""#, "-5"),
+            (r#""default_daily_distribution": "5""#, "h - 100"),
+            (r#""default_daily_multipliers": "1""#, "-5"),
+            (r#""default_daily_multipliers": "1""#, "d - 100"),
+        ] {
+            let field = from.split(':').next().unwrap();
+            let json = CONFIG.replace(from, &format!(r#"{field}: "{to}""#));
+            let err = serde_json::from_str::<SimConfig>(&json).unwrap_err();
+            assert!(err.to_string().contains("must be non-negative"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_zero_withdrawal_period_is_rejected() {
+        let json = CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_period_in_days": 0"#,
+        );
+        let err = serde_json::from_str::<SimConfig>(&json).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("withdrawal_period_in_days must be positive"));
+    }
+
+    #[test]
+    fn test_zero_currencies_are_rejected() {
+        let json = CONFIG.replace(
+            r#""simulated_shops_number": 10,"#,
+            r#""simulated_shops_number": 10,
+            "simulated_currencies_number": 0,"#,
+        );
+        let err = serde_json::from_str::<SimConfig>(&json).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("simulated_currencies_number must be positive"));
+    }
+
+    #[test]
+    fn test_withdrawal_policy() {
+        let config = parse_config(CONFIG);
+        assert_eq!(
+            config.withdrawal_policy,
+            Some(WithdrawalPolicy::Periodic { days: 30 })
+        );
+
+        let config = parse_config(&CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_policy": {"type": "threshold", "amount": 1000}"#,
+        ));
+        assert_eq!(
+            config.withdrawal_policy,
+            Some(WithdrawalPolicy::Threshold { amount: 1000.0 })
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_period_in_hours() {
+        let config = parse_config(&CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_period_in_hours": 6"#,
+        ));
+        assert_eq!(config.withdrawal_policy, None);
+        assert_eq!(
+            config.effective_withdrawal_policy(),
+            WithdrawalPolicy::Hourly { hours: 6 }
+        );
+        assert!(validate_config(&config).is_ok());
+
+        let config = parse_config(&CONFIG.replace(
+            r#""withdrawal_period_in_days": 30"#,
+            r#""withdrawal_period_in_days": 30,
+               "withdrawal_period_in_hours": 6"#,
+        ));
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("mutually exclusive"));
+
+        let config = SimConfig::builder().withdrawal_period_in_hours(0).build();
+        assert!(validate_config(&config).is_err());
+        assert_eq!(
+            SimConfig::default().effective_withdrawal_policy(),
+            WithdrawalPolicy::Periodic { days: 1 }
+        );
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let properties = &schema["properties"];
+
+        let required = schema["required"].as_array().unwrap();
+        assert!(required.contains(&"simulated_shops_number".into()));
+        assert!(!required.contains(&"withdrawal_fee".into()));
+        assert_eq!(
+            properties["simulated_years_number"]["description"],
+            "Number of years for which simulation is run."
+        );
+        assert_eq!(properties["default_daily_distribution"]["type"], "string");
+        assert!(properties["withdrawal_policy"]["anyOf"].is_array());
+        assert_eq!(
+            properties["simulated_regions_number"]["description"],
+            "Number of regions the shops are spread over \
+             as described by `region_assignment`."
+        );
+    }
+
+    #[test]
+    fn test_shop_overrides() {
+        let config = parse_config(&CONFIG.replace(
+            r#""simulated_shops_number": 10,"#,
+            r#""simulated_shops_number": 10,
+            "shop_overrides": {
+                "0": {"size": 50},
+                "3": {"daily_distribution": "h", "daily_multipliers": "2"}
+            },"#,
+        ));
+        assert_eq!(config.shop_overrides[&0].size, Some(50.0));
+        assert_eq!(config.shop_overrides[&0].daily_distribution, None);
+        assert_eq!(
+            config.shop_overrides[&3].daily_distribution,
+            Some(DailyOrdersExpr::new("h").unwrap())
+        );
+        assert_eq!(
+            config.shop_overrides[&3].daily_multipliers,
+            Some([2.0; DAYS_IN_YEAR])
+        );
+        assert!(validate_config(&config).is_ok());
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<SimConfig>(&json).unwrap(), config);
+
+        let mut config = config;
+        config.simulated_shops_number = 3;
+        assert!(validate_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_parse_shop_sizes() {
+        assert_eq!(
+            parse_shop_sizes("1\n 2.5 \n\n3\n").unwrap(),
+            [1.0, 2.5, 3.0]
+        );
+        assert_eq!(parse_shop_sizes("[1, 2.5, 3]").unwrap(), [1.0, 2.5, 3.0]);
+        assert!(parse_shop_sizes("").is_err());
+        assert!(parse_shop_sizes("[]").is_err());
+        assert!(parse_shop_sizes("1\nlarge\n").is_err());
+        assert!(parse_shop_sizes("1\n0\n").is_err());
+        assert!(parse_shop_sizes("[1, -2]").is_err());
+    }
+
+    #[test]
+    fn test_assign_regions_round_robin() {
+        let config = SimConfig::builder().simulated_regions_number(3).build();
+        assert_eq!(assign_regions(&config, 7).unwrap(), [0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_assign_regions_random() {
+        let config = |seed| {
+            SimConfig::builder()
+                .simulated_regions_number(4)
+                .region_assignment(RegionAssignment::Random { seed })
+                .build()
+        };
+
+        let regions = assign_regions(&config(7), 1000).unwrap();
+        assert_eq!(regions, assign_regions(&config(7), 1000).unwrap());
+        assert_ne!(regions, assign_regions(&config(8), 1000).unwrap());
+        for region in 0..4 {
+            let shops = regions.iter().filter(|&&r| r == region).count();
+            assert!((200..300).contains(&shops), "{} shops", shops);
+        }
+    }
+
+    #[test]
+    fn test_assign_regions_by_expression() {
+        let config = |expr: &str| {
+            SimConfig::builder()
+                .simulated_regions_number(2)
+                .region_assignment(RegionAssignment::ByExpression {
+                    expr: expr.to_string(),
+                })
+                .build()
+        };
+
+        assert_eq!(
+            assign_regions(&config("shop_id / 3"), 7).unwrap(),
+            [0, 0, 0, 1, 1, 1, 0]
+        );
+        assert_eq!(
+            assign_regions(&config("if(shop_id < 2, 1, 0)"), 4).unwrap(),
+            [1, 1, 0, 0]
+        );
+        assert!(assign_regions(&config("shop_id - 1"), 2).is_err());
+        assert!(validate_config(&config("shop_id +")).is_err());
+    }
 }
@@ -0,0 +1,27 @@
+pub mod data;
+pub mod pool;
+pub mod sim;
+pub mod util;
+
+pub use data::{
+    assign_regions, config_schema, config_seed, load_shop_sizes,
+    parse_shop_sizes, validate_config, CurrencyId, DailyOrdersExpr,
+    Distribution, EnsembleResults, HolidaySpec, MethodTiming, MetricDiff,
+    MetricSummary, OrderCountNoise, PoolDiff, PoolEnsembleResults, PoolResults,
+    PoolTimings, RegionAssignment, ResultsDiff, SaleTiming, ShopOverride,
+    ShopSizeScaling, SimConfig, SimConfigBuilder, SimResults, Transaction,
+    TransactionEstimate, WithdrawalFee, WithdrawalPolicy,
+};
+pub use pool::{
+    AccountsPool, CappedPool, NoWithdrawalPool, PoolError, PoolKind,
+    PoolPerRegion, PoolPerShop, PoolSnapshot, SinglePool, SinglePoolGreedy,
+    SinglePoolWithSingleAccount, Withdrawal,
+};
+pub use sim::{
+    diff, estimate_transactions, run_ensemble, run_simulation,
+    run_simulation_reusing, run_simulation_streaming,
+    run_simulation_with_options, simulate_day, AnnualData, DailyData,
+    EnsembleStats, GlobalData, GlobalStats, OnTransaction, PoolStats, Progress,
+    RunOptions, TransactionStream,
+};
+pub use util::{CountingRng, NumberFormat, RngCounts, Welford};
@@ -1,123 +1,2036 @@
-#![feature(array_try_map)]
-
-use anyhow::Result;
-use clap::Parser;
-use data::{SimConfig, SimResults};
-use pool::{PoolPerShop, SinglePool, SinglePoolWithSingleAccount};
-use rand::{rngs::SmallRng, RngCore, SeedableRng};
-use sim::{AnnualData, GlobalData, GlobalStats, PoolStats};
-use std::fs::File;
-
-use crate::{
-    data::DAYS_IN_YEAR,
-    sim::{simulate_day, DailyData},
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use hex_sim::{
+    config_schema, config_seed, data::DAYS_IN_YEAR, diff,
+    estimate_transactions, run_ensemble, run_simulation_reusing,
+    run_simulation_streaming, run_simulation_with_options, validate_config,
+    AccountsPool, EnsembleResults, MetricDiff, MetricSummary, NumberFormat,
+    PoolKind, PoolResults, Progress, ResultsDiff, RngCounts, RunOptions,
+    SimConfig, SimResults, TransactionEstimate,
 };
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::RngCore;
+use rayon::prelude::*;
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Write},
+    iter,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::{info, Level};
 
-mod data;
-mod pool;
-mod sim;
-mod util;
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
+enum Format {
+    #[default]
+    Text,
+    Json,
+    /// One row per pool with the global metrics repeated.
+    Csv,
+}
+
+/** How `--human` shortens the numbers of the text output. */
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HumanNumbers {
+    /// Thousands separators, as in 123,456,789.
+    Separators,
+    /// SI suffixes, as in 123.5M.
+    Si,
+}
 
 struct Args {
+    scenarios: Vec<Scenario>,
+    seed: Option<u64>,
+    deterministic: bool,
+    format: Format,
+    output: Option<String>,
+    runs: Option<NonZeroUsize>,
+    pools: Vec<PoolKind>,
+    trace: Option<String>,
+    dump_accounts: Option<String>,
+    progress: bool,
+    count_rng: bool,
+    stream: bool,
+    number_format: NumberFormat,
+}
+
+struct EstimateArgs {
     config: SimConfig,
     seed: Option<u64>,
+    deterministic: bool,
+    format: Format,
+}
+
+struct SweepArgs {
+    config: SimConfig,
+    seeds: Range<u64>,
+    output: Option<String>,
+    pools: Vec<PoolKind>,
+    parallel: bool,
+    progress: bool,
+}
+
+struct DiffArgs {
+    before: String,
+    after: String,
+    format: Format,
+}
+
+enum Command {
+    Run(Args),
+    Estimate(EstimateArgs),
+    Sweep(SweepArgs),
+    Diff(DiffArgs),
+    Validate(Vec<Scenario>),
+    Schema,
+}
+
+/**
+ * Config fields which can be overridden from the command line.
+ */
+#[derive(clap::Args, Debug, Default)]
+struct Overrides {
+    /// Simulate this many years instead of the number in the config.
+    #[arg(long)]
+    years: Option<usize>,
+
+    /// Stop after this many days, even in the middle of a year.
+    #[arg(long)]
+    max_days: Option<NonZeroUsize>,
+
+    /// Simulate this many shops instead of the number in the config.
+    #[arg(long)]
+    shops: Option<NonZeroUsize>,
+
+    /// Start every year with fresh pools and simulate years
+    /// in parallel. Nothing is carried over between years.
+    #[arg(long)]
+    independent_years: bool,
+
+    /// Leave out all the sales to see the baseline demand alone.
+    #[arg(long)]
+    no_sales: bool,
+
+    /// Read the shop sizes from this file, one per line
+    /// or as a JSON array, instead of sampling them.
+    #[arg(long)]
+    shop_sizes_file: Option<PathBuf>,
+
+    /// Stop early once the transactions per withdrawal of every
+    /// pool change by less than this fraction between withdrawals.
+    #[arg(long, value_name = "EPSILON")]
+    converge: Option<f64>,
+
+    /// Number of withdrawals in a row which must stay within
+    /// the --converge fraction.
+    #[arg(long, requires = "converge")]
+    converge_window: Option<NonZeroUsize>,
+
+    /// Measure the time each pool spends processing transactions
+    /// and withdrawing.
+    #[arg(long)]
+    profile: bool,
+
+    /// Generate the shops and the sale days from this seed
+    /// instead of the seed of the run.
+    #[arg(long)]
+    world_seed: Option<u64>,
+
+    /// Generate the order counts and the prices from this seed
+    /// instead of the seed of the run.
+    #[arg(long)]
+    transaction_seed: Option<u64>,
 }
 
-fn read_args() -> Result<Args> {
+impl Overrides {
+    fn apply(&self, config: &mut SimConfig) {
+        if let Some(years) = self.years {
+            config.simulated_years_number = years;
+        }
+        if let Some(max_days) = self.max_days {
+            config.max_days = Some(max_days.get());
+        }
+        if let Some(shops) = self.shops {
+            config.simulated_shops_number = shops.get();
+        }
+        if self.independent_years {
+            config.independent_years = true;
+        }
+        if self.no_sales {
+            config.disable_sales = true;
+        }
+        if let Some(shop_sizes_file) = &self.shop_sizes_file {
+            config.shop_sizes_file = Some(shop_sizes_file.clone());
+        }
+        if let Some(epsilon) = self.converge {
+            config.convergence_epsilon = Some(epsilon);
+        }
+        if let Some(window) = self.converge_window {
+            config.convergence_window = window.get();
+        }
+        if self.profile {
+            config.profile = true;
+        }
+        if let Some(world_seed) = self.world_seed {
+            config.world_seed = Some(world_seed);
+        }
+        if let Some(transaction_seed) = self.transaction_seed {
+            config.transaction_seed = Some(transaction_seed);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /**
+     * Picks the format by the file extension.
+     * Anything unrecognized is read as JSON.
+     */
+    fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, text: &str) -> Result<T> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+        })
+    }
+}
+
+/**
+ * Contents of a config file: a single config or several
+ * named scenarios, each a whole config, to run together.
+ */
+#[derive(Debug, PartialEq)]
+enum ConfigFile {
+    Single(Box<SimConfig>),
+    Scenarios(BTreeMap<String, SimConfig>),
+}
+
+/** Tells a config file holding scenarios from a single config. */
+#[derive(Deserialize)]
+struct ScenariosProbe {
+    scenarios: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenariosConfig {
+    scenarios: BTreeMap<String, SimConfig>,
+}
+
+/** Config with the name of its scenario, if it comes from one. */
+struct Scenario {
+    name: Option<String>,
+    config: SimConfig,
+}
+
+impl ConfigFile {
+    /**
+     * Returns the scenario called `name`, or all the scenarios
+     * in the order of their names if no name is given.
+     * A single config is a scenario without a name.
+     */
+    fn select(self, name: Option<&str>) -> Result<Vec<Scenario>> {
+        match (self, name) {
+            (ConfigFile::Single(config), None) => Ok(vec![Scenario {
+                name: None,
+                config: *config,
+            }]),
+            (ConfigFile::Single(_), Some(name)) => {
+                bail!("config has no scenarios to pick {} from", name)
+            }
+            (ConfigFile::Scenarios(mut scenarios), Some(name)) => {
+                let config = scenarios
+                    .remove(name)
+                    .with_context(|| format!("no scenario named {}", name))?;
+                Ok(vec![Scenario {
+                    name: Some(name.to_string()),
+                    config,
+                }])
+            }
+            (ConfigFile::Scenarios(scenarios), None) => {
+                ensure!(!scenarios.is_empty(), "config holds no scenarios");
+                Ok(scenarios
+                    .into_iter()
+                    .map(|(name, config)| Scenario {
+                        name: Some(name),
+                        config,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /** Same as `select`, but there must be a single config left. */
+    fn select_one(self, name: Option<&str>) -> Result<SimConfig> {
+        let mut scenarios = self.select(name)?;
+        ensure!(
+            scenarios.len() == 1,
+            "config holds several scenarios, pick one with --scenario"
+        );
+        Ok(scenarios.remove(0).config)
+    }
+}
+
+/**
+ * Reads the config from the file at `path`
+ * or, as JSON, from stdin if `path` is `-`.
+ */
+fn read_config(path: &str) -> Result<ConfigFile> {
+    if path == "-" {
+        parse_config_from(io::stdin().lock(), "stdin", ConfigFormat::Json)
+    } else {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open config {}", path))?;
+        parse_config_from(
+            BufReader::new(file),
+            path,
+            ConfigFormat::from_path(path),
+        )
+    }
+}
+
+fn parse_config_from(
+    mut reader: impl Read,
+    source: &str,
+    format: ConfigFormat,
+) -> Result<ConfigFile> {
+    let mut text = String::new();
+    reader
+        .read_to_string(&mut text)
+        .with_context(|| format!("failed to read config from {}", source))?;
+    ensure!(!text.trim().is_empty(), "config from {} is empty", source);
+    let malformed = || format!("malformed config from {}", source);
+    let probe: ScenariosProbe = format.parse(&text).with_context(malformed)?;
+    Ok(if probe.scenarios.is_some() {
+        let config: ScenariosConfig =
+            format.parse(&text).with_context(malformed)?;
+        ConfigFile::Scenarios(config.scenarios)
+    } else {
+        ConfigFile::Single(format.parse(&text).with_context(malformed)?)
+    })
+}
+
+/** Reads the results of a run written with `--format json`. */
+fn read_results(path: &str) -> Result<SimResults> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open results {}", path))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("malformed results from {}", path))
+}
+
+fn read_args() -> Result<Command> {
     #[derive(Parser, Debug)]
     #[command(author, version, about, long_about = None)]
     struct CmdArgs {
+        #[command(subcommand)]
+        command: CmdCommand,
+
+        /// Most verbose level of the diagnostics written to stderr.
+        #[arg(long, global = true, default_value_t = Level::INFO)]
+        log_level: Level,
+    }
+
+    #[derive(Subcommand, Debug)]
+    enum CmdCommand {
+        /// Run the simulation.
+        Run(RunArgs),
+
+        /// Estimate the transactions and the memory of a run
+        /// without simulating it.
+        Estimate {
+            /// Path to the JSON, YAML or TOML config,
+            /// `-` to read JSON from stdin.
+            #[arg(long, short)]
+            config: String,
+
+            /// Scenario to estimate if the config holds several.
+            #[arg(long)]
+            scenario: Option<String>,
+
+            /// Seed the shop sizes are sampled with.
+            #[arg(long, short)]
+            seed: Option<u64>,
+
+            /// Derive the seed from the config instead of picking
+            /// a random one when no seed is given.
+            #[arg(long, short, conflicts_with = "seed")]
+            deterministic: bool,
+
+            #[arg(long, short, value_enum, default_value_t)]
+            format: Format,
+
+            #[command(flatten)]
+            overrides: Overrides,
+        },
+
+        /// Run the simulation for each seed of a range and write
+        /// a CSV row with the key metrics of every run.
+        Sweep {
+            /// Path to the JSON, YAML or TOML config,
+            /// `-` to read JSON from stdin.
+            #[arg(long, short)]
+            config: String,
+
+            /// Scenario to sweep if the config holds several.
+            #[arg(long)]
+            scenario: Option<String>,
+
+            /// First seed of the range.
+            #[arg(long)]
+            seed_start: u64,
+
+            /// Seed right past the end of the range.
+            #[arg(long)]
+            seed_end: u64,
+
+            #[arg(long, short)]
+            output: Option<String>,
+
+            /// Comma-separated list of pools to simulate, all by default.
+            #[arg(long, short, value_delimiter = ',')]
+            pools: Vec<PoolKind>,
+
+            /// Run the seeds in parallel. Rows are then written
+            /// in the order the runs complete.
+            #[arg(long)]
+            parallel: bool,
+
+            /// Show a progress bar on stderr.
+            /// Ignored when stderr is not a terminal.
+            #[arg(long)]
+            progress: bool,
+
+            #[command(flatten)]
+            overrides: Overrides,
+        },
+
+        /// Compare the JSON results of two runs
+        /// and show how each metric changed.
+        Diff {
+            /// Results of the run to compare against.
+            before: String,
+
+            /// Results of the changed run.
+            after: String,
+
+            #[arg(long, short, value_enum, default_value_t)]
+            format: Format,
+        },
+
+        /// Check the config without running the simulation.
+        Validate {
+            #[arg(long, short)]
+            config: String,
+        },
+
+        /// Print the JSON Schema of the config.
+        Schema,
+    }
+
+    #[derive(clap::Args, Debug)]
+    struct RunArgs {
+        /// Path to the JSON, YAML or TOML config,
+        /// `-` to read JSON from stdin.
         #[arg(long, short)]
         config: String,
 
+        /// Run only this scenario of a config holding several,
+        /// instead of all of them one after another.
+        #[arg(long)]
+        scenario: Option<String>,
+
+        /// Seed of the run, shared by all the scenarios.
         #[arg(long, short)]
         seed: Option<u64>,
+
+        /// Derive the seed from the config instead of picking
+        /// a random one when no seed is given.
+        #[arg(long, short, conflicts_with = "seed")]
+        deterministic: bool,
+
+        #[arg(long, short, value_enum, default_value_t)]
+        format: Format,
+
+        #[arg(long, short)]
+        output: Option<String>,
+
+        /// Make the numbers of the text output easier to read,
+        /// with thousands separators unless `si` is given.
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            default_missing_value = "separators"
+        )]
+        human: Option<HumanNumbers>,
+
+        /// Written in front of monetary amounts in the text output.
+        #[arg(long, default_value = "")]
+        currency_symbol: String,
+
+        /// Run the simulation this many times with consecutive seeds
+        /// and report the mean and standard deviation of each metric.
+        #[arg(long, short)]
+        runs: Option<NonZeroUsize>,
+
+        /// Comma-separated list of pools to simulate, all by default.
+        #[arg(long, short, value_delimiter = ',')]
+        pools: Vec<PoolKind>,
+
+        /// Write per-day figures of the simulation to this CSV file.
+        #[arg(long, short, conflicts_with = "runs")]
+        trace: Option<String>,
+
+        /// Write the balances of all accounts right before each
+        /// withdrawal to this CSV file, one row per withdrawal.
+        /// Needs a single pool selected with --pools.
+        /// Rows are written as the simulation goes, and for long
+        /// runs with many accounts the file gets large.
+        #[arg(long, conflicts_with = "runs")]
+        dump_accounts: Option<String>,
+
+        /// Show a progress bar on stderr.
+        /// Ignored when stderr is not a terminal.
+        #[arg(long)]
+        progress: bool,
+
+        /// Log how many values were drawn from the random number
+        /// generators, to check that a change keeps the draws intact.
+        #[arg(long, conflicts_with = "runs")]
+        count_rng: bool,
+
+        /// Generate the transactions as the pools consume them
+        /// instead of keeping the whole run in memory.
+        /// Each pool regenerates the input, so runs take longer.
+        #[arg(
+            long,
+            conflicts_with_all = ["runs", "trace", "dump_accounts", "count_rng"]
+        )]
+        stream: bool,
+
+        #[command(flatten)]
+        overrides: Overrides,
     }
 
-    let args = CmdArgs::parse();
-    let file = File::open(args.config)?;
-    let config: SimConfig = serde_json::from_reader(file)?;
+    let cmd_args = CmdArgs::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(cmd_args.log_level)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
+
+    let args = match cmd_args.command {
+        CmdCommand::Run(args) => args,
+        CmdCommand::Estimate {
+            config,
+            scenario,
+            seed,
+            deterministic,
+            format,
+            overrides,
+        } => {
+            let mut config =
+                read_config(&config)?.select_one(scenario.as_deref())?;
+            overrides.apply(&mut config);
+            return Ok(Command::Estimate(EstimateArgs {
+                config,
+                seed,
+                deterministic,
+                format,
+            }));
+        }
+        CmdCommand::Sweep {
+            config,
+            scenario,
+            seed_start,
+            seed_end,
+            output,
+            pools,
+            parallel,
+            progress,
+            overrides,
+        } => {
+            ensure!(
+                seed_start < seed_end,
+                "--seed-end must be greater than --seed-start"
+            );
+            let mut config =
+                read_config(&config)?.select_one(scenario.as_deref())?;
+            overrides.apply(&mut config);
+            return Ok(Command::Sweep(SweepArgs {
+                config,
+                seeds: seed_start..seed_end,
+                output,
+                pools: if pools.is_empty() {
+                    PoolKind::ALL.to_vec()
+                } else {
+                    pools
+                },
+                parallel,
+                progress,
+            }));
+        }
+        CmdCommand::Diff {
+            before,
+            after,
+            format,
+        } => {
+            return Ok(Command::Diff(DiffArgs {
+                before,
+                after,
+                format,
+            }));
+        }
+        CmdCommand::Validate { config } => {
+            return Ok(Command::Validate(read_config(&config)?.select(None)?));
+        }
+        CmdCommand::Schema => return Ok(Command::Schema),
+    };
 
-    Ok(Args {
-        config,
+    let mut scenarios =
+        read_config(&args.config)?.select(args.scenario.as_deref())?;
+    for scenario in &mut scenarios {
+        args.overrides.apply(&mut scenario.config);
+        match &scenario.name {
+            Some(name) => validate_config(&scenario.config)
+                .with_context(|| format!("scenario {}", name))?,
+            None => validate_config(&scenario.config)?,
+        }
+    }
+
+    Ok(Command::Run(Args {
+        scenarios,
         seed: args.seed,
-    })
+        deterministic: args.deterministic,
+        format: args.format,
+        output: args.output,
+        runs: args.runs,
+        pools: if args.pools.is_empty() {
+            PoolKind::ALL.to_vec()
+        } else {
+            args.pools
+        },
+        trace: args.trace,
+        dump_accounts: args.dump_accounts,
+        progress: args.progress && io::stderr().is_terminal(),
+        count_rng: args.count_rng,
+        stream: args.stream,
+        number_format: NumberFormat {
+            separators: matches!(args.human, Some(HumanNumbers::Separators)),
+            si_suffixes: matches!(args.human, Some(HumanNumbers::Si)),
+            currency_symbol: args.currency_symbol,
+        },
+    }))
 }
 
-fn write_results(results: SimResults) -> Result<()> {
-    println!(
+/**
+ * Shows the simulation progress as a bar on stderr.
+ */
+struct ProgressBarProgress(ProgressBar);
+
+impl ProgressBarProgress {
+    fn new(visible: bool) -> Self {
+        let bar = ProgressBar::with_draw_target(
+            Some(0),
+            if visible {
+                ProgressDrawTarget::stderr()
+            } else {
+                ProgressDrawTarget::hidden()
+            },
+        );
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{elapsed_precise} [{wide_bar}] {percent}% (eta {eta})",
+            )
+            .expect("the progress template is valid"),
+        );
+        Self(bar)
+    }
+}
+
+impl Progress for ProgressBarProgress {
+    fn add_total(&self, days: u64) {
+        self.0.inc_length(days);
+    }
+
+    fn advance(&self, days: u64) {
+        self.0.inc(days);
+    }
+}
+
+/** Results of a scenario of `run`. */
+#[derive(Serialize)]
+#[serde(untagged)]
+enum RunResults {
+    Single(Box<SimResults>),
+    Ensemble(EnsembleResults),
+}
+
+/** Results tagged with their scenario in the JSON output. */
+#[derive(Serialize)]
+struct ScenarioResults {
+    scenario: Option<String>,
+    #[serde(flatten)]
+    results: RunResults,
+}
+
+/**
+ * Writes the results of each scenario one after another.
+ * With named scenarios, the text gets a line with the name,
+ * the JSON becomes an array of results with their names,
+ * and the CSV gets a leading column with the name.
+ * Only the text of single runs follows the number format.
+ */
+fn write_run_results(
+    out: &mut dyn Write,
+    results: Vec<(Option<String>, RunResults)>,
+    format: Format,
+    number_format: &NumberFormat,
+) -> Result<()> {
+    let named = results.iter().any(|(name, _)| name.is_some());
+    if let Format::Json = format {
+        if named {
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|(scenario, results)| ScenarioResults {
+                    scenario,
+                    results,
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut *out, &results)?;
+        } else {
+            for (_, results) in &results {
+                serde_json::to_writer_pretty(&mut *out, results)?;
+            }
+        }
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for (i, (name, results)) in results.into_iter().enumerate() {
+        let first = i == 0;
+        match format {
+            Format::Text => {
+                if !first {
+                    writeln!(out)?;
+                }
+                if let Some(name) = &name {
+                    writeln!(out, "Scenario: {}", name)?;
+                }
+                match results {
+                    RunResults::Single(results) => {
+                        write_text_results(out, *results, number_format)?
+                    }
+                    RunResults::Ensemble(results) => {
+                        write_text_ensemble_results(out, results)?
+                    }
+                }
+            }
+            Format::Csv => match results {
+                RunResults::Single(results) => {
+                    write_csv_results(out, *results, name.as_deref(), first)?
+                }
+                RunResults::Ensemble(results) => write_csv_ensemble_results(
+                    out,
+                    results,
+                    name.as_deref(),
+                    first,
+                )?,
+            },
+            Format::Json => unreachable!("JSON is written above"),
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Writes one row per pool, with the header if `header` is set.
+ * The histogram and the config don't fit into a row
+ * and are left out. A `scenario` goes in the first column.
+ */
+fn write_csv_results(
+    out: &mut dyn Write,
+    results: SimResults,
+    scenario: Option<&str>,
+    header: bool,
+) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    let columns = [
+        "pool_name",
+        "total_number_of_transactions_during_withdrawals",
+        "withdrawal_events",
+        "last_balance_min",
+        "last_balance_p25",
+        "last_balance_median",
+        "last_balance_p75",
+        "last_balance_max",
+        "average_gini",
+        "failed_withdrawal_transactions",
+        "total_number_of_accounts",
+        "peak_accounts",
+        "peak_parallel_accounts_touched",
+        "total_money_withdrawn",
+        "total_fees",
+        "total_number_of_queued_transactions",
+        "extra_accounts_due_to_settlement",
+        "accounts_created_due_to_cap",
+        "average_active_shops_per_period",
+        "peak_active_shops_per_period",
+        "accounts_created",
+        "accounts_freed",
+        "average_dust_left",
+        "average_money_left",
+        "seed",
+        "total_number_of_transactions",
+        "peak_parallel_transactions_number",
+        "peak_concurrent_transactions",
+        "total_number_of_clamped_order_counts",
+        "total_transaction_volume",
+        "min_amount",
+        "max_amount",
+        "mean_amount",
+        "amount_variance",
+        "simulated_years",
+        "simulated_days",
+    ];
+    if header {
+        let scenario_column = scenario.map(|_| "scenario");
+        writer.write_record(scenario_column.into_iter().chain(columns))?;
+    }
+    for pool_results in &results.pool_results {
+        let [min, p25, median, p75, max] =
+            pool_results.last_balance_percentiles;
+        let record = [
+            pool_results.pool_name.to_string(),
+            pool_results
+                .total_number_of_transactions_during_withdrawals
+                .to_string(),
+            pool_results.withdrawal_events.to_string(),
+            min.to_string(),
+            p25.to_string(),
+            median.to_string(),
+            p75.to_string(),
+            max.to_string(),
+            pool_results.average_gini.to_string(),
+            pool_results.failed_withdrawal_transactions.to_string(),
+            pool_results.total_number_of_accounts.to_string(),
+            pool_results.peak_accounts.to_string(),
+            pool_results.peak_parallel_accounts_touched.to_string(),
+            pool_results.total_money_withdrawn.to_string(),
+            pool_results.total_fees.to_string(),
+            pool_results.total_number_of_queued_transactions.to_string(),
+            pool_results.extra_accounts_due_to_settlement.to_string(),
+            pool_results.accounts_created_due_to_cap.to_string(),
+            pool_results.average_active_shops_per_period.to_string(),
+            pool_results.peak_active_shops_per_period.to_string(),
+            pool_results.accounts_created.to_string(),
+            pool_results.accounts_freed.to_string(),
+            pool_results.average_dust_left.to_string(),
+            pool_results.average_money_left.to_string(),
+            results.seed.to_string(),
+            results.total_number_of_transactions.to_string(),
+            results.peak_parallel_transactions_number.to_string(),
+            results.peak_concurrent_transactions.to_string(),
+            results.total_number_of_clamped_order_counts.to_string(),
+            results.total_transaction_volume.to_string(),
+            results.min_amount.to_string(),
+            results.max_amount.to_string(),
+            results.mean_amount.to_string(),
+            results.amount_variance.to_string(),
+            results.simulated_years.to_string(),
+            results.simulated_days.to_string(),
+        ];
+        writer.write_record(
+            scenario.map(str::to_string).into_iter().chain(record),
+        )?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/** Width of the longest bar of a histogram in characters. */
+const HISTOGRAM_BAR_WIDTH: usize = 40;
+
+/**
+ * Draws one bar per bin, scaled so that the fullest bin
+ * gets `HISTOGRAM_BAR_WIDTH` characters.
+ */
+fn write_histogram(
+    out: &mut dyn Write,
+    bin_width: usize,
+    histogram: &[usize],
+) -> Result<()> {
+    let max_count = histogram.iter().copied().max().unwrap_or(0).max(1);
+    for (bin, &count) in histogram.iter().enumerate() {
+        let low = bin * bin_width;
+        let range = if bin + 1 == histogram.len() {
+            format!("{}+", low)
+        } else {
+            format!("{}-{}", low, low + bin_width - 1)
+        };
+        let bar = "#".repeat(count * HISTOGRAM_BAR_WIDTH / max_count);
+        writeln!(
+            out,
+            "{:>16} |{:<width$} {}",
+            range,
+            bar,
+            count,
+            width = HISTOGRAM_BAR_WIDTH
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_text_results(
+    out: &mut dyn Write,
+    results: SimResults,
+    number_format: &NumberFormat,
+) -> Result<()> {
+    let count = |value| number_format.count(value);
+    let money = |value| number_format.money(value);
+    writeln!(
+        out,
         "Total number of transactions: {}",
-        results.total_number_of_transactions
-    );
-    println!(
+        count(results.total_number_of_transactions)
+    )?;
+    let configured_years = results.effective_config.simulated_years_number;
+    let configured_days = results.effective_config.total_days();
+    if results.simulated_days < configured_days {
+        writeln!(
+            out,
+            "Converged after {} of {} years",
+            results.simulated_years, configured_years
+        )?;
+    } else if configured_days < configured_years * DAYS_IN_YEAR {
+        writeln!(
+            out,
+            "Stopped after {} of {} days",
+            configured_days,
+            configured_years * DAYS_IN_YEAR
+        )?;
+    }
+    let destinations_per_shop = results.effective_config.destinations_per_shop;
+    if destinations_per_shop > 1 {
+        writeln!(
+            out,
+            "Withdrawals fan out to {} destinations per shop",
+            destinations_per_shop
+        )?;
+    }
+    writeln!(
+        out,
         "Peak parallel transactions number: {}",
-        results.peak_parallel_transactions_number
-    );
+        count(results.peak_parallel_transactions_number)
+    )?;
+    writeln!(
+        out,
+        "Peak concurrent transactions: {}",
+        count(results.peak_concurrent_transactions)
+    )?;
+    writeln!(
+        out,
+        "Clamped order counts: {}",
+        count(results.total_number_of_clamped_order_counts)
+    )?;
+    writeln!(
+        out,
+        "Total transaction volume: {}",
+        money(results.total_transaction_volume)
+    )?;
+    writeln!(
+        out,
+        "Transaction amount: min {}, max {}, mean {}, variance {:.2}",
+        money(results.min_amount),
+        money(results.max_amount),
+        money(results.mean_amount),
+        results.amount_variance
+    )?;
+    writeln!(out, "Hours by number of transactions:")?;
+    write_histogram(
+        out,
+        results.transactions_per_hour_bin_width,
+        &results.transactions_per_hour_histogram,
+    )?;
 
-    for pool_results in results.pool_results {
-        println!("");
-        println!("Results for {}:", pool_results.pool_name);
-        println!(
+    for pool_results in &results.pool_results {
+        writeln!(out)?;
+        writeln!(out, "Results for {}:", pool_results.pool_name)?;
+        writeln!(
+            out,
             "Total number of accounts: {}",
+            count(pool_results.total_number_of_accounts)
+        )?;
+        writeln!(
+            out,
+            "Peak number of accounts: {}",
+            count(pool_results.peak_accounts)
+        )?;
+        if pool_results.accounts_per_currency.len() > 1 {
+            let accounts: Vec<_> = pool_results
+                .accounts_per_currency
+                .iter()
+                .map(|(currency, &accounts)| {
+                    format!("{}: {}", currency, count(accounts))
+                })
+                .collect();
+            writeln!(out, "Accounts per currency: {}", accounts.join(", "))?;
+        }
+        writeln!(
+            out,
+            "Peak parallel accounts touched: {}",
+            count(pool_results.peak_parallel_accounts_touched)
+        )?;
+        writeln!(
+            out,
+            "Total money withdrawn: {}",
+            money(pool_results.total_money_withdrawn)
+        )?;
+        writeln!(out, "Total fees: {}", money(pool_results.total_fees))?;
+        writeln!(
+            out,
+            "Total number of queued transactions: {}",
+            count(pool_results.total_number_of_queued_transactions)
+        )?;
+        writeln!(
+            out,
+            "Extra accounts due to settlement delay: {}",
+            count(pool_results.extra_accounts_due_to_settlement)
+        )?;
+        writeln!(
+            out,
+            "Accounts created due to the balance cap: {}",
+            count(pool_results.accounts_created_due_to_cap)
+        )?;
+        writeln!(
+            out,
+            "Total number of transactions during withdrawals: {}",
+            count(pool_results.total_number_of_transactions_during_withdrawals)
+        )?;
+        if pool_results.failed_withdrawal_transactions > 0 {
+            writeln!(
+                out,
+                "Failed withdrawal transactions, retried: {}",
+                count(pool_results.failed_withdrawal_transactions)
+            )?;
+        }
+        writeln!(
+            out,
+            "Withdrawal events: {}",
+            count(pool_results.withdrawal_events)
+        )?;
+        let [min, p25, median, p75, max] =
+            pool_results.last_balance_percentiles;
+        writeln!(
+            out,
+            "Account balances before the last withdrawal: \
+             min {}, p25 {}, median {}, p75 {}, max {}",
+            money(min),
+            money(p25),
+            money(median),
+            money(p75),
+            money(max)
+        )?;
+        writeln!(
+            out,
+            "Average Gini of account balances: {:.3}",
+            pool_results.average_gini
+        )?;
+        let average_transactions_per_withdrawal =
+            if pool_results.withdrawal_events > 0 {
+                pool_results.total_number_of_transactions_during_withdrawals
+                    as f64
+                    / pool_results.withdrawal_events as f64
+            } else {
+                0.0
+            };
+        writeln!(
+            out,
+            "Average transactions per withdrawal: {:.2}",
+            average_transactions_per_withdrawal
+        )?;
+        writeln!(
+            out,
+            "Active shops per withdrawal period: average {:.2}, peak {}",
+            pool_results.average_active_shops_per_period,
+            count(pool_results.peak_active_shops_per_period)
+        )?;
+        writeln!(
+            out,
+            "Accounts created: {}, freed: {}",
+            count(pool_results.accounts_created),
+            count(pool_results.accounts_freed)
+        )?;
+        writeln!(
+            out,
+            "Average dust left per withdrawal: {}",
+            money(pool_results.average_dust_left)
+        )?;
+        writeln!(
+            out,
+            "Average money left per withdrawal: {}",
+            money(pool_results.average_money_left)
+        )?;
+        if let Some(timings) = &pool_results.timings {
+            for (method, timing) in [
+                ("process_transactions", timings.process_transactions),
+                ("withdraw_all", timings.withdraw_all),
+            ] {
+                writeln!(
+                    out,
+                    "Time in {}: {:.3} s over {} calls, {:.1} µs per call",
+                    method,
+                    timing.total_seconds,
+                    timing.calls,
+                    timing.average_seconds * 1e6
+                )?;
+            }
+        }
+    }
+
+    writeln!(out)?;
+    write_ranking(out, &results.pool_results)?;
+
+    Ok(())
+}
+
+/**
+ * Writes a table of the pools ordered by the number of withdrawal
+ * transactions, fewest first, with the best pool marked.
+ */
+fn write_ranking(
+    out: &mut dyn Write,
+    pool_results: &[PoolResults],
+) -> Result<()> {
+    let mut ranked: Vec<&PoolResults> = pool_results.iter().collect();
+    ranked.sort_by_key(|pool_results| {
+        pool_results.total_number_of_transactions_during_withdrawals
+    });
+
+    let name_width = ranked
+        .iter()
+        .map(|pool_results| pool_results.pool_name.len())
+        .chain(["Pool".len()])
+        .max()
+        .unwrap_or_default();
+
+    writeln!(out, "Ranking by withdrawal transactions:")?;
+    writeln!(
+        out,
+        "  {:<4}  {:<name_width$}  {:>15}  {:>10}",
+        "Rank", "Pool", "Withdrawal txs", "Accounts"
+    )?;
+    for (i, pool_results) in ranked.iter().enumerate() {
+        writeln!(
+            out,
+            "{} {:<4}  {:<name_width$}  {:>15}  {:>10}",
+            if i == 0 { '*' } else { ' ' },
+            i + 1,
+            pool_results.pool_name,
+            pool_results.total_number_of_transactions_during_withdrawals,
             pool_results.total_number_of_accounts
-        );
-        println!(
+        )?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Writes one row per pool with the mean and the standard
+ * deviation of each metric in columns of their own,
+ * like `write_csv_results`.
+ */
+fn write_csv_ensemble_results(
+    out: &mut dyn Write,
+    results: EnsembleResults,
+    scenario: Option<&str>,
+    header: bool,
+) -> Result<()> {
+    fn summary_header(name: &str) -> [String; 2] {
+        [format!("{}_mean", name), format!("{}_std_dev", name)]
+    }
+
+    fn summary_fields(summary: MetricSummary) -> [String; 2] {
+        [summary.mean.to_string(), summary.std_dev.to_string()]
+    }
+
+    let mut writer = csv::Writer::from_writer(out);
+    let mut columns: Vec<_> = scenario
+        .map(|_| "scenario".to_string())
+        .into_iter()
+        .collect();
+    columns.extend(["pool_name".to_string(), "runs".to_string()]);
+    for name in [
+        "total_number_of_transactions_during_withdrawals",
+        "withdrawal_events",
+        "total_number_of_accounts",
+        "peak_parallel_accounts_touched",
+        "total_money_withdrawn",
+        "total_number_of_queued_transactions",
+        "total_number_of_transactions",
+        "peak_parallel_transactions_number",
+    ] {
+        columns.extend(summary_header(name));
+    }
+    if header {
+        writer.write_record(&columns)?;
+    }
+    for pool_results in &results.pool_results {
+        let mut record: Vec<_> =
+            scenario.map(str::to_string).into_iter().collect();
+        record.extend([
+            pool_results.pool_name.to_string(),
+            results.runs.to_string(),
+        ]);
+        for summary in [
+            pool_results.total_number_of_transactions_during_withdrawals,
+            pool_results.withdrawal_events,
+            pool_results.total_number_of_accounts,
+            pool_results.peak_parallel_accounts_touched,
+            pool_results.total_money_withdrawn,
+            pool_results.total_number_of_queued_transactions,
+            results.total_number_of_transactions,
+            results.peak_parallel_transactions_number,
+        ] {
+            record.extend(summary_fields(summary));
+        }
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_text_ensemble_results(
+    out: &mut dyn Write,
+    results: EnsembleResults,
+) -> Result<()> {
+    fn fmt(summary: MetricSummary) -> String {
+        format!("mean {:.2}, std {:.2}", summary.mean, summary.std_dev)
+    }
+
+    writeln!(out, "Number of runs: {}", results.runs)?;
+    writeln!(
+        out,
+        "Total number of transactions: {}",
+        fmt(results.total_number_of_transactions)
+    )?;
+    writeln!(
+        out,
+        "Peak parallel transactions number: {}",
+        fmt(results.peak_parallel_transactions_number)
+    )?;
+
+    for pool_results in results.pool_results {
+        writeln!(out)?;
+        writeln!(out, "Results for {}:", pool_results.pool_name)?;
+        writeln!(
+            out,
+            "Total number of accounts: {}",
+            fmt(pool_results.total_number_of_accounts)
+        )?;
+        writeln!(
+            out,
+            "Peak parallel accounts touched: {}",
+            fmt(pool_results.peak_parallel_accounts_touched)
+        )?;
+        writeln!(
+            out,
+            "Total money withdrawn: {}",
+            fmt(pool_results.total_money_withdrawn)
+        )?;
+        writeln!(
+            out,
+            "Total number of queued transactions: {}",
+            fmt(pool_results.total_number_of_queued_transactions)
+        )?;
+        writeln!(
+            out,
             "Total number of transactions during withdrawals: {}",
-            pool_results.total_number_of_transactions_during_withdrawals
-        );
+            fmt(pool_results.total_number_of_transactions_during_withdrawals)
+        )?;
+        writeln!(
+            out,
+            "Withdrawal events: {}",
+            fmt(pool_results.withdrawal_events)
+        )?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Writes the change of each metric as text, as JSON,
+ * or as a CSV row per metric with an empty pool
+ * for the global metrics.
+ */
+fn write_diff(
+    out: &mut dyn Write,
+    results_diff: &ResultsDiff,
+    format: Format,
+) -> Result<()> {
+    fn percent(metric: &MetricDiff) -> String {
+        match metric.percent_change {
+            Some(percent) => format!("{:+.2}%", percent),
+            None => "n/a".to_string(),
+        }
+    }
+
+    let sections = iter::once(("", &results_diff.metrics)).chain(
+        results_diff.pool_results.iter().map(|pool_diff| {
+            (pool_diff.pool_name.as_str(), &pool_diff.metrics)
+        }),
+    );
+    match format {
+        Format::Text => {
+            let metric_width = sections
+                .clone()
+                .flat_map(|(_, metrics)| metrics)
+                .map(|metric| metric.metric.len())
+                .max()
+                .unwrap_or_default();
+            for (i, (pool_name, metrics)) in sections.enumerate() {
+                if i == 0 {
+                    writeln!(out, "Global metrics:")?;
+                } else {
+                    writeln!(out)?;
+                    writeln!(out, "Results for {}:", pool_name)?;
+                }
+                for metric in metrics {
+                    writeln!(
+                        out,
+                        "  {:<metric_width$}  {:>14} -> {:>14}  {:>+14}  {:>9}",
+                        metric.metric,
+                        metric.before,
+                        metric.after,
+                        metric.delta,
+                        percent(metric)
+                    )?;
+                }
+            }
+            if !results_diff.unmatched_pools.is_empty() {
+                writeln!(out)?;
+                writeln!(
+                    out,
+                    "Pools in only one of the runs: {}",
+                    results_diff.unmatched_pools.join(", ")
+                )?;
+            }
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, results_diff)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record([
+                "pool_name",
+                "metric",
+                "before",
+                "after",
+                "delta",
+                "percent_change",
+            ])?;
+            for (pool_name, metrics) in sections {
+                for metric in metrics {
+                    writer.write_record([
+                        pool_name.to_string(),
+                        metric.metric.to_string(),
+                        metric.before.to_string(),
+                        metric.after.to_string(),
+                        metric.delta.to_string(),
+                        metric
+                            .percent_change
+                            .map(|percent| percent.to_string())
+                            .unwrap_or_default(),
+                    ])?;
+                }
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Writes the estimate as text, as JSON or as a CSV
+ * with a single row.
+ */
+fn write_estimate(
+    out: &mut dyn Write,
+    estimate: TransactionEstimate,
+    format: Format,
+) -> Result<()> {
+    match format {
+        Format::Text => {
+            writeln!(out, "Seed: {}", estimate.seed)?;
+            writeln!(
+                out,
+                "Expected transactions: {:.0}",
+                estimate.expected_transactions
+            )?;
+            writeln!(
+                out,
+                "Expected transactions from sales: {:.0}",
+                estimate.expected_sale_transactions
+            )?;
+            writeln!(
+                out,
+                "Expected transactions per day: {:.1}",
+                estimate.expected_transactions_per_day
+            )?;
+            writeln!(
+                out,
+                "Expected memory for the days: {:.1} MiB",
+                estimate.expected_memory_bytes / (1024.0 * 1024.0)
+            )?;
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, &estimate)?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            writer.write_record([
+                "seed",
+                "expected_transactions",
+                "expected_sale_transactions",
+                "expected_transactions_per_day",
+                "expected_memory_bytes",
+            ])?;
+            writer.write_record([
+                estimate.seed.to_string(),
+                estimate.expected_transactions.to_string(),
+                estimate.expected_sale_transactions.to_string(),
+                estimate.expected_transactions_per_day.to_string(),
+                estimate.expected_memory_bytes.to_string(),
+            ])?;
+            writer.flush()?;
+        }
     }
 
     Ok(())
 }
 
+/**
+ * Runs the simulation for every seed of `seeds` and writes a CSV
+ * row with the key metrics of each run and each pool as soon as
+ * the run completes. With `parallel`, the runs go in parallel
+ * and the rows are written in the order they complete.
+ */
+fn write_sweep(
+    out: &mut (dyn Write + Send),
+    config: &SimConfig,
+    seeds: Range<u64>,
+    pools: &[PoolKind],
+    parallel: bool,
+    progress: &dyn Progress,
+) -> Result<()> {
+    const POOL_METRICS: [&str; 5] = [
+        "withdrawal_transactions",
+        "withdrawal_events",
+        "accounts",
+        "money_withdrawn",
+        "queued_transactions",
+    ];
+
+    let mut writer = csv::Writer::from_writer(out);
+    let mut header = vec![
+        "seed".to_string(),
+        "total_number_of_transactions".to_string(),
+        "peak_parallel_transactions_number".to_string(),
+        "peak_concurrent_transactions".to_string(),
+    ];
+    for pool in pools {
+        header.extend(
+            POOL_METRICS
+                .iter()
+                .map(|metric| format!("{}.{}", pool.id(), metric)),
+        );
+    }
+    writer.write_record(&header)?;
+    writer.flush()?;
+
+    let writer = Mutex::new(writer);
+    // Each thread keeps its pools and resets them for the next seed.
+    let run = |seed: u64,
+               reusable: &mut Vec<Box<dyn AccountsPool + Send>>|
+     -> Result<()> {
+        let results =
+            run_simulation_reusing(config, seed, pools, reusable, progress)?;
+        let mut row = vec![
+            results.seed.to_string(),
+            results.total_number_of_transactions.to_string(),
+            results.peak_parallel_transactions_number.to_string(),
+            results.peak_concurrent_transactions.to_string(),
+        ];
+        for pool_results in &results.pool_results {
+            row.extend([
+                pool_results
+                    .total_number_of_transactions_during_withdrawals
+                    .to_string(),
+                pool_results.withdrawal_events.to_string(),
+                pool_results.total_number_of_accounts.to_string(),
+                pool_results.total_money_withdrawn.to_string(),
+                pool_results.total_number_of_queued_transactions.to_string(),
+            ]);
+        }
+        let mut writer = writer.lock().expect("no sweep writer panics");
+        writer.write_record(&row)?;
+        writer.flush()?;
+        Ok(())
+    };
+    if parallel {
+        seeds
+            .into_par_iter()
+            .try_for_each_init(Vec::new, |reusable, seed| run(seed, reusable))
+    } else {
+        let mut reusable = vec![];
+        seeds
+            .into_iter()
+            .try_for_each(|seed| run(seed, &mut reusable))
+    }
+}
+
+/**
+ * Returns `seed` if given, otherwise the seed derived from
+ * the config if `deterministic` or a random one.
+ */
+fn pick_seed(
+    seed: Option<u64>,
+    deterministic: bool,
+    config: &SimConfig,
+) -> u64 {
+    seed.unwrap_or_else(|| {
+        if deterministic {
+            config_seed(config)
+        } else {
+            rand::thread_rng().next_u64()
+        }
+    })
+}
+
 fn main() -> Result<()> {
-    let Args { config, seed } = read_args()?;
+    match read_args()? {
+        Command::Run(args) => run(args),
+        Command::Sweep(args) => {
+            validate_config(&args.config)?;
+            let mut out: Box<dyn Write + Send> = match args.output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let progress = ProgressBarProgress::new(args.progress);
+            write_sweep(
+                &mut out,
+                &args.config,
+                args.seeds,
+                &args.pools,
+                args.parallel,
+                &progress,
+            )?;
+            progress.0.finish_and_clear();
+            Ok(())
+        }
+        Command::Estimate(args) => {
+            validate_config(&args.config)?;
+            let seed = pick_seed(args.seed, args.deterministic, &args.config);
+            let estimate = estimate_transactions(&args.config, seed)?;
+            write_estimate(&mut io::stdout().lock(), estimate, args.format)
+        }
+        Command::Diff(args) => {
+            let results_diff =
+                diff(&read_results(&args.before)?, &read_results(&args.after)?);
+            write_diff(&mut io::stdout().lock(), &results_diff, args.format)
+        }
+        Command::Validate(scenarios) => {
+            for Scenario { name, config } in scenarios {
+                match name {
+                    Some(name) => validate_config(&config)
+                        .with_context(|| format!("scenario {}", name))?,
+                    None => validate_config(&config)?,
+                }
+            }
+            println!("OK");
+            Ok(())
+        }
+        Command::Schema => {
+            let mut out = io::stdout().lock();
+            serde_json::to_writer_pretty(&mut out, &config_schema())?;
+            writeln!(out)?;
+            Ok(())
+        }
+    }
+}
 
-    let seed = seed.unwrap_or_else(|| rand::thread_rng().next_u64());
-    let mut rng = SmallRng::seed_from_u64(seed);
+fn run(args: Args) -> Result<()> {
+    let Args {
+        scenarios,
+        seed,
+        deterministic,
+        format,
+        output,
+        runs,
+        pools,
+        trace,
+        dump_accounts,
+        progress,
+        count_rng,
+        stream,
+        number_format,
+    } = args;
 
-    println!("Seed: {}", seed);
+    ensure!(
+        dump_accounts.is_none() || pools.len() == 1,
+        "--dump-accounts needs a single pool selected with --pools"
+    );
+    ensure!(
+        scenarios.len() == 1 || (trace.is_none() && dump_accounts.is_none()),
+        "--trace and --dump-accounts need a single scenario \
+         picked with --scenario"
+    );
 
-    let global_data = GlobalData::gen(&mut rng, &config);
-    let mut global_stats = GlobalStats::default();
+    // Scenarios share a random seed, so that they differ
+    // in their configs only.
+    let seed = seed.or_else(|| {
+        (!deterministic && scenarios.len() > 1)
+            .then(|| rand::thread_rng().next_u64())
+    });
 
-    let mut pool_per_shop = PoolPerShop::new();
-    let mut single_pool = SinglePool::new();
-    let mut single_pool_with_single_account =
-        SinglePoolWithSingleAccount::new();
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
 
-    let mut pool_per_shop_stats = PoolStats::default();
-    let mut single_pool_stats = PoolStats::default();
-    let mut single_pool_with_single_account_stats = PoolStats::default();
+    let progress = ProgressBarProgress::new(progress);
+    let mut results = Vec::with_capacity(scenarios.len());
+    for Scenario { name, config } in scenarios {
+        if let Some(name) = &name {
+            info!("Scenario: {}", name);
+        }
+        let seed = pick_seed(seed, deterministic, &config);
+        info!("Seed: {}", seed);
 
-    for _year in 0..config.simulated_years_number {
-        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
-        for day in 0..DAYS_IN_YEAR {
-            let daily_data =
-                DailyData::gen(&mut rng, &config, &annual_data, day);
-            global_stats.update(&daily_data);
+        let scenario_results = match runs {
+            Some(runs) => RunResults::Ensemble(run_ensemble(
+                &config,
+                seed,
+                runs.get(),
+                &pools,
+                &progress,
+            )?),
+            None if stream => RunResults::Single(Box::new(
+                run_simulation_streaming(&config, seed, &pools, &progress)?,
+            )),
+            None => {
+                let mut trace = match &trace {
+                    Some(path) => Some(BufWriter::new(File::create(path)?)),
+                    None => None,
+                };
+                let mut dump = match &dump_accounts {
+                    Some(path) => Some(BufWriter::new(File::create(path)?)),
+                    None => None,
+                };
+                let mut rng_counts = RngCounts::default();
+                let results = run_simulation_with_options(
+                    &config,
+                    seed,
+                    &pools,
+                    RunOptions {
+                        trace: trace
+                            .as_mut()
+                            .map(|trace| trace as &mut dyn Write),
+                        accounts_dump: dump
+                            .as_mut()
+                            .map(|dump| dump as &mut (dyn Write + Send)),
+                        rng_counts: count_rng.then_some(&mut rng_counts),
+                        progress: &progress,
+                        ..RunOptions::default()
+                    },
+                )?;
+                if count_rng {
+                    info!(
+                        "RNG calls: next_u32 {}, next_u64 {}, fill_bytes {}",
+                        rng_counts.next_u32,
+                        rng_counts.next_u64,
+                        rng_counts.fill_bytes
+                    );
+                }
+                if let Some(mut trace) = trace {
+                    trace.flush()?;
+                }
+                if let Some(mut dump) = dump {
+                    dump.flush()?;
+                }
+                RunResults::Single(Box::new(results))
+            }
+        };
+        results.push((name, scenario_results));
+    }
+    progress.0.finish_and_clear();
 
-            simulate_day(
-                &daily_data,
-                &mut pool_per_shop,
-                &mut pool_per_shop_stats,
-            );
-            simulate_day(&daily_data, &mut single_pool, &mut single_pool_stats);
-            simulate_day(
-                &daily_data,
-                &mut single_pool_with_single_account,
-                &mut single_pool_with_single_account_stats,
+    write_run_results(&mut out, results, format, &number_format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_sim::{
+        run_simulation, AnnualData, Distribution, GlobalData, SaleTiming,
+    };
+    use rand::{rngs::SmallRng, SeedableRng};
+
+    fn example_config() -> SimConfig {
+        read_config(concat!(env!("CARGO_MANIFEST_DIR"), "/config.json"))
+            .unwrap()
+            .select_one(None)
+            .unwrap()
+    }
+
+    fn pool_results(
+        pool_name: &str,
+        withdrawal_transactions: usize,
+    ) -> PoolResults {
+        PoolResults {
+            pool_name: pool_name.to_string(),
+            total_number_of_transactions_during_withdrawals:
+                withdrawal_transactions,
+            withdrawal_events: 1,
+            last_balance_percentiles: [0.0; 5],
+            average_gini: 0.0,
+            failed_withdrawal_transactions: 0,
+            total_number_of_accounts: 10,
+            peak_accounts: 10,
+            accounts_per_currency: std::collections::BTreeMap::from([(0, 10)]),
+            peak_parallel_accounts_touched: 1,
+            total_money_withdrawn: 0.0,
+            total_fees: 0.0,
+            total_number_of_queued_transactions: 0,
+            extra_accounts_due_to_settlement: 0,
+            accounts_created_due_to_cap: 0,
+            average_active_shops_per_period: 0.0,
+            peak_active_shops_per_period: 0,
+            accounts_created: 10,
+            accounts_freed: 0,
+            average_dust_left: 0.0,
+            average_money_left: 0.0,
+            timings: None,
+        }
+    }
+
+    #[test]
+    fn test_ranking_marks_fewest_withdrawal_transactions() {
+        let mut out = Vec::new();
+        write_ranking(
+            &mut out,
+            &[pool_results("Worse", 20), pool_results("Better Pool", 5)],
+        )
+        .unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let rows: Vec<&str> = out.lines().skip(2).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("* 1     Better Pool"));
+        assert!(rows[1].starts_with("  2     Worse      "));
+    }
+
+    #[test]
+    fn test_years_override() {
+        let mut config = example_config();
+        Overrides::default().apply(&mut config);
+        assert_eq!(
+            config.simulated_years_number,
+            example_config().simulated_years_number
+        );
+
+        let overrides = Overrides {
+            years: Some(7),
+            ..Overrides::default()
+        };
+        overrides.apply(&mut config);
+        assert_eq!(config.simulated_years_number, 7);
+    }
+
+    #[test]
+    fn test_no_sales_override_keeps_base_multipliers() {
+        let mut config = example_config();
+        config.simulated_shops_number = 5;
+        config.sales_per_year_for_each_shop = 20;
+        config.sale_multiplier = 3.0;
+        let overrides = Overrides {
+            no_sales: true,
+            ..Overrides::default()
+        };
+        overrides.apply(&mut config);
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        for sale_timing in [
+            SaleTiming::Uniform,
+            SaleTiming::Spaced {
+                mean_gap_days: 10.0,
+            },
+        ] {
+            config.sale_timing = sale_timing;
+            let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+            for distribution in annual_data.shop_distributions {
+                assert_eq!(
+                    distribution.daily_multipliers,
+                    config.default_daily_multipliers
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_shops_override() {
+        let mut config = example_config();
+        let overrides = Overrides {
+            shops: NonZeroUsize::new(3),
+            ..Overrides::default()
+        };
+        overrides.apply(&mut config);
+        assert_eq!(config.simulated_shops_number, 3);
+
+        #[derive(Parser)]
+        struct OverridesArgs {
+            #[command(flatten)]
+            overrides: Overrides,
+        }
+        let args = OverridesArgs::try_parse_from(["hex_sim", "--shops", "5"]);
+        assert_eq!(args.unwrap().overrides.shops, NonZeroUsize::new(5));
+        assert!(
+            OverridesArgs::try_parse_from(["hex_sim", "--shops", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_config_rejects_empty_input() {
+        let err =
+            parse_config_from(" \n".as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap_err();
+        assert_eq!(err.to_string(), "config from stdin is empty");
+    }
+
+    #[test]
+    fn test_parse_config_reports_malformed_input() {
+        let err =
+            parse_config_from("{".as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap_err();
+        assert_eq!(err.to_string(), "malformed config from stdin");
+    }
+
+    #[test]
+    fn test_parse_config_matches_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.json");
+        let text = std::fs::read_to_string(path).unwrap();
+        let config =
+            parse_config_from(text.as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap();
+        assert_eq!(config, ConfigFile::Single(Box::new(example_config())));
+    }
+
+    #[test]
+    fn test_two_scenarios_run_together() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.json");
+        let config = std::fs::read_to_string(path).unwrap();
+        let text = format!(
+            r#"{{"scenarios": {{"busy": {}, "baseline": {}}}}}"#,
+            config.replace(
+                r#""simulated_shops_number": 10"#,
+                r#""simulated_shops_number": 20"#
+            ),
+            config
+        );
+        let file =
+            parse_config_from(text.as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap();
+        assert!(matches!(&file, ConfigFile::Scenarios(scenarios)
+            if scenarios.len() == 2));
+
+        let overrides = Overrides {
+            years: Some(1),
+            max_days: NonZeroUsize::new(30),
+            ..Overrides::default()
+        };
+        let mut results = vec![];
+        for Scenario { name, mut config } in file.select(None).unwrap() {
+            config.warmup_years = 0;
+            overrides.apply(&mut config);
+            let run =
+                run_simulation(&config, 1, &[PoolKind::PoolPerShop]).unwrap();
+            results.push((name, RunResults::Single(Box::new(run))));
+        }
+        let mut out = Vec::new();
+        write_run_results(
+            &mut out,
+            results,
+            Format::Csv,
+            &NumberFormat::default(),
+        )
+        .unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let header = reader.headers().unwrap().clone();
+        let rows: Vec<_> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(&header[0], "scenario");
+        assert_eq!(rows.len(), 2);
+        // Scenarios run in the order of their names.
+        assert_eq!(&rows[0][0], "baseline");
+        assert_eq!(&rows[1][0], "busy");
+        let column = |name| header.iter().position(|h| h == name).unwrap();
+        let transactions = |row: &csv::StringRecord| -> usize {
+            row[column("total_number_of_transactions")].parse().unwrap()
+        };
+        assert!(transactions(&rows[1]) > transactions(&rows[0]));
+
+        let file =
+            parse_config_from(text.as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap();
+        let err = file.select(Some("missing")).err().unwrap();
+        assert_eq!(err.to_string(), "no scenario named missing");
+        let file =
+            parse_config_from(text.as_bytes(), "stdin", ConfigFormat::Json)
+                .unwrap();
+        let busy = file.select_one(Some("busy")).unwrap();
+        assert_eq!(busy.simulated_shops_number, 20);
+    }
+
+    #[test]
+    fn test_config_format_from_extension() {
+        assert_eq!(ConfigFormat::from_path("a.json"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("a.yaml"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("a.yml"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("a.toml"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path("config"), ConfigFormat::Json);
+    }
+
+    #[test]
+    fn test_yaml_and_toml_configs_match_json() {
+        let json = example_config();
+        for name in ["config.yaml", "config.toml"] {
+            let path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let config = read_config(&path).unwrap();
+            assert_eq!(
+                config,
+                ConfigFile::Single(Box::new(json.clone())),
+                "{}",
+                name
             );
         }
     }
 
-    let results = global_stats.results(vec![
-        pool_per_shop_stats.results(&pool_per_shop),
-        single_pool_stats.results(&single_pool),
-        single_pool_with_single_account_stats
-            .results(&single_pool_with_single_account),
-    ]);
-    write_results(results)?;
+    #[test]
+    fn test_histogram_bars_are_scaled_to_fullest_bin() {
+        let mut out = Vec::new();
+        write_histogram(&mut out, 5, &[10, 40, 0]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
 
-    Ok(())
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("             0-4 |##########     "));
+        assert!(lines[0].ends_with(" 10"));
+        assert!(lines[1].contains(&format!("|{} 40", "#".repeat(40))));
+        assert!(lines[2].starts_with("             10+ | "));
+    }
+
+    #[test]
+    fn test_sweep_writes_a_reproducible_row_per_seed() {
+        let mut config = example_config();
+        config.simulated_years_number = 1;
+        config.warmup_years = 0;
+        config.simulated_shops_number = 5;
+        // Whole amounts keep the sums exact whatever order
+        // the pools add them up in.
+        config.price_distribution = Distribution::Normal {
+            mean: 1.0,
+            std: 0.0,
+        };
+        let pools = [PoolKind::PoolPerShop, PoolKind::SinglePoolGreedy];
+
+        let sweep = |parallel| {
+            let mut out = Vec::new();
+            write_sweep(&mut out, &config, 3..6, &pools, parallel, &())
+                .unwrap();
+            let mut lines: Vec<String> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            lines[1..].sort();
+            lines
+        };
+
+        let lines = sweep(false);
+        assert_eq!(lines.len(), 1 + 3);
+        assert!(lines[0].starts_with("seed,"));
+        assert!(lines[0].contains("single-pool-greedy.accounts"));
+        assert!(lines[1].starts_with("3,"));
+        assert_eq!(lines[1].split(',').count(), 4 + 2 * 5);
+        assert_eq!(sweep(true), lines);
+    }
+
+    #[test]
+    fn test_csv_results_have_a_row_per_pool() {
+        let results = SimResults {
+            seed: 42,
+            total_number_of_transactions: 100,
+            peak_parallel_transactions_number: 3,
+            peak_concurrent_transactions: 3,
+            total_number_of_clamped_order_counts: 0,
+            transactions_per_hour_bin_width: 1,
+            transactions_per_hour_histogram: vec![],
+            total_transaction_volume: 250.0,
+            min_amount: 1.0,
+            max_amount: 5.0,
+            mean_amount: 2.5,
+            amount_variance: 1.0,
+            simulated_years: 1,
+            simulated_days: DAYS_IN_YEAR,
+            pool_results: vec![
+                pool_results("Pool, With Comma", 20),
+                pool_results("Other", 5),
+            ],
+            effective_config: SimConfig::default(),
+        };
+        let mut out = Vec::new();
+        write_csv_results(&mut out, results, None, true).unwrap();
+
+        let mut reader = csv::Reader::from_reader(out.as_slice());
+        let header = reader.headers().unwrap().clone();
+        let rows: Vec<_> = reader.records().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][0], "Pool, With Comma");
+        assert_eq!(&rows[1][0], "Other");
+        let column = |name| header.iter().position(|h| h == name).unwrap();
+        assert_eq!(
+            &rows[0][column("total_number_of_transactions_during_withdrawals")],
+            "20"
+        );
+        assert_eq!(&rows[1][column("seed")], "42");
+        assert_eq!(&rows[1][column("mean_amount")], "2.5");
+    }
 }
@@ -1,136 +1,783 @@
 use std::{
     cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
+    collections::{BTreeMap, BinaryHeap, HashMap, VecDeque},
+    error::Error,
+    fmt,
+    str::FromStr,
 };
 
+use anyhow::Result;
+use rand::{rngs::SmallRng, SeedableRng};
+use rand_distr::{Distribution as _, Geometric};
+
 use crate::{
-    data::{ShopId, Transaction},
-    util::F64AsKey,
+    data::{
+        assign_regions, CurrencyId, RegionId, ShopId, SimConfig, Transaction,
+    },
+    util::{percentiles, F64AsKey, KahanSum},
 };
 
+/**
+ * Summary of a single withdrawal.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Withdrawal {
+    /** Number of transactions needed to distribute the money. */
+    pub total_transactions: usize,
+    /** Amount of money distributed between shops. */
+    pub total_money: f64,
+    /**
+     * Money left in the accounts which were not drained,
+     * which carries forward to the next withdrawal.
+     */
+    pub money_left: f64,
+    /** Part of `money_left` in the accounts below the dust threshold. */
+    pub dust_left: f64,
+    /**
+     * Attempts which failed and had to be retried,
+     * already counted in `total_transactions`.
+     */
+    pub failed_transactions: usize,
+}
+
+/**
+ * Reasons for a pool to reject a batch of transactions.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PoolError {
+    /** The amount is infinite or NaN. */
+    InvalidAmount { shop_id: ShopId, amount: f64 },
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PoolError::InvalidAmount { shop_id, amount } => write!(
+                f,
+                "invalid amount {} of a transaction of shop {}",
+                amount, shop_id
+            ),
+        }
+    }
+}
+
+impl Error for PoolError {}
+
+/**
+ * Checks the whole batch before any of it is processed,
+ * so that a rejected batch leaves the pool unchanged.
+ */
+fn check_amounts(transactions: &[Transaction]) -> Result<(), PoolError> {
+    transactions.iter().try_for_each(check_amount)
+}
+
+fn check_amount(
+    &Transaction {
+        shop_id, amount, ..
+    }: &Transaction,
+) -> Result<(), PoolError> {
+    if amount.is_finite() {
+        Ok(())
+    } else {
+        Err(PoolError::InvalidAmount { shop_id, amount })
+    }
+}
+
+/**
+ * Marks the `fraction` of the balances with the most money,
+ * rounding the number of marked balances up.
+ */
+fn largest_fraction(balances: &[f64], fraction: f64) -> Vec<bool> {
+    let count = ((balances.len() as f64 * fraction).ceil() as usize)
+        .min(balances.len());
+    if count == balances.len() {
+        return vec![true; balances.len()];
+    }
+    let mut order: Vec<usize> = (0..balances.len()).collect();
+    order.sort_by(|&a, &b| balances[b].total_cmp(&balances[a]));
+    let mut largest = vec![false; balances.len()];
+    for &index in &order[..count] {
+        largest[index] = true;
+    }
+    largest
+}
+
+/**
+ * Adds `amount` to `balance` up to `max_balance`. Returns the new
+ * balance and the balances of the new accounts the rest spills
+ * into, each of them holding at most `max_balance`.
+ */
+fn add_capped(balance: f64, amount: f64, max_balance: f64) -> (f64, Vec<f64>) {
+    let total = balance + amount;
+    if total <= max_balance {
+        return (total, Vec::new());
+    }
+    let mut spilled = Vec::new();
+    let mut rest = total - balance.max(max_balance);
+    while rest > 0.0 {
+        let part = rest.min(max_balance);
+        spilled.push(part);
+        rest -= part;
+    }
+    (balance.max(max_balance), spilled)
+}
+
+/**
+ * Moves the `surplus` of drained money nobody was paid onto the
+ * `kept` accounts owing money after refunds, and the rest of it
+ * onto an account of its own. Without such accounts the surplus
+ * is only floating point error and is dropped.
+ */
+fn settle_owing_accounts(kept: &mut Vec<f64>, mut surplus: f64) {
+    if !kept.iter().any(|&balance| balance < 0.0) {
+        return;
+    }
+    for balance in kept.iter_mut().filter(|balance| **balance < 0.0) {
+        let settled = surplus.min(-*balance).max(0.0);
+        *balance += settled;
+        surplus -= settled;
+    }
+    if surplus > 0.0 {
+        kept.push(surplus);
+    }
+}
+
+/**
+ * Makes each transaction of the withdrawal fail with probability
+ * `failure_rate` and retries it until it goes through. Every failed
+ * attempt counts as a transaction of its own. Draws nothing from
+ * `rng` when transactions never fail.
+ */
+fn retry_failed_transactions(
+    withdrawal: &mut Withdrawal,
+    failure_rate: f64,
+    rng: &mut SmallRng,
+) {
+    if failure_rate == 0.0 {
+        return;
+    }
+    let failures =
+        Geometric::new(1.0 - failure_rate).expect("failure rate below one");
+    let failed: u64 = (0..withdrawal.total_transactions)
+        .map(|_| failures.sample(rng))
+        .sum();
+    withdrawal.failed_transactions += failed as usize;
+    withdrawal.total_transactions += failed as usize;
+}
+
+/**
+ * Owned copy of the balances held by a pool at some moment.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolSnapshot {
+    /** Balances of all accounts of the pool, in no particular order. */
+    pub account_balances: Vec<f64>,
+    /** Money received by each shop since the last withdrawal. */
+    pub shop_balances: HashMap<ShopId, f64>,
+}
+
 pub trait AccountsPool {
     /**
      * Process all transaction as though they happen in parallel.
+     *
+     * Returns the number of accounts touched by the batch.
+     * Fails without changing the pool if some amount is invalid.
+     */
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError>;
+
+    /**
+     * Same as `process_transactions`, but takes the batch as it
+     * is generated, so that pools processing it incrementally
+     * never hold the whole batch in memory. Those check each
+     * amount as it comes, so an invalid one leaves the part
+     * of the batch before it processed.
+     *
+     * By default the batch is collected and processed at once.
      */
-    fn process_transactions(&mut self, transactions: &[Transaction]);
+    fn process_transaction_stream(
+        &mut self,
+        transactions: &mut dyn Iterator<Item = Transaction>,
+    ) -> Result<usize, PoolError> {
+        let transactions: Vec<Transaction> = transactions.collect();
+        self.process_transactions(&transactions)
+    }
 
     /**
      * Withdraw all money from all accounts from the pool
      * and distribute between shops.
      *
-     * Returns the total number of transactions.
+     * Refunds may leave a shop owing money. Such a shop is not
+     * paid, and its debt is carried over and settled by the next
+     * money it receives. Accounts holding the debt are not drained.
+     *
+     * Returns the total number of transactions
+     * and the amount of money withdrawn.
      */
-    fn withdraw_all(&mut self) -> usize;
+    fn withdraw_all(&mut self) -> Withdrawal;
 
     /**
      * Returns the total number of accounts in all pools.
      */
     fn total_accounts(&self) -> usize;
 
+    /**
+     * Returns the largest number of accounts the pool held at once,
+     * counted after each batch since it was created or reset.
+     */
+    fn peak_accounts(&self) -> usize;
+
+    /**
+     * Returns the money held by all accounts of the pool.
+     */
+    fn total_balance(&self) -> f64;
+
+    /**
+     * Copies the current balances of the pool without changing it.
+     */
+    fn snapshot(&self) -> PoolSnapshot;
+
+    /**
+     * Returns the current balances of all accounts of the pool.
+     */
+    fn account_balances(&self) -> Vec<f64> {
+        self.snapshot().account_balances
+    }
+
+    /**
+     * Returns the min, p25, median, p75 and max
+     * of the account balances.
+     */
+    fn balance_percentiles(&self) -> [f64; 5] {
+        percentiles(self.account_balances())
+    }
+
     /**
      * Returns the name of the pool.
      * Receives &self to be object-safe.
      */
     fn name(&self) -> &'static str;
+
+    /**
+     * Returns the total number of transactions that could not get
+     * an account of their own and were queued onto a busy one.
+     */
+    fn queued_transactions(&self) -> usize {
+        0
+    }
+
+    /**
+     * Called at the end of each simulated day,
+     * after the withdrawal if there was one.
+     */
+    fn end_day(&mut self) {}
+
+    /**
+     * Empties the pool into the state it was created in, keeping
+     * its settings and, where it can, its allocations, so that it
+     * can be reused for another run. The generator given to
+     * `set_rng` is not restored and has to be set again.
+     */
+    fn reset(&mut self);
+
+    /**
+     * Returns the number of accounts opened while emptied accounts
+     * were still unavailable because of the settlement delay.
+     */
+    fn extra_accounts_due_to_settlement(&self) -> usize {
+        0
+    }
+
+    /**
+     * Returns the number of accounts opened so far for the money
+     * which would have pushed an account over its balance cap.
+     */
+    fn accounts_created_due_to_cap(&self) -> usize {
+        0
+    }
+
+    /**
+     * Returns the number of accounts torn down so far. Accounts
+     * emptied and recreated by a withdrawal count as torn down
+     * and then as created again.
+     */
+    fn accounts_freed(&self) -> usize {
+        0
+    }
+
+    /**
+     * Returns the number of accounts created so far, that is
+     * the accounts held now and the ones torn down.
+     */
+    fn accounts_created(&self) -> usize {
+        self.total_accounts() + self.accounts_freed()
+    }
+
+    /**
+     * Gives the pool a generator of its own for any random
+     * decisions, so that they neither interfere with the input
+     * nor with the other pools. Called right after creation.
+     */
+    fn set_rng(&mut self, _rng: SmallRng) {}
+
+    /**
+     * Returns the number of accounts held in each currency.
+     * Accounts of different currencies never share money.
+     */
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize>;
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PoolPerShop {
-    pools: HashMap<ShopId, Vec<f64>>,
+    pools: HashMap<(ShopId, CurrencyId), Vec<KahanSum>>,
+    dust_threshold: f64,
+    withdrawal_fraction: f64,
+    max_account_balance: f64,
+    accounts_created_due_to_cap: usize,
+    destinations_per_shop: usize,
+    peak_accounts: usize,
+    withdrawal_failure_rate: f64,
+    rng: SmallRng,
 }
 
-impl AccountsPool for PoolPerShop {
-    fn process_transactions(&mut self, transactions: &[Transaction]) {
-        let mut txs_per_shop = HashMap::<ShopId, Vec<f64>>::new();
-        for &Transaction { shop_id, amount } in transactions {
-            let txs = txs_per_shop.entry(shop_id).or_default();
-            txs.push(amount);
+impl Default for PoolPerShop {
+    fn default() -> Self {
+        Self {
+            pools: HashMap::new(),
+            dust_threshold: 0.0,
+            withdrawal_fraction: 1.0,
+            max_account_balance: f64::INFINITY,
+            accounts_created_due_to_cap: 0,
+            destinations_per_shop: 1,
+            peak_accounts: 0,
+            withdrawal_failure_rate: 0.0,
+            rng: SmallRng::seed_from_u64(0),
         }
+    }
+}
+
+impl AccountsPool for PoolPerShop {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        check_amounts(transactions)?;
+        self.process_transaction_stream(&mut transactions.iter().copied())
+    }
 
-        for (shop_id, txs) in txs_per_shop {
-            let pool = self.pools.entry(shop_id).or_default();
-            if pool.len() < txs.len() {
-                pool.resize(txs.len(), 0.0);
+    fn process_transaction_stream(
+        &mut self,
+        transactions: &mut dyn Iterator<Item = Transaction>,
+    ) -> Result<usize, PoolError> {
+        // The i-th transaction of a shop in the batch goes to its i-th
+        // account. Spilled accounts join the pool only after the batch,
+        // so that no transaction lands on an account touched before.
+        let mut next_accounts = HashMap::<(ShopId, CurrencyId), usize>::new();
+        let mut spilled_accounts = vec![];
+        let mut accounts_touched = 0;
+        let mut result = Ok(());
+        for tx in transactions {
+            if let Err(err) = check_amount(&tx) {
+                result = Err(err);
+                break;
             }
+            let key = (tx.shop_id, tx.currency);
+            let next_account = next_accounts.entry(key).or_default();
+            let pool = self.pools.entry(key).or_default();
+            if *next_account == pool.len() {
+                pool.push(KahanSum::default());
+            }
+            let account = &mut pool[*next_account];
+            *next_account += 1;
+            accounts_touched += 1;
 
-            for (account, amount) in pool.iter_mut().zip(txs) {
-                *account += amount;
+            if account.value() + tx.amount <= self.max_account_balance {
+                *account += tx.amount;
+                continue;
             }
+            let (balance, spilled) = add_capped(
+                account.value(),
+                tx.amount,
+                self.max_account_balance,
+            );
+            *account = balance.into();
+            accounts_touched += spilled.len();
+            self.accounts_created_due_to_cap += spilled.len();
+            spilled_accounts
+                .extend(spilled.into_iter().map(|balance| (key, balance)));
+        }
+        for (key, balance) in spilled_accounts {
+            self.pools.entry(key).or_default().push(balance.into());
         }
+        self.peak_accounts = self.peak_accounts.max(self.total_accounts());
+        result.map(|()| accounts_touched)
     }
 
-    fn withdraw_all(&mut self) -> usize {
-        for (_, pool) in &mut self.pools {
-            pool.fill(0.0);
+    fn withdraw_all(&mut self) -> Withdrawal {
+        let balances: Vec<f64> =
+            self.pools.values().flatten().map(KahanSum::value).collect();
+        let mut largest =
+            largest_fraction(&balances, self.withdrawal_fraction).into_iter();
+        let mut withdrawal = Withdrawal::default();
+        for pool in self.pools.values_mut() {
+            let mut money = 0.0;
+            for account in pool {
+                let largest = largest.next().unwrap_or_default();
+                // A negative balance is a refund the shop still owes.
+                let owed = account.value() < 0.0;
+                let dust = !owed && account.value() < self.dust_threshold;
+                if !largest || owed || dust {
+                    withdrawal.money_left += account.value();
+                    if dust {
+                        withdrawal.dust_left += account.value();
+                    }
+                } else {
+                    money += account.value();
+                    // Each drained account pays every destination its share.
+                    withdrawal.total_transactions += self.destinations_per_shop;
+                    *account = KahanSum::default();
+                }
+            }
+            withdrawal.total_money += money;
         }
-        self.total_accounts()
+        retry_failed_transactions(
+            &mut withdrawal,
+            self.withdrawal_failure_rate,
+            &mut self.rng,
+        );
+        withdrawal
     }
 
     fn total_accounts(&self) -> usize {
-        self.pools.iter().map(|(_, pool)| pool.len()).sum()
+        self.pools.values().map(|pool| pool.len()).sum()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.peak_accounts
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.pools.values().flatten().map(KahanSum::value).sum()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            account_balances: self
+                .pools
+                .values()
+                .flatten()
+                .map(KahanSum::value)
+                .collect(),
+            shop_balances: self.pools.iter().fold(
+                HashMap::new(),
+                |mut balances, (&(shop_id, _), pool)| {
+                    *balances.entry(shop_id).or_default() +=
+                        pool.iter().map(KahanSum::value).sum::<f64>();
+                    balances
+                },
+            ),
+        }
     }
 
     fn name(&self) -> &'static str {
         "Pool per Shop"
     }
+
+    fn reset(&mut self) {
+        self.pools.clear();
+        self.accounts_created_due_to_cap = 0;
+        self.peak_accounts = 0;
+    }
+
+    fn accounts_created_due_to_cap(&self) -> usize {
+        self.accounts_created_due_to_cap
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.rng = rng;
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        let mut accounts = BTreeMap::new();
+        for (&(_, currency), pool) in &self.pools {
+            *accounts.entry(currency).or_default() += pool.len();
+        }
+        accounts
+    }
 }
 
 impl PoolPerShop {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /**
+     * Accounts with less than `dust_threshold` on them
+     * are not drained by withdrawals.
+     */
+    pub fn with_dust_threshold(mut self, dust_threshold: f64) -> Self {
+        self.dust_threshold = dust_threshold;
+        self
+    }
+
+    /**
+     * Withdrawals drain only this fraction of the accounts,
+     * the ones with the most money.
+     */
+    pub fn with_withdrawal_fraction(
+        mut self,
+        withdrawal_fraction: f64,
+    ) -> Self {
+        self.withdrawal_fraction = withdrawal_fraction;
+        self
+    }
+
+    /**
+     * No account holds more than `max_account_balance`,
+     * the rest of a transaction spills into new accounts.
+     */
+    pub fn with_max_account_balance(
+        mut self,
+        max_account_balance: f64,
+    ) -> Self {
+        self.max_account_balance = max_account_balance;
+        self
+    }
+
+    /**
+     * Each shop splits its withdrawn money evenly between
+     * `destinations_per_shop` destination accounts.
+     */
+    pub fn with_destinations_per_shop(
+        mut self,
+        destinations_per_shop: usize,
+    ) -> Self {
+        self.destinations_per_shop = destinations_per_shop;
+        self
+    }
+
+    /**
+     * Each transaction of a withdrawal fails with probability
+     * `withdrawal_failure_rate` and is retried until it goes through.
+     */
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.withdrawal_failure_rate = withdrawal_failure_rate;
+        self
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct SinglePool {
-    pool: BinaryHeap<Reverse<F64AsKey>>,
-    shop_balances: HashMap<ShopId, f64>,
+    /** Accounts of each currency, which transactions never mix. */
+    pools: BTreeMap<CurrencyId, BinaryHeap<Reverse<F64AsKey>>>,
+    shop_balances: HashMap<(ShopId, CurrencyId), KahanSum>,
+    shortfall: f64,
+    settlement_days: usize,
+    current_day: usize,
+    /**
+     * Emptied accounts as triples of the day they are back,
+     * their currency and their number.
+     */
+    settling: VecDeque<(usize, CurrencyId, usize)>,
+    settling_accounts: usize,
+    extra_accounts_due_to_settlement: usize,
+    accounts_freed: usize,
+    dust_threshold: f64,
+    withdrawal_fraction: f64,
+    max_account_balance: f64,
+    accounts_created_due_to_cap: usize,
+    destinations_per_shop: usize,
+    peak_accounts: usize,
+    withdrawal_failure_rate: f64,
+    rng: SmallRng,
 }
 
-impl AccountsPool for SinglePool {
-    fn process_transactions(&mut self, transactions: &[Transaction]) {
-        let mut updated_accounts = vec![];
-        for &Transaction { shop_id, amount } in transactions {
-            let balance = self.shop_balances.entry(shop_id).or_default();
-            *balance += amount;
-
-            let account = self.pool.pop().unwrap_or_default();
-            let updated_account = Reverse((amount + *account.0).into());
-            updated_accounts.push(updated_account);
+impl Default for SinglePool {
+    fn default() -> Self {
+        Self {
+            pools: BTreeMap::new(),
+            shop_balances: HashMap::new(),
+            shortfall: 0.0,
+            settlement_days: 0,
+            current_day: 0,
+            settling: VecDeque::new(),
+            settling_accounts: 0,
+            extra_accounts_due_to_settlement: 0,
+            accounts_freed: 0,
+            dust_threshold: 0.0,
+            withdrawal_fraction: 1.0,
+            max_account_balance: f64::INFINITY,
+            accounts_created_due_to_cap: 0,
+            destinations_per_shop: 1,
+            peak_accounts: 0,
+            withdrawal_failure_rate: 0.0,
+            rng: SmallRng::seed_from_u64(0),
         }
-        self.pool.extend(updated_accounts)
     }
+}
 
-    fn withdraw_all(&mut self) -> usize {
-        let mut current = 0;
-        let mut accounts = self.accounts();
-        let mut total_transactions = 0;
+/**
+ * Outcome of paying the shops of one currency
+ * from the accounts drained by a withdrawal.
+ */
+struct Payout {
+    total_transactions: usize,
+    total_money: f64,
+    /** Balances of the shops which could not be paid in full. */
+    unpaid: Vec<(ShopId, f64)>,
+}
 
-        'outer: for (_, balance) in &mut self.shop_balances {
-            while *balance > 0.0 {
-                while accounts[current] == 0.0 {
-                    current += 1;
-                    if current == accounts.len() {
-                        break 'outer;
-                    }
-                }
-                let amount = balance.min(accounts[current]);
-                accounts[current] -= amount;
-                *balance -= amount;
-                total_transactions += 1;
+impl AccountsPool for SinglePool {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        check_amounts(transactions)?;
+        self.process_transaction_stream(&mut transactions.iter().copied())
+    }
+
+    fn process_transaction_stream(
+        &mut self,
+        transactions: &mut dyn Iterator<Item = Transaction>,
+    ) -> Result<usize, PoolError> {
+        // Accounts go back to the pool only after the batch,
+        // so that each transaction of the batch gets its own.
+        let mut updated_accounts = vec![];
+        let mut result = Ok(());
+        for tx in transactions {
+            if let Err(err) = check_amount(&tx) {
+                result = Err(err);
+                break;
             }
+            let Transaction {
+                shop_id,
+                amount,
+                currency,
+            } = tx;
+            let amount = F64AsKey::new(amount);
+
+            let balance =
+                self.shop_balances.entry((shop_id, currency)).or_default();
+            *balance += *amount;
+
+            let pool = self.pools.entry(currency).or_default();
+            let account = pool.pop().unwrap_or_else(|| {
+                if self.settling_accounts > 0 {
+                    self.extra_accounts_due_to_settlement += 1;
+                }
+                Default::default()
+            });
+            let (balance, spilled) =
+                add_capped(*account.0, *amount, self.max_account_balance);
+            self.accounts_created_due_to_cap += spilled.len();
+            updated_accounts.extend(
+                std::iter::once(balance)
+                    .chain(spilled)
+                    .map(|balance| (currency, Reverse(balance.into()))),
+            );
         }
-        self.reset();
-        total_transactions
+        let accounts_touched = updated_accounts.len();
+        for (currency, account) in updated_accounts {
+            self.pools.entry(currency).or_default().push(account);
+        }
+        self.update_peak_accounts();
+        result.map(|()| accounts_touched)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        self.withdraw_with(Self::pay_in_order)
     }
 
     fn total_accounts(&self) -> usize {
-        self.pool.len()
+        self.pools.values().map(BinaryHeap::len).sum::<usize>()
+            + self.settling_accounts
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.peak_accounts
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.pools
+            .values()
+            .flatten()
+            .map(|account| *account.0)
+            .sum()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        let mut snapshot = PoolSnapshot {
+            account_balances: self.accounts(),
+            ..PoolSnapshot::default()
+        };
+        self.add_shop_balances(&mut snapshot.shop_balances);
+        snapshot
     }
 
     fn name(&self) -> &'static str {
         "Single Pool"
     }
+
+    fn end_day(&mut self) {
+        self.current_day += 1;
+        while let Some(&(ready_day, currency, count)) = self.settling.front() {
+            if ready_day > self.current_day {
+                break;
+            }
+            self.settling.pop_front();
+            self.settling_accounts -= count;
+            self.pools
+                .entry(currency)
+                .or_default()
+                .extend((0..count).map(|_| Reverse(F64AsKey::new(0.0))));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pools.clear();
+        self.shop_balances.clear();
+        self.shortfall = 0.0;
+        self.current_day = 0;
+        self.settling.clear();
+        self.settling_accounts = 0;
+        self.extra_accounts_due_to_settlement = 0;
+        self.accounts_freed = 0;
+        self.accounts_created_due_to_cap = 0;
+        self.peak_accounts = 0;
+    }
+
+    fn extra_accounts_due_to_settlement(&self) -> usize {
+        self.extra_accounts_due_to_settlement
+    }
+
+    fn accounts_created_due_to_cap(&self) -> usize {
+        self.accounts_created_due_to_cap
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.accounts_freed
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.rng = rng;
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        let mut accounts: BTreeMap<CurrencyId, usize> = self
+            .pools
+            .iter()
+            .map(|(&currency, pool)| (currency, pool.len()))
+            .collect();
+        for &(_, currency, count) in &self.settling {
+            *accounts.entry(currency).or_default() += count;
+        }
+        accounts
+    }
 }
 
 impl SinglePool {
@@ -138,50 +785,1905 @@ impl SinglePool {
         Self::default()
     }
 
-    fn accounts(&self) -> Vec<f64> {
-        self.pool.iter().map(|account| *account.0).collect()
+    /**
+     * Accounts emptied by a withdrawal can't be used
+     * for the following `settlement_days` days.
+     */
+    pub fn with_settlement_days(settlement_days: usize) -> Self {
+        Self {
+            settlement_days,
+            ..Self::default()
+        }
     }
 
-    fn reset(&mut self) {
-        self.pool = (0..self.pool.len())
-            .map(|_| Reverse(F64AsKey::new(0.0)))
-            .collect();
-        self.shop_balances.clear();
+    /**
+     * Accounts with less than `dust_threshold` on them
+     * are not drained by withdrawals.
+     */
+    pub fn with_dust_threshold(mut self, dust_threshold: f64) -> Self {
+        self.dust_threshold = dust_threshold;
+        self
     }
 
-    fn shop_balances(&self) -> &HashMap<ShopId, f64> {
-        &self.shop_balances
+    /**
+     * Withdrawals drain only this fraction of the accounts,
+     * the ones with the most money.
+     */
+    pub fn with_withdrawal_fraction(
+        mut self,
+        withdrawal_fraction: f64,
+    ) -> Self {
+        self.withdrawal_fraction = withdrawal_fraction;
+        self
     }
-}
 
-#[derive(Debug, Default)]
-pub struct SinglePoolWithSingleAccount {
-    inner: SinglePool,
-}
+    /**
+     * No account holds more than `max_account_balance`,
+     * the rest of a transaction spills into new accounts.
+     */
+    pub fn with_max_account_balance(
+        mut self,
+        max_account_balance: f64,
+    ) -> Self {
+        self.max_account_balance = max_account_balance;
+        self
+    }
 
-impl SinglePoolWithSingleAccount {
-    pub fn new() -> Self {
-        Self::default()
+    /**
+     * Each shop splits its withdrawn money evenly between
+     * `destinations_per_shop` destination accounts, which
+     * are paid like separate shops.
+     */
+    pub fn with_destinations_per_shop(
+        mut self,
+        destinations_per_shop: usize,
+    ) -> Self {
+        self.destinations_per_shop = destinations_per_shop;
+        self
     }
-}
 
-impl AccountsPool for SinglePoolWithSingleAccount {
-    fn process_transactions(&mut self, transactions: &[Transaction]) {
-        self.inner.process_transactions(transactions);
+    /**
+     * Each transaction of a withdrawal fails with probability
+     * `withdrawal_failure_rate` and is retried until it goes through.
+     */
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.withdrawal_failure_rate = withdrawal_failure_rate;
+        self
     }
 
-    fn withdraw_all(&mut self) -> usize {
-        let total_transactions =
-            self.inner.total_accounts() + self.inner.shop_balances().len();
-        self.inner.reset();
-        total_transactions
+    /**
+     * Pays each shop in turn from the accounts in the order
+     * of the pool, moving on once an account is empty.
+     */
+    fn pay_in_order(
+        mut accounts: Vec<f64>,
+        balances: Vec<(ShopId, f64)>,
+    ) -> Payout {
+        let mut current = 0;
+        let mut payout = Payout {
+            total_transactions: 0,
+            total_money: 0.0,
+            unpaid: Vec::new(),
+        };
+        for (shop_id, mut balance) in balances {
+            while balance > 0.0 {
+                while current < accounts.len() && accounts[current] == 0.0 {
+                    current += 1;
+                }
+                if current == accounts.len() {
+                    payout.unpaid.push((shop_id, balance));
+                    break;
+                }
+                let amount = balance.min(accounts[current]);
+                accounts[current] -= amount;
+                balance -= amount;
+                payout.total_money += amount;
+                payout.total_transactions += 1;
+            }
+        }
+        payout
     }
 
-    fn total_accounts(&self) -> usize {
-        self.inner.total_accounts()
+    /**
+     * Pays the largest shop balances first, each from the largest
+     * accounts available, to keep the number of transactions low.
+     */
+    fn pay_greedily(
+        accounts: Vec<f64>,
+        mut balances: Vec<(ShopId, f64)>,
+    ) -> Payout {
+        let mut accounts: BinaryHeap<F64AsKey> = accounts
+            .into_iter()
+            .filter(|&account| account > 0.0)
+            .map(F64AsKey::new)
+            .collect();
+        balances.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let mut payout = Payout {
+            total_transactions: 0,
+            total_money: 0.0,
+            unpaid: Vec::new(),
+        };
+        for (shop_id, mut balance) in balances {
+            while balance > 0.0 {
+                let Some(account) = accounts.pop() else {
+                    payout.unpaid.push((shop_id, balance));
+                    break;
+                };
+                let amount = balance.min(*account);
+                balance -= amount;
+                payout.total_money += amount;
+                payout.total_transactions += 1;
+
+                let rest = *account - amount;
+                if rest > 0.0 {
+                    accounts.push(rest.into());
+                }
+            }
+        }
+        payout
     }
 
-    fn name(&self) -> &'static str {
-        "Single Pool with Single Account"
+    fn withdraw_all_greedy(&mut self) -> Withdrawal {
+        self.withdraw_with(Self::pay_greedily)
+    }
+
+    /**
+     * Withdraws each currency on its own, paying its shops
+     * with `pay` from the accounts of that currency.
+     */
+    fn withdraw_with(
+        &mut self,
+        pay: fn(Vec<f64>, Vec<(ShopId, f64)>) -> Payout,
+    ) -> Withdrawal {
+        let mut withdrawal = Withdrawal::default();
+        let currencies: Vec<CurrencyId> = self.pools.keys().copied().collect();
+        for currency in currencies {
+            let (accounts, mut kept) = self.split_accounts(currency);
+            let drained: f64 = accounts.iter().sum();
+            let destinations = self.destinations_per_shop;
+            let (owed, mut balances): (Vec<_>, Vec<_>) = self
+                .shop_balances()
+                .filter(|&(_, balance_currency, _)| {
+                    balance_currency == currency
+                })
+                .map(|(shop_id, _, balance)| (shop_id, balance))
+                .partition(|&(_, balance)| balance < 0.0);
+            // Shops are paid in the order of their ids rather than
+            // that of the hash map, so withdrawals are reproducible.
+            balances.sort_by_key(|&(shop_id, _)| shop_id);
+            let balances = balances
+                .into_iter()
+                .flat_map(|(shop_id, balance)| {
+                    let part = balance / destinations as f64;
+                    (0..destinations).map(move |_| (shop_id, part))
+                })
+                .collect();
+            let payout = pay(accounts, balances);
+            settle_owing_accounts(&mut kept, drained - payout.total_money);
+            let dust_left: f64 = kept
+                .iter()
+                .filter(|&&balance| {
+                    (0.0..self.dust_threshold).contains(&balance)
+                })
+                .sum();
+            let money_left = self.reset_currency(currency, kept);
+            self.carry_forward(currency, payout.unpaid, owed, money_left);
+            withdrawal.total_transactions += payout.total_transactions;
+            withdrawal.total_money += payout.total_money;
+            withdrawal.money_left += money_left;
+            withdrawal.dust_left += dust_left;
+        }
+        retry_failed_transactions(
+            &mut withdrawal,
+            self.withdrawal_failure_rate,
+            &mut self.rng,
+        );
+        withdrawal
+    }
+
+    /**
+     * Withdrawals recreate the drained accounts, so only
+     * batches can raise the number of accounts held.
+     */
+    fn update_peak_accounts(&mut self) {
+        self.peak_accounts = self.peak_accounts.max(self.total_accounts());
+    }
+
+    fn accounts(&self) -> Vec<f64> {
+        self.pools
+            .values()
+            .flatten()
+            .map(|account| *account.0)
+            .collect()
+    }
+
+    /**
+     * Splits the balances of the accounts of `currency` into the ones
+     * a withdrawal drains, in the order of the pool, and the ones it
+     * keeps: those owing money, below the dust threshold or beyond
+     * the withdrawal fraction.
+     */
+    fn split_accounts(&self, currency: CurrencyId) -> (Vec<f64>, Vec<f64>) {
+        let accounts: Vec<f64> = self.pools[&currency]
+            .iter()
+            .map(|account| *account.0)
+            .collect();
+        let largest = largest_fraction(&accounts, self.withdrawal_fraction);
+        let mut drained = Vec::with_capacity(accounts.len());
+        let mut kept = Vec::new();
+        for (balance, largest) in accounts.into_iter().zip(largest) {
+            if largest && balance >= 0.0 && balance >= self.dust_threshold {
+                drained.push(balance);
+            } else {
+                kept.push(balance);
+            }
+        }
+        (drained, kept)
+    }
+
+    /**
+     * Empties all accounts of `currency` except the `kept` ones,
+     * which keep their balance, clears the balances of the shops
+     * in that currency and returns the money left on the accounts.
+     */
+    fn reset_currency(&mut self, currency: CurrencyId, kept: Vec<f64>) -> f64 {
+        let pool = self.pools.entry(currency).or_default();
+        let drained = pool.len() - kept.len();
+        let money_left = kept.iter().sum();
+        let kept = kept.into_iter().map(|balance| Reverse(balance.into()));
+        self.accounts_freed += drained;
+        if self.settlement_days == 0 {
+            *pool = (0..drained)
+                .map(|_| Reverse(F64AsKey::new(0.0)))
+                .chain(kept)
+                .collect();
+        } else {
+            let ready_day = self.current_day + self.settlement_days + 1;
+            self.settling.push_back((ready_day, currency, drained));
+            self.settling_accounts += drained;
+            *pool = kept.collect();
+        }
+        self.shop_balances
+            .retain(|&(_, balance_currency), _| balance_currency != currency);
+        money_left
+    }
+
+    /**
+     * Keeps the balances shops could not be paid up to the money
+     * left on the accounts of `currency`, so that both still match.
+     * Anything beyond it is a shortfall.
+     *
+     * The `owed` balances of shops in debt are kept as they are.
+     * Refunds took their money from the accounts, so the balances
+     * of other shops the accounts could not cover are kept on top
+     * of the money left.
+     */
+    fn carry_forward(
+        &mut self,
+        currency: CurrencyId,
+        unpaid: Vec<(ShopId, f64)>,
+        owed: Vec<(ShopId, f64)>,
+        money_left: f64,
+    ) {
+        let mut left = money_left;
+        for (shop_id, balance) in owed {
+            left -= balance;
+            self.shop_balances
+                .insert((shop_id, currency), balance.into());
+        }
+        for (shop_id, balance) in unpaid {
+            let carried = balance.min(left);
+            left -= carried;
+            self.shortfall += balance - carried;
+            if carried > 0.0 {
+                // A shop with several destinations may be unpaid
+                // for more than one of them.
+                *self.shop_balances.entry((shop_id, currency)).or_default() +=
+                    carried;
+            }
+        }
+    }
+
+    /** Returns the balance of each shop in each currency. */
+    fn shop_balances(
+        &self,
+    ) -> impl ExactSizeIterator<Item = (ShopId, CurrencyId, f64)> + '_ {
+        self.shop_balances
+            .iter()
+            .map(|(&(shop_id, currency), balance)| {
+                (shop_id, currency, balance.value())
+            })
+    }
+
+    /** Adds the balances of each shop over all currencies to `totals`. */
+    fn add_shop_balances(&self, totals: &mut HashMap<ShopId, f64>) {
+        for (shop_id, _, balance) in self.shop_balances() {
+            *totals.entry(shop_id).or_default() += balance;
+        }
+    }
+
+    /**
+     * Returns the total amount of shop balances that could not be
+     * withdrawn because the accounts ran out of money.
+     * Apart from floating point error it should always be zero.
+     */
+    pub fn shortfall(&self) -> f64 {
+        self.shortfall
+    }
+}
+
+/**
+ * A single pool which withdraws money greedily
+ * from the largest accounts first.
+ */
+#[derive(Debug, Default)]
+pub struct SinglePoolGreedy {
+    inner: SinglePool,
+}
+
+impl SinglePoolGreedy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_settlement_days(settlement_days: usize) -> Self {
+        Self {
+            inner: SinglePool::with_settlement_days(settlement_days),
+        }
+    }
+
+    pub fn with_dust_threshold(mut self, dust_threshold: f64) -> Self {
+        self.inner = self.inner.with_dust_threshold(dust_threshold);
+        self
+    }
+
+    pub fn with_withdrawal_fraction(
+        mut self,
+        withdrawal_fraction: f64,
+    ) -> Self {
+        self.inner = self.inner.with_withdrawal_fraction(withdrawal_fraction);
+        self
+    }
+
+    pub fn with_max_account_balance(
+        mut self,
+        max_account_balance: f64,
+    ) -> Self {
+        self.inner = self.inner.with_max_account_balance(max_account_balance);
+        self
+    }
+
+    pub fn with_destinations_per_shop(
+        mut self,
+        destinations_per_shop: usize,
+    ) -> Self {
+        self.inner =
+            self.inner.with_destinations_per_shop(destinations_per_shop);
+        self
+    }
+
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.inner = self
+            .inner
+            .with_withdrawal_failure_rate(withdrawal_failure_rate);
+        self
+    }
+}
+
+impl AccountsPool for SinglePoolGreedy {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        self.inner.process_transactions(transactions)
+    }
+
+    fn process_transaction_stream(
+        &mut self,
+        transactions: &mut dyn Iterator<Item = Transaction>,
+    ) -> Result<usize, PoolError> {
+        self.inner.process_transaction_stream(transactions)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        self.inner.withdraw_all_greedy()
+    }
+
+    fn total_accounts(&self) -> usize {
+        self.inner.total_accounts()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.inner.peak_accounts()
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.inner.total_balance()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        self.inner.snapshot()
+    }
+
+    fn name(&self) -> &'static str {
+        "Single Pool Greedy"
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn end_day(&mut self) {
+        self.inner.end_day();
+    }
+
+    fn extra_accounts_due_to_settlement(&self) -> usize {
+        self.inner.extra_accounts_due_to_settlement()
+    }
+
+    fn accounts_created_due_to_cap(&self) -> usize {
+        self.inner.accounts_created_due_to_cap()
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.inner.accounts_freed()
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.inner.set_rng(rng);
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        self.inner.accounts_per_currency()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SinglePoolWithSingleAccount {
+    inner: SinglePool,
+}
+
+impl SinglePoolWithSingleAccount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.inner = self
+            .inner
+            .with_withdrawal_failure_rate(withdrawal_failure_rate);
+        self
+    }
+}
+
+impl AccountsPool for SinglePoolWithSingleAccount {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        self.inner.process_transactions(transactions)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        let (owed, paid): (Vec<_>, Vec<_>) = self
+            .inner
+            .shop_balances()
+            .partition(|&(_, _, balance)| balance < 0.0);
+        let total_transactions = self.inner.total_accounts() + paid.len();
+        let total_money = paid.iter().map(|&(_, _, balance)| balance).sum();
+        let currencies: Vec<CurrencyId> =
+            self.inner.pools.keys().copied().collect();
+        let mut money_left = 0.0;
+        for currency in currencies {
+            // The single account keeps the debts of the currency.
+            let owed: Vec<_> = owed
+                .iter()
+                .filter(|&&(_, owed_currency, _)| owed_currency == currency)
+                .map(|&(shop_id, _, balance)| (shop_id, balance))
+                .collect();
+            let debt: f64 = owed.iter().map(|&(_, balance)| balance).sum();
+            let kept = if owed.is_empty() { vec![] } else { vec![debt] };
+            money_left += self.inner.reset_currency(currency, kept);
+            self.inner.carry_forward(currency, vec![], owed, 0.0);
+        }
+        let mut withdrawal = Withdrawal {
+            total_transactions,
+            total_money,
+            money_left,
+            ..Withdrawal::default()
+        };
+        retry_failed_transactions(
+            &mut withdrawal,
+            self.inner.withdrawal_failure_rate,
+            &mut self.inner.rng,
+        );
+        withdrawal
+    }
+
+    fn total_accounts(&self) -> usize {
+        self.inner.total_accounts()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.inner.peak_accounts()
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.inner.total_balance()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        self.inner.snapshot()
+    }
+
+    fn name(&self) -> &'static str {
+        "Single Pool with Single Account"
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.inner.accounts_freed()
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.inner.set_rng(rng);
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        self.inner.accounts_per_currency()
+    }
+}
+
+/**
+ * Keeps a separate single pool for each region,
+ * so accounts are shared only between shops of the same region.
+ */
+#[derive(Debug)]
+pub struct PoolPerRegion {
+    regions_number: usize,
+    shop_regions: Vec<RegionId>,
+    pools: HashMap<RegionId, SinglePool>,
+    /** The regions peak apart, so their peaks don't add up. */
+    peak_accounts: usize,
+    /** Applies to the withdrawals of all regions at once. */
+    withdrawal_failure_rate: f64,
+    rng: SmallRng,
+}
+
+impl PoolPerRegion {
+    /** Assigns shops to regions round-robin. */
+    pub fn new(regions_number: usize) -> Self {
+        Self::with_shop_regions(regions_number, Vec::new())
+    }
+
+    /**
+     * Shop `i` belongs to region `shop_regions[i]`.
+     * Shops beyond `shop_regions` are assigned round-robin.
+     */
+    pub fn with_shop_regions(
+        regions_number: usize,
+        shop_regions: Vec<RegionId>,
+    ) -> Self {
+        assert!(regions_number > 0, "There must be at least one region.");
+        Self {
+            regions_number,
+            shop_regions,
+            pools: HashMap::new(),
+            peak_accounts: 0,
+            withdrawal_failure_rate: 0.0,
+            rng: SmallRng::seed_from_u64(0),
+        }
+    }
+
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.withdrawal_failure_rate = withdrawal_failure_rate;
+        self
+    }
+
+    pub fn region_of(&self, shop_id: ShopId) -> RegionId {
+        self.shop_regions
+            .get(shop_id)
+            .copied()
+            .unwrap_or(shop_id % self.regions_number)
+    }
+}
+
+impl AccountsPool for PoolPerRegion {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        check_amounts(transactions)?;
+        let mut txs_per_region = HashMap::<RegionId, Vec<Transaction>>::new();
+        for &transaction in transactions {
+            let txs = txs_per_region
+                .entry(self.region_of(transaction.shop_id))
+                .or_default();
+            txs.push(transaction);
+        }
+
+        let mut accounts_touched = 0;
+        for (region_id, txs) in txs_per_region {
+            let pool = self.pools.entry(region_id).or_default();
+            accounts_touched += pool.process_transactions(&txs)?;
+        }
+        self.peak_accounts = self.peak_accounts.max(self.total_accounts());
+        Ok(accounts_touched)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        let mut total = Withdrawal::default();
+        for pool in self.pools.values_mut() {
+            let withdrawal = pool.withdraw_all();
+            total.total_transactions += withdrawal.total_transactions;
+            total.total_money += withdrawal.total_money;
+            total.money_left += withdrawal.money_left;
+            total.dust_left += withdrawal.dust_left;
+        }
+        retry_failed_transactions(
+            &mut total,
+            self.withdrawal_failure_rate,
+            &mut self.rng,
+        );
+        total
+    }
+
+    fn total_accounts(&self) -> usize {
+        self.pools.values().map(|pool| pool.total_accounts()).sum()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.peak_accounts
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.pools.values().map(|pool| pool.total_balance()).sum()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        let mut snapshot = PoolSnapshot::default();
+        for pool in self.pools.values() {
+            snapshot.account_balances.extend(pool.accounts());
+            pool.add_shop_balances(&mut snapshot.shop_balances);
+        }
+        snapshot
+    }
+
+    fn name(&self) -> &'static str {
+        "Pool per Region"
+    }
+
+    fn reset(&mut self) {
+        self.pools.clear();
+        self.peak_accounts = 0;
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.pools.values().map(|pool| pool.accounts_freed()).sum()
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.rng = rng;
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        let mut accounts = BTreeMap::new();
+        for pool in self.pools.values() {
+            for (currency, count) in pool.accounts_per_currency() {
+                *accounts.entry(currency).or_default() += count;
+            }
+        }
+        accounts
+    }
+}
+
+/**
+ * A single pool that never holds more than `max_accounts` accounts
+ * of each currency. Transactions beyond the cap are queued onto
+ * accounts of their currency already used in the same batch.
+ */
+#[derive(Debug)]
+pub struct CappedPool {
+    inner: SinglePool,
+    max_accounts: usize,
+    queued_transactions: usize,
+}
+
+impl CappedPool {
+    pub fn new(max_accounts: usize) -> Self {
+        assert!(
+            max_accounts > 0,
+            "The pool must allow at least one account."
+        );
+        Self {
+            inner: SinglePool::new(),
+            max_accounts,
+            queued_transactions: 0,
+        }
+    }
+
+    pub fn with_withdrawal_failure_rate(
+        mut self,
+        withdrawal_failure_rate: f64,
+    ) -> Self {
+        self.inner = self
+            .inner
+            .with_withdrawal_failure_rate(withdrawal_failure_rate);
+        self
+    }
+}
+
+impl AccountsPool for CappedPool {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        check_amounts(transactions)?;
+        let mut updated_accounts = HashMap::<CurrencyId, Vec<f64>>::new();
+        for &Transaction {
+            shop_id,
+            amount,
+            currency,
+        } in transactions
+        {
+            let balance = self
+                .inner
+                .shop_balances
+                .entry((shop_id, currency))
+                .or_default();
+            *balance += amount;
+
+            let pool = self.inner.pools.entry(currency).or_default();
+            let updated = updated_accounts.entry(currency).or_default();
+            let total_accounts = pool.len() + updated.len();
+            match pool.pop() {
+                Some(account) => updated.push(*account.0 + amount),
+                None if total_accounts < self.max_accounts => {
+                    updated.push(amount)
+                }
+                None => {
+                    let i = self.queued_transactions % updated.len();
+                    updated[i] += amount;
+                    self.queued_transactions += 1;
+                }
+            }
+        }
+
+        let mut accounts_touched = 0;
+        for (currency, accounts) in updated_accounts {
+            accounts_touched += accounts.len();
+            self.inner.pools.entry(currency).or_default().extend(
+                accounts.into_iter().map(|account| Reverse(account.into())),
+            );
+        }
+        self.inner.update_peak_accounts();
+        Ok(accounts_touched)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        self.inner.withdraw_all()
+    }
+
+    fn total_accounts(&self) -> usize {
+        self.inner.total_accounts()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.inner.peak_accounts()
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.inner.total_balance()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        self.inner.snapshot()
+    }
+
+    fn name(&self) -> &'static str {
+        "Capped Pool"
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.queued_transactions = 0;
+    }
+
+    fn queued_transactions(&self) -> usize {
+        self.queued_transactions
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.inner.accounts_freed()
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.inner.set_rng(rng);
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        self.inner.accounts_per_currency()
+    }
+}
+
+/**
+ * A single pool whose money is never withdrawn.
+ * It is the baseline of never sweeping the accounts:
+ * withdrawals cost nothing and the accounts only ever grow.
+ */
+#[derive(Debug, Default)]
+pub struct NoWithdrawalPool {
+    inner: SinglePool,
+}
+
+impl NoWithdrawalPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountsPool for NoWithdrawalPool {
+    fn process_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<usize, PoolError> {
+        self.inner.process_transactions(transactions)
+    }
+
+    fn withdraw_all(&mut self) -> Withdrawal {
+        Withdrawal::default()
+    }
+
+    fn total_accounts(&self) -> usize {
+        self.inner.total_accounts()
+    }
+
+    fn peak_accounts(&self) -> usize {
+        self.inner.peak_accounts()
+    }
+
+    fn total_balance(&self) -> f64 {
+        self.inner.total_balance()
+    }
+
+    fn snapshot(&self) -> PoolSnapshot {
+        self.inner.snapshot()
+    }
+
+    fn name(&self) -> &'static str {
+        "No Withdrawal Pool"
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn accounts_freed(&self) -> usize {
+        self.inner.accounts_freed()
+    }
+
+    fn set_rng(&mut self, rng: SmallRng) {
+        self.inner.set_rng(rng);
+    }
+
+    fn accounts_per_currency(&self) -> BTreeMap<CurrencyId, usize> {
+        self.inner.accounts_per_currency()
+    }
+}
+
+/**
+ * Identifies one of the built-in pool strategies.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolKind {
+    PoolPerShop,
+    SinglePool,
+    SinglePoolGreedy,
+    SinglePoolWithSingleAccount,
+    PoolPerRegion,
+    CappedPool,
+    NoWithdrawalPool,
+}
+
+impl PoolKind {
+    pub const ALL: [PoolKind; 7] = [
+        PoolKind::PoolPerShop,
+        PoolKind::SinglePool,
+        PoolKind::SinglePoolGreedy,
+        PoolKind::SinglePoolWithSingleAccount,
+        PoolKind::PoolPerRegion,
+        PoolKind::CappedPool,
+        PoolKind::NoWithdrawalPool,
+    ];
+
+    /**
+     * Returns the identifier used to select the pool from the CLI.
+     */
+    pub fn id(self) -> &'static str {
+        match self {
+            PoolKind::PoolPerShop => "pool-per-shop",
+            PoolKind::SinglePool => "single-pool",
+            PoolKind::SinglePoolGreedy => "single-pool-greedy",
+            PoolKind::SinglePoolWithSingleAccount => {
+                "single-pool-with-single-account"
+            }
+            PoolKind::PoolPerRegion => "pool-per-region",
+            PoolKind::CappedPool => "capped-pool",
+            PoolKind::NoWithdrawalPool => "no-withdrawal",
+        }
+    }
+
+    /**
+     * Derives the generator of a pool of this kind from `seed`.
+     * It depends on the kind rather than on the position among
+     * the selected pools, so a pool makes the same draws whatever
+     * pools run along with it. The mixed-in index starts at one
+     * for no pool to share the stream of the input.
+     */
+    pub fn rng(self, seed: u64) -> SmallRng {
+        let index = Self::ALL
+            .iter()
+            .position(|&kind| kind == self)
+            .expect("all kinds are listed");
+        SmallRng::seed_from_u64(
+            seed ^ (index as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15),
+        )
+    }
+
+    /**
+     * Creates an empty pool of this kind configured from `config`.
+     * Fails if the config can't describe such a pool,
+     * e.g. because the shops can't be assigned to regions.
+     */
+    pub fn create(
+        self,
+        config: &SimConfig,
+    ) -> Result<Box<dyn AccountsPool + Send>> {
+        Ok(match self {
+            PoolKind::PoolPerShop => Box::new(
+                PoolPerShop::new()
+                    .with_dust_threshold(config.dust_threshold)
+                    .with_withdrawal_fraction(config.withdrawal_fraction)
+                    .with_max_account_balance(
+                        config.max_account_balance.unwrap_or(f64::INFINITY),
+                    )
+                    .with_destinations_per_shop(config.destinations_per_shop)
+                    .with_withdrawal_failure_rate(
+                        config.withdrawal_failure_rate,
+                    ),
+            ),
+            PoolKind::SinglePool => Box::new(
+                SinglePool::with_settlement_days(config.settlement_days)
+                    .with_dust_threshold(config.dust_threshold)
+                    .with_withdrawal_fraction(config.withdrawal_fraction)
+                    .with_max_account_balance(
+                        config.max_account_balance.unwrap_or(f64::INFINITY),
+                    )
+                    .with_destinations_per_shop(config.destinations_per_shop)
+                    .with_withdrawal_failure_rate(
+                        config.withdrawal_failure_rate,
+                    ),
+            ),
+            PoolKind::SinglePoolGreedy => Box::new(
+                SinglePoolGreedy::with_settlement_days(config.settlement_days)
+                    .with_dust_threshold(config.dust_threshold)
+                    .with_withdrawal_fraction(config.withdrawal_fraction)
+                    .with_max_account_balance(
+                        config.max_account_balance.unwrap_or(f64::INFINITY),
+                    )
+                    .with_destinations_per_shop(config.destinations_per_shop)
+                    .with_withdrawal_failure_rate(
+                        config.withdrawal_failure_rate,
+                    ),
+            ),
+            PoolKind::SinglePoolWithSingleAccount => Box::new(
+                SinglePoolWithSingleAccount::new()
+                    .with_withdrawal_failure_rate(
+                        config.withdrawal_failure_rate,
+                    ),
+            ),
+            PoolKind::PoolPerRegion => Box::new(
+                PoolPerRegion::with_shop_regions(
+                    config.simulated_regions_number,
+                    assign_regions(config, config.simulated_shops_number)?,
+                )
+                .with_withdrawal_failure_rate(config.withdrawal_failure_rate),
+            ),
+            PoolKind::CappedPool => Box::new(
+                CappedPool::new(config.capped_pool_max_accounts)
+                    .with_withdrawal_failure_rate(
+                        config.withdrawal_failure_rate,
+                    ),
+            ),
+            PoolKind::NoWithdrawalPool => Box::new(NoWithdrawalPool::new()),
+        })
+    }
+}
+
+impl FromStr for PoolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PoolKind::ALL
+            .into_iter()
+            .find(|kind| kind.id() == s)
+            .ok_or_else(|| {
+                let ids: Vec<_> =
+                    PoolKind::ALL.iter().map(|kind| kind.id()).collect();
+                format!(
+                    "unknown pool '{}', expected one of: {}",
+                    s,
+                    ids.join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_pool_withdraw_all_reports_shortfall() {
+        let mut pool = SinglePool::new();
+        pool.process_transactions(&[
+            Transaction {
+                amount: 10.0,
+                shop_id: 0,
+                currency: 0,
+            },
+            Transaction {
+                amount: 5.0,
+                shop_id: 1,
+                currency: 0,
+            },
+        ])
+        .unwrap();
+        // Balances now exceed the summed account values by 7.
+        pool.shop_balances.insert((2, 0), 7.0.into());
+
+        let withdrawal = pool.withdraw_all();
+
+        // Shop 0 drains the account of 5 and half of the one of 10,
+        // shop 1 gets the rest, and nothing is left for shop 2.
+        assert_eq!(withdrawal.total_transactions, 3);
+        assert_eq!(withdrawal.total_money, 15.0);
+        assert_eq!(pool.shortfall(), 7.0);
+        assert_eq!(pool.shop_balances().len(), 0);
+        assert!(pool.accounts().iter().all(|&account| account == 0.0));
+    }
+
+    #[test]
+    fn test_single_pool_withdraw_all_without_shortfall() {
+        let mut pool = SinglePool::new();
+        let accounts_touched = pool
+            .process_transactions(&[
+                Transaction {
+                    amount: 10.0,
+                    shop_id: 0,
+                    currency: 0,
+                },
+                Transaction {
+                    amount: 5.0,
+                    shop_id: 0,
+                    currency: 0,
+                },
+            ])
+            .unwrap();
+
+        assert_eq!(accounts_touched, 2);
+        assert_eq!(
+            pool.withdraw_all(),
+            Withdrawal {
+                total_transactions: 2,
+                total_money: 15.0,
+                ..Withdrawal::default()
+            }
+        );
+        assert_eq!(pool.shortfall(), 0.0);
+    }
+
+    fn shop_txs(shop_ids: &[ShopId], amount: f64) -> Vec<Transaction> {
+        shop_ids
+            .iter()
+            .map(|&shop_id| Transaction {
+                amount,
+                shop_id,
+                currency: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pool_per_shop_spreads_batch_across_shops() {
+        let mut pool = PoolPerShop::new();
+
+        let accounts_touched = pool
+            .process_transactions(&shop_txs(&[0, 0, 0, 1, 2, 2], 1.0))
+            .unwrap();
+
+        assert_eq!(accounts_touched, 6);
+        assert_eq!(pool.pools[&(0, 0)], [1.0, 1.0, 1.0]);
+        assert_eq!(pool.pools[&(1, 0)], [1.0]);
+        assert_eq!(pool.pools[&(2, 0)], [1.0, 1.0]);
+        assert_eq!(pool.total_accounts(), 6);
+        assert_eq!(pool.total_balance(), 6.0);
+    }
+
+    #[test]
+    fn test_pool_per_shop_keeps_size_after_smaller_batch() {
+        let mut pool = PoolPerShop::new();
+        pool.process_transactions(&shop_txs(&[0, 0, 0], 1.0))
+            .unwrap();
+
+        let accounts_touched =
+            pool.process_transactions(&shop_txs(&[0], 2.0)).unwrap();
+
+        assert_eq!(accounts_touched, 1);
+        assert_eq!(pool.pools[&(0, 0)], [3.0, 1.0, 1.0]);
+        assert_eq!(pool.total_accounts(), 3);
+    }
+
+    #[test]
+    fn test_pool_per_shop_withdraw_all_keeps_accounts() {
+        let mut pool = PoolPerShop::new();
+        pool.process_transactions(&shop_txs(&[0, 0, 1], 2.0))
+            .unwrap();
+        pool.process_transactions(&shop_txs(&[1], 3.0)).unwrap();
+
+        let withdrawal = pool.withdraw_all();
+
+        assert_eq!(withdrawal.total_money, 9.0);
+        assert_eq!(withdrawal.total_transactions, 3);
+        assert_eq!(pool.total_accounts(), 3);
+        assert_eq!(pool.total_balance(), 0.0);
+        assert_eq!(pool.pools[&(0, 0)], [0.0, 0.0]);
+        assert_eq!(pool.pools[&(1, 0)], [0.0]);
+    }
+
+    #[test]
+    fn test_settlement_delay_prevents_reusing_accounts() {
+        let simulate = |settlement_days: usize| {
+            let mut pool = SinglePool::with_settlement_days(settlement_days);
+            pool.process_transactions(&shop_txs(&[0, 1, 2], 1.0))
+                .unwrap();
+            pool.withdraw_all();
+            pool.end_day();
+            pool.process_transactions(&shop_txs(&[0, 1, 2], 1.0))
+                .unwrap();
+            pool.end_day();
+            pool
+        };
+
+        let pool = simulate(0);
+        assert_eq!(pool.total_accounts(), 3);
+        assert_eq!(pool.extra_accounts_due_to_settlement(), 0);
+
+        let mut pool = simulate(1);
+        assert_eq!(pool.total_accounts(), 6);
+        assert_eq!(pool.extra_accounts_due_to_settlement(), 3);
+
+        // The settled accounts are available again.
+        pool.process_transactions(&shop_txs(&[0, 1, 2, 3, 4, 5], 1.0))
+            .unwrap();
+        assert_eq!(pool.total_accounts(), 6);
+        assert_eq!(pool.extra_accounts_due_to_settlement(), 3);
+    }
+
+    #[test]
+    fn test_pool_per_region_shares_accounts_within_region() {
+        let mut pool = PoolPerRegion::new(2);
+        let txs = |shop_ids: &[ShopId]| -> Vec<Transaction> {
+            shop_ids
+                .iter()
+                .map(|&shop_id| Transaction {
+                    amount: 1.0,
+                    shop_id,
+                    currency: 0,
+                })
+                .collect()
+        };
+
+        assert_eq!(pool.region_of(2), 0);
+        assert_eq!(pool.region_of(3), 1);
+
+        assert_eq!(pool.process_transactions(&txs(&[0, 1, 2, 3])).unwrap(), 4);
+        assert_eq!(pool.total_accounts(), 4);
+
+        // Shops 0 and 2 reuse the two accounts of region 0.
+        assert_eq!(pool.process_transactions(&txs(&[0, 2])).unwrap(), 2);
+        assert_eq!(pool.total_accounts(), 4);
+
+        let withdrawal = pool.withdraw_all();
+        assert_eq!(withdrawal.total_money, 6.0);
+        assert_eq!(pool.total_accounts(), 4);
+    }
+
+    #[test]
+    fn test_pool_per_region_with_shop_regions() {
+        let mut pool = PoolPerRegion::with_shop_regions(2, vec![1, 1, 0]);
+        assert_eq!(pool.region_of(0), 1);
+        assert_eq!(pool.region_of(2), 0);
+        // Shops beyond the assignment fall back to round-robin.
+        assert_eq!(pool.region_of(3), 1);
+
+        let txs: Vec<_> = [0, 1]
+            .iter()
+            .map(|&shop_id| Transaction {
+                amount: 1.0,
+                shop_id,
+                currency: 0,
+            })
+            .collect();
+        pool.process_transactions(&txs).unwrap();
+        pool.withdraw_all();
+        // Both shops are in region 1, so the second day reuses its accounts.
+        pool.process_transactions(&txs).unwrap();
+        assert_eq!(pool.total_accounts(), 2);
+    }
+
+    #[test]
+    fn test_capped_pool_never_exceeds_cap() {
+        let mut pool = CappedPool::new(10);
+        let transactions: Vec<Transaction> = (0..1000)
+            .map(|i| Transaction {
+                amount: 1.0,
+                shop_id: i % 7,
+                currency: 0,
+            })
+            .collect();
+
+        assert_eq!(pool.process_transactions(&transactions).unwrap(), 10);
+        assert_eq!(pool.total_accounts(), 10);
+        assert_eq!(pool.queued_transactions(), 990);
+
+        assert_eq!(pool.process_transactions(&transactions[..5]).unwrap(), 5);
+        assert_eq!(pool.total_accounts(), 10);
+        assert_eq!(pool.queued_transactions(), 990);
+
+        let withdrawal = pool.withdraw_all();
+        assert_eq!(withdrawal.total_money, 1005.0);
+        assert_eq!(pool.total_accounts(), 10);
+    }
+
+    #[test]
+    fn test_pool_kind_from_str() {
+        for kind in PoolKind::ALL {
+            assert_eq!(kind.id().parse::<PoolKind>(), Ok(kind));
+        }
+        assert!("no-such-pool".parse::<PoolKind>().is_err());
+    }
+
+    #[test]
+    fn test_snapshot_leaves_pool_unchanged() {
+        let transactions = [
+            Transaction {
+                amount: 1.0,
+                shop_id: 0,
+                currency: 0,
+            },
+            Transaction {
+                amount: 2.0,
+                shop_id: 0,
+                currency: 0,
+            },
+            Transaction {
+                amount: 4.0,
+                shop_id: 1,
+                currency: 0,
+            },
+        ];
+        let expected_shop_balances = HashMap::from([(0, 3.0), (1, 4.0)]);
+
+        for kind in PoolKind::ALL {
+            let mut pool = kind.create(&SimConfig::default()).unwrap();
+            pool.process_transactions(&transactions).unwrap();
+
+            let snapshot = pool.snapshot();
+            let mut account_balances = snapshot.account_balances.clone();
+            account_balances.sort_by(f64::total_cmp);
+            assert_eq!(account_balances, [1.0, 2.0, 4.0], "{}", pool.name());
+            assert_eq!(
+                snapshot.shop_balances,
+                expected_shop_balances,
+                "{}",
+                pool.name()
+            );
+            assert_eq!(pool.snapshot(), snapshot, "{}", pool.name());
+
+            // The money is either withdrawn or still in the accounts.
+            let withdrawal = pool.withdraw_all();
+            let left: f64 = pool.snapshot().account_balances.iter().sum();
+            assert_eq!(withdrawal.total_money + left, 7.0, "{}", pool.name());
+        }
+    }
+
+    #[test]
+    fn test_no_withdrawal_pool_keeps_growing() {
+        let mut pool = NoWithdrawalPool::new();
+        let txs = |count: usize| -> Vec<Transaction> {
+            (0..count)
+                .map(|shop_id| Transaction {
+                    amount: 1.0,
+                    shop_id,
+                    currency: 0,
+                })
+                .collect()
+        };
+
+        let mut last_accounts = 0;
+        for count in [3, 1, 5, 2] {
+            pool.process_transactions(&txs(count)).unwrap();
+            assert_eq!(pool.withdraw_all(), Withdrawal::default());
+            assert!(pool.total_accounts() >= last_accounts);
+            last_accounts = pool.total_accounts();
+        }
+        assert_eq!(last_accounts, 5);
+        assert_eq!(pool.total_balance(), 11.0);
+    }
+
+    #[test]
+    fn test_pools_reject_invalid_amounts() {
+        let valid = Transaction {
+            amount: 3.0,
+            shop_id: 1,
+            currency: 0,
+        };
+        for amount in [f64::NAN, f64::INFINITY] {
+            let invalid = Transaction {
+                amount,
+                shop_id: 0,
+                currency: 0,
+            };
+            for kind in PoolKind::ALL {
+                let mut pool = kind.create(&SimConfig::default()).unwrap();
+                pool.process_transactions(&[valid]).unwrap();
+
+                let err =
+                    pool.process_transactions(&[valid, invalid]).unwrap_err();
+                assert!(matches!(
+                    err,
+                    PoolError::InvalidAmount { shop_id: 0, .. }
+                ));
+                // The rejected batch leaves no trace in the pool.
+                assert_eq!(pool.total_accounts(), 1, "{}", pool.name());
+                assert_eq!(pool.total_balance(), 3.0, "{}", pool.name());
+            }
+        }
+    }
+
+    #[test]
+    fn test_greedy_withdrawal_uses_fewer_transactions() {
+        let transactions: Vec<Transaction> = [1.0, 1.0, 4.0, 1.0, 1.0]
+            .into_iter()
+            .map(|amount| Transaction {
+                amount,
+                shop_id: 0,
+                currency: 0,
+            })
+            .collect();
+        // Both shops have the same balance, so the order
+        // in which they are paid doesn't matter.
+        let balances =
+            HashMap::from([((0, 0), 4.0.into()), ((1, 0), 4.0.into())]);
+
+        let mut pool = SinglePool::new();
+        pool.process_transactions(&transactions).unwrap();
+        pool.shop_balances = balances.clone();
+        let withdrawal = pool.withdraw_all();
+
+        let mut greedy_pool = SinglePoolGreedy::new();
+        greedy_pool.process_transactions(&transactions).unwrap();
+        greedy_pool.inner.shop_balances = balances;
+        let greedy_withdrawal = greedy_pool.withdraw_all();
+
+        assert_eq!(
+            withdrawal,
+            Withdrawal {
+                total_transactions: 6,
+                total_money: 8.0,
+                ..Withdrawal::default()
+            }
+        );
+        assert_eq!(
+            greedy_withdrawal,
+            Withdrawal {
+                total_transactions: 5,
+                total_money: 8.0,
+                ..Withdrawal::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_balance_percentiles() {
+        let mut pool = PoolPerShop::new();
+        let transactions: Vec<Transaction> = (1..=5)
+            .map(|i| Transaction {
+                amount: i as f64,
+                shop_id: i % 2,
+                currency: 0,
+            })
+            .collect();
+        pool.process_transactions(&transactions).unwrap();
+
+        assert_eq!(pool.balance_percentiles(), [1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(PoolPerShop::new().balance_percentiles(), [0.0; 5]);
+    }
+
+    #[test]
+    fn test_pool_rngs_are_reproducible_and_independent() {
+        use rand::RngCore;
+
+        let draws = |mut rng: SmallRng| -> Vec<u64> {
+            (0..4).map(|_| rng.next_u64()).collect()
+        };
+        let single = draws(PoolKind::SinglePool.rng(7));
+        assert_eq!(single, draws(PoolKind::SinglePool.rng(7)));
+        assert_ne!(single, draws(PoolKind::SinglePool.rng(8)));
+        assert_ne!(single, draws(PoolKind::PoolPerShop.rng(7)));
+        // The input is generated from the seed itself.
+        let input = draws(SmallRng::seed_from_u64(7));
+        assert!(PoolKind::ALL.iter().all(|kind| draws(kind.rng(7)) != input));
+    }
+
+    #[test]
+    fn test_account_churn() {
+        let tx = |shop_id| Transaction {
+            amount: 1.0,
+            shop_id,
+            currency: 0,
+        };
+
+        let mut pool = PoolPerShop::new();
+        pool.process_transactions(&[tx(0), tx(0)]).unwrap();
+        assert_eq!(pool.accounts_created(), 2);
+        pool.process_transactions(&[tx(0), tx(0), tx(0), tx(1)])
+            .unwrap();
+        pool.withdraw_all();
+        pool.process_transactions(&[tx(1)]).unwrap();
+        assert_eq!(pool.accounts_created(), 4);
+        assert_eq!(pool.accounts_freed(), 0);
+
+        // Withdrawals recreate all accounts of a single pool.
+        let mut pool = SinglePool::new();
+        pool.process_transactions(&[tx(0), tx(1), tx(2)]).unwrap();
+        pool.withdraw_all();
+        pool.process_transactions(&[tx(0)]).unwrap();
+        pool.withdraw_all();
+        assert_eq!(pool.total_accounts(), 3);
+        assert_eq!(pool.accounts_freed(), 6);
+        assert_eq!(pool.accounts_created(), 9);
+    }
+
+    #[test]
+    fn test_dust_accounts_are_not_drained() {
+        let tx = |shop_id, amount| Transaction {
+            amount,
+            shop_id,
+            currency: 0,
+        };
+        let transactions = [tx(0, 10.0), tx(0, 0.5), tx(1, 0.25), tx(1, 5.0)];
+
+        let mut pools: [Box<dyn AccountsPool>; 2] = [
+            Box::new(SinglePool::new().with_dust_threshold(1.0)),
+            Box::new(SinglePoolGreedy::new().with_dust_threshold(1.0)),
+        ];
+        for pool in &mut pools {
+            pool.process_transactions(&transactions).unwrap();
+            let withdrawal = pool.withdraw_all();
+            assert_eq!(withdrawal.total_money, 15.0);
+            assert_eq!(withdrawal.dust_left, 0.75);
+            assert_eq!(pool.total_balance(), 0.75);
+            let snapshot = pool.snapshot();
+            assert_eq!(snapshot.shop_balances.values().sum::<f64>(), 0.75);
+            let mut balances = snapshot.account_balances;
+            balances.sort_by(f64::total_cmp);
+            assert_eq!(balances, [0.0, 0.0, 0.25, 0.5]);
+
+            // The dust keeps accumulating until it is worth draining.
+            pool.process_transactions(&[tx(1, 0.5)]).unwrap();
+            let withdrawal = pool.withdraw_all();
+            assert_eq!(withdrawal.total_transactions, 0);
+            assert_eq!(withdrawal.dust_left, 1.25);
+        }
+
+        let mut pool = PoolPerShop::new().with_dust_threshold(1.0);
+        pool.process_transactions(&transactions).unwrap();
+        let withdrawal = pool.withdraw_all();
+        assert_eq!(
+            withdrawal,
+            Withdrawal {
+                total_transactions: 2,
+                total_money: 15.0,
+                money_left: 0.75,
+                dust_left: 0.75,
+                failed_transactions: 0,
+            }
+        );
+        assert_eq!(pool.total_balance(), 0.75);
+    }
+
+    #[test]
+    fn test_withdrawal_fraction_sweeps_largest_accounts() {
+        let tx = |shop_id, amount| Transaction {
+            amount,
+            shop_id,
+            currency: 0,
+        };
+        let transactions =
+            [tx(0, 8.0), tx(0, 4.0), tx(1, 2.0), tx(1, 1.0), tx(2, 0.5)];
+
+        for (fraction, transactions_made, money_left) in
+            [(1.0, 5, 0.0), (0.5, 3, 1.5), (0.25, 2, 3.5)]
+        {
+            let mut pools: [Box<dyn AccountsPool>; 3] = [
+                Box::new(PoolPerShop::new().with_withdrawal_fraction(fraction)),
+                Box::new(SinglePool::new().with_withdrawal_fraction(fraction)),
+                Box::new(
+                    SinglePoolGreedy::new().with_withdrawal_fraction(fraction),
+                ),
+            ];
+            for pool in &mut pools {
+                pool.process_transactions(&transactions).unwrap();
+                let withdrawal = pool.withdraw_all();
+                assert_eq!(withdrawal.money_left, money_left, "{fraction}");
+                assert_eq!(withdrawal.total_money, 15.5 - money_left);
+                assert_eq!(pool.total_balance(), money_left);
+                let snapshot = pool.snapshot();
+                let carried = snapshot.shop_balances.values().sum::<f64>();
+                assert_eq!(carried, money_left);
+                // The plain single pool pays the shops in hash order,
+                // so its number of transactions is not fixed.
+                if pool.name() != "Single Pool" {
+                    assert_eq!(
+                        withdrawal.total_transactions,
+                        transactions_made
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_batches_leave_pools_empty() {
+        let config = SimConfig::default();
+        for kind in PoolKind::ALL {
+            let mut pool = kind.create(&config).unwrap();
+            assert_eq!(
+                pool.process_transactions(&[]),
+                Ok(0),
+                "{}",
+                pool.name()
+            );
+            assert_eq!(pool.total_accounts(), 0);
+            assert_eq!(pool.total_balance(), 0.0);
+            assert_eq!(pool.snapshot(), PoolSnapshot::default());
+            assert_eq!(pool.balance_percentiles(), [0.0; 5]);
+            assert_eq!(pool.withdraw_all(), Withdrawal::default());
+            pool.end_day();
+            assert_eq!(pool.accounts_created(), 0);
+        }
+    }
+
+    #[test]
+    fn test_max_account_balance_spills_into_new_accounts() {
+        let tx = |amount| Transaction {
+            amount,
+            shop_id: 0,
+            currency: 0,
+        };
+        let pools: [Box<dyn AccountsPool>; 3] = [
+            Box::new(PoolPerShop::new().with_max_account_balance(5.0)),
+            Box::new(SinglePool::new().with_max_account_balance(5.0)),
+            Box::new(SinglePoolGreedy::new().with_max_account_balance(5.0)),
+        ];
+        for mut pool in pools {
+            // 12 fills an account and spills into two new ones,
+            // then 4 overflows the account holding 3 by one.
+            assert_eq!(pool.process_transactions(&[tx(3.0), tx(12.0)]), Ok(4));
+            pool.process_transactions(&[tx(4.0)]).unwrap();
+
+            assert_eq!(
+                pool.accounts_created_due_to_cap(),
+                3,
+                "{}",
+                pool.name()
+            );
+            assert_eq!(pool.total_accounts(), 5);
+            assert_eq!(pool.total_balance(), 19.0);
+            assert!(pool.account_balances().iter().all(|&b| b <= 5.0));
+            assert_eq!(pool.withdraw_all().total_money, 19.0);
+        }
+    }
+
+    #[test]
+    fn test_destinations_multiply_withdrawal_transactions() {
+        let tx = |shop_id| Transaction {
+            amount: 3.0,
+            shop_id,
+            currency: 0,
+        };
+        let transactions = [tx(0), tx(1), tx(2)];
+
+        for destinations in [1, 3] {
+            let pools: [Box<dyn AccountsPool>; 3] = [
+                Box::new(
+                    PoolPerShop::new().with_destinations_per_shop(destinations),
+                ),
+                Box::new(
+                    SinglePool::new().with_destinations_per_shop(destinations),
+                ),
+                Box::new(
+                    SinglePoolGreedy::new()
+                        .with_destinations_per_shop(destinations),
+                ),
+            ];
+            for mut pool in pools {
+                pool.process_transactions(&transactions).unwrap();
+                let withdrawal = pool.withdraw_all();
+                assert_eq!(
+                    withdrawal.total_transactions,
+                    3 * destinations,
+                    "{}",
+                    pool.name()
+                );
+                assert_eq!(withdrawal.total_money, 9.0);
+                let snapshot = pool.snapshot();
+                assert_eq!(snapshot.shop_balances.values().sum::<f64>(), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_currencies_do_not_share_accounts() {
+        let tx = |amount, currency| Transaction {
+            amount,
+            shop_id: 0,
+            currency,
+        };
+        let pools: [Box<dyn AccountsPool>; 6] = [
+            Box::new(PoolPerShop::new()),
+            Box::new(SinglePool::new()),
+            Box::new(SinglePoolGreedy::new()),
+            Box::new(SinglePoolWithSingleAccount::new()),
+            Box::new(PoolPerRegion::new(1)),
+            Box::new(CappedPool::new(2)),
+        ];
+        for mut pool in pools {
+            pool.process_transactions(&[tx(5.0, 0), tx(1.0, 1)])
+                .unwrap();
+            pool.withdraw_all();
+            // Both accounts are empty, but only the one
+            // of the same currency can take the transaction.
+            pool.process_transactions(&[tx(2.0, 1)]).unwrap();
+            assert_eq!(
+                pool.accounts_per_currency(),
+                BTreeMap::from([(0, 1), (1, 1)]),
+                "{}",
+                pool.name()
+            );
+            pool.process_transactions(&[tx(3.0, 1), tx(4.0, 1)])
+                .unwrap();
+            assert_eq!(
+                pool.accounts_per_currency(),
+                BTreeMap::from([(0, 1), (1, 2)]),
+                "{}",
+                pool.name()
+            );
+            let withdrawal = pool.withdraw_all();
+            assert_eq!(withdrawal.total_money, 9.0, "{}", pool.name());
+            assert_eq!(pool.total_balance(), 0.0, "{}", pool.name());
+        }
+    }
+
+    #[test]
+    fn test_peak_accounts_is_the_high_water_mark() {
+        let batch = |size| -> Vec<Transaction> {
+            (0..size)
+                .map(|shop_id| Transaction {
+                    amount: 1.0,
+                    shop_id,
+                    currency: 0,
+                })
+                .collect()
+        };
+        let config = SimConfig::builder().settlement_days(1).build();
+        for kind in PoolKind::ALL {
+            let mut pool = kind.create(&config).unwrap();
+            let name = pool.name();
+            let mut high_water_mark = 0;
+            for size in [2, 5, 3, 1] {
+                pool.process_transactions(&batch(size)).unwrap();
+                high_water_mark = high_water_mark.max(pool.total_accounts());
+                assert_eq!(pool.peak_accounts(), high_water_mark, "{}", name);
+                pool.withdraw_all();
+                pool.end_day();
+                assert!(pool.peak_accounts() >= pool.total_accounts());
+            }
+            assert!(pool.peak_accounts() >= 5, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_failed_withdrawal_transactions_are_retried() {
+        let transactions: Vec<Transaction> = (0..2000)
+            .map(|shop_id| Transaction {
+                amount: 1.0,
+                shop_id,
+                currency: 0,
+            })
+            .collect();
+        let withdraw = |config: &SimConfig, kind: PoolKind| {
+            let mut pool = kind.create(config).unwrap();
+            pool.set_rng(kind.rng(0));
+            pool.process_transactions(&transactions).unwrap();
+            pool.withdraw_all()
+        };
+        let reliable = SimConfig::builder().simulated_shops_number(2000);
+        let failing = reliable.clone().withdrawal_failure_rate(0.5).build();
+        let reliable = reliable.build();
+        for kind in PoolKind::ALL {
+            if kind == PoolKind::NoWithdrawalPool {
+                continue;
+            }
+            let expected = withdraw(&reliable, kind);
+            let withdrawal = withdraw(&failing, kind);
+            let name = kind.id();
+            assert_eq!(expected.failed_transactions, 0, "{}", name);
+            assert_eq!(withdrawal.total_money, expected.total_money);
+            assert_eq!(
+                withdrawal.total_transactions,
+                expected.total_transactions + withdrawal.failed_transactions,
+                "{}",
+                name
+            );
+            // Half the attempts fail, so each transaction
+            // takes two attempts on average.
+            let inflation = withdrawal.total_transactions as f64
+                / expected.total_transactions as f64;
+            assert!((inflation - 2.0).abs() < 0.1, "{}: {}", name, inflation);
+        }
+    }
+
+    #[test]
+    fn test_refunds_net_to_a_known_balance() {
+        let tx = |shop_id, amount| Transaction {
+            amount,
+            shop_id,
+            currency: 0,
+        };
+        let shop_balance = |pool: &dyn AccountsPool, shop_id| {
+            pool.snapshot()
+                .shop_balances
+                .get(&shop_id)
+                .copied()
+                .unwrap_or_default()
+        };
+        for kind in PoolKind::ALL {
+            let mut pool = kind.create(&SimConfig::default()).unwrap();
+            let name = pool.name();
+            for batch in [
+                [tx(0, 5.0), tx(1, 4.0)],
+                [tx(0, 3.0), tx(1, -6.0)],
+                [tx(0, -2.0), tx(1, 0.0)],
+            ] {
+                pool.process_transactions(&batch).unwrap();
+            }
+            assert_eq!(pool.total_balance(), 4.0, "{}", name);
+            assert_eq!(shop_balance(pool.as_ref(), 0), 6.0, "{}", name);
+            assert_eq!(shop_balance(pool.as_ref(), 1), -2.0, "{}", name);
+
+            // Shop 1 owes money, so it is not paid and keeps its debt.
+            let first = pool.withdraw_all();
+            assert_eq!(shop_balance(pool.as_ref(), 1), -2.0, "{}", name);
+            assert_eq!(
+                first.total_money + pool.total_balance(),
+                4.0,
+                "{}",
+                name
+            );
+            let snapshot = pool.snapshot();
+            assert_eq!(
+                snapshot.shop_balances.values().sum::<f64>(),
+                pool.total_balance(),
+                "{}",
+                name
+            );
+
+            // Paying the debt off leaves shop 1 with the rest.
+            pool.process_transactions(&[tx(1, 3.0)]).unwrap();
+            let second = pool.withdraw_all();
+            if kind == PoolKind::NoWithdrawalPool {
+                assert_eq!(pool.total_balance(), 7.0);
+                continue;
+            }
+            assert_eq!(first.total_money + second.total_money, 7.0, "{}", name);
+            assert_eq!(pool.total_balance(), 0.0, "{}", name);
+            assert_eq!(shop_balance(pool.as_ref(), 1), 0.0, "{}", name);
+        }
+
+        let mut pool = SinglePool::new();
+        pool.process_transactions(&[tx(0, 5.0)]).unwrap();
+        pool.process_transactions(&[tx(1, -2.0)]).unwrap();
+        // The refund took shop 0's money, which it gets later.
+        assert_eq!(pool.withdraw_all().total_money, 3.0);
+        assert_eq!(shop_balance(&pool, 0), 2.0);
+        assert_eq!(pool.shortfall(), 0.0);
+    }
+
+    #[test]
+    fn test_reset_pool_matches_a_fresh_one() {
+        let config = SimConfig::builder()
+            .simulated_shops_number(4)
+            .simulated_currencies_number(2)
+            .settlement_days(1)
+            .capped_pool_max_accounts(3)
+            .max_account_balance(2.5)
+            .build();
+        // Equal amounts keep the order shops are paid in from mattering.
+        let batches: Vec<Vec<Transaction>> = (1..=4)
+            .map(|count| {
+                (0..count)
+                    .map(|shop_id| Transaction {
+                        amount: 1.0,
+                        shop_id,
+                        currency: shop_id % 2,
+                    })
+                    .collect()
+            })
+            .collect();
+        let run = |pool: &mut dyn AccountsPool| {
+            let mut states = vec![];
+            for batch in &batches {
+                let touched = pool.process_transactions(batch).unwrap();
+                let mut snapshot = pool.snapshot();
+                snapshot.account_balances.sort_by(f64::total_cmp);
+                let withdrawal = pool.withdraw_all();
+                pool.end_day();
+                states.push((
+                    touched,
+                    snapshot,
+                    withdrawal,
+                    pool.total_accounts(),
+                    pool.peak_accounts(),
+                    pool.accounts_created(),
+                    pool.accounts_per_currency(),
+                    pool.queued_transactions(),
+                    pool.extra_accounts_due_to_settlement(),
+                    pool.accounts_created_due_to_cap(),
+                ));
+            }
+            states
+        };
+
+        for kind in PoolKind::ALL {
+            let mut fresh = kind.create(&config).unwrap();
+            let expected = run(fresh.as_mut());
+
+            let mut reused = kind.create(&config).unwrap();
+            run(reused.as_mut());
+            reused.reset();
+            assert_eq!(reused.total_accounts(), 0, "{}", reused.name());
+            assert_eq!(reused.peak_accounts(), 0, "{}", reused.name());
+            assert_eq!(reused.accounts_created(), 0, "{}", reused.name());
+            assert_eq!(run(reused.as_mut()), expected, "{}", reused.name());
+        }
     }
 }
@@ -1,28 +1,113 @@
-use rand::Rng;
-use rand_distr::Distribution;
+use anyhow::{anyhow, ensure, Result};
+use rand::{distributions::DistIter, rngs::SmallRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Geometric, Poisson};
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    io::Write,
+    iter,
+    ops::Range,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
 
 use crate::{
     data::{
-        AnnualOrdersDistribution, PoolResults, SimConfig, SimResults,
-        Transaction, DAYS_IN_YEAR, HOURS_IN_DAY,
+        load_shop_sizes, validate_config, AnnualOrdersDistribution,
+        DailyMultipliers, DailyOrdersDistribution, EnsembleResults,
+        MethodTiming, MetricDiff, MetricSummary, OrderCountNoise, PoolDiff,
+        PoolEnsembleResults, PoolResults, PoolTimings, ResultsDiff, SaleTiming,
+        ShopId, SimConfig, SimResults, Transaction, TransactionEstimate,
+        WithdrawalFee, WithdrawalPolicy, DAYS_IN_WEEK, DAYS_IN_YEAR,
+        HOURS_IN_DAY,
     },
-    pool::AccountsPool,
+    pool::{AccountsPool, PoolError, PoolKind, PoolSnapshot},
+    util::{gini, CountingRng, KahanSum, RngCounts, Welford},
 };
 
 pub struct GlobalData {
     pub shop_sizes: Vec<f64>,
+    /** Expected number of orders of each shop at each hour. */
+    pub shop_daily_distributions: Vec<DailyOrdersDistribution>,
 }
 
+/**
+ * Upper bound on the number of samples drawn per requested shop
+ * before giving up on the shop size distribution.
+ */
+const SHOP_SIZE_ATTEMPTS_PER_SHOP: usize = 100;
+
 impl GlobalData {
-    pub fn gen(rng: impl Rng, config: &SimConfig) -> Self {
-        Self {
-            shop_sizes: config
-                .shop_size_distribution
-                .sample_iter(rng)
-                .filter(|&size| size > 0.0)
-                .take(config.simulated_shops_number)
-                .collect(),
+    /**
+     * Fails if the shop sizes file can't be loaded,
+     * the shop size distribution produces too few
+     * positive values to fill all the requested shops
+     * or the daily orders expression fails for some shop size.
+     *
+     * Sizes are sampled even for shops with a fixed size,
+     * so that overriding one shop doesn't change the others.
+     * `shop_sizes` keeps them before `shop_size_scaling`.
+     */
+    pub fn try_gen(mut rng: impl Rng, config: &SimConfig) -> Result<Self> {
+        let requested = config.simulated_shops_number;
+        let mut shop_sizes = match load_shop_sizes(config, requested)? {
+            Some(shop_sizes) => shop_sizes,
+            None => {
+                let attempts = requested * SHOP_SIZE_ATTEMPTS_PER_SHOP;
+                let shop_sizes: Vec<f64> = (0..attempts)
+                    .filter_map(|_| {
+                        config.shop_size_distribution.try_sample(&mut rng)
+                    })
+                    .take(requested)
+                    .collect();
+
+                ensure!(
+                    shop_sizes.len() == requested,
+                    "only {} of {} shops got a positive size from {:?}",
+                    shop_sizes.len(),
+                    requested,
+                    config.shop_size_distribution,
+                );
+                shop_sizes
+            }
+        };
+
+        for (&shop_id, shop_override) in &config.shop_overrides {
+            if let (Some(shop_size), Some(size)) =
+                (shop_sizes.get_mut(shop_id), shop_override.size)
+            {
+                *shop_size = size;
+            }
         }
+
+        let shop_daily_distributions = shop_sizes
+            .iter()
+            .enumerate()
+            .map(|(shop_id, &shop_size)| {
+                config
+                    .shop_overrides
+                    .get(&shop_id)
+                    .and_then(|shop_override| {
+                        shop_override.daily_distribution.as_ref()
+                    })
+                    .unwrap_or(&config.default_daily_distribution)
+                    .eval(config.shop_size_scaling.apply(shop_size))
+                    .map_err(|err| {
+                        anyhow!(
+                            "shop {} of size {}: {}",
+                            shop_id,
+                            shop_size,
+                            err
+                        )
+                    })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            shop_sizes,
+            shop_daily_distributions,
+        })
     }
 }
 
@@ -36,23 +121,69 @@ impl AnnualData {
         config: &SimConfig,
         global_data: &GlobalData,
     ) -> Self {
+        let shared_sale_days = (config.synchronize_sales
+            && !config.disable_sales)
+            .then(|| sale_days(&mut rng, config));
         Self {
             shop_distributions: global_data
-                .shop_sizes
+                .shop_daily_distributions
                 .iter()
-                .map(|&shop_size| {
+                .enumerate()
+                .map(|(shop_id, &default_daily_distribution)| {
                     let mut daily_multipliers =
-                        config.default_daily_multipliers;
-                    for _ in 0..config.sales_per_year_for_each_shop {
-                        let i = (rng.next_u32() as usize) % DAYS_IN_YEAR;
-                        daily_multipliers[i] *= config.sale_multiplier;
+                        shop_daily_multipliers(config, shop_id);
+                    let own_sale_days;
+                    let sale_days: &[usize] = match &shared_sale_days {
+                        Some(sale_days) => sale_days,
+                        None if config.disable_sales => &[],
+                        None => {
+                            own_sale_days = sale_days(&mut rng, config);
+                            &own_sale_days
+                        }
+                    };
+                    for &day in sale_days {
+                        daily_multipliers[day] *= config.sale_multiplier;
                     }
 
-                    let default_daily_distribution =
-                        config.default_daily_distribution.map(|txs_per_hour| {
-                            ((txs_per_hour as f64) * shop_size) as usize
-                        });
+                    AnnualOrdersDistribution {
+                        daily_multipliers,
+                        default_daily_distribution,
+                    }
+                })
+                .collect(),
+        }
+    }
 
+    /**
+     * Same as `gen`, but without randomness: each day gets
+     * the expected product of the multipliers of the sales
+     * falling on it instead of the sampled ones.
+     */
+    pub fn expected(config: &SimConfig, global_data: &GlobalData) -> Self {
+        let sale_multiplier = match config.sale_timing {
+            _ if config.disable_sales => 1.0,
+            // Each sale falls on the day with probability 1 / DAYS_IN_YEAR,
+            // independently of the others.
+            SaleTiming::Uniform => (1.0
+                + (config.sale_multiplier - 1.0) / DAYS_IN_YEAR as f64)
+                .powi(config.sales_per_year_for_each_shop as i32),
+            // The geometric gaps make every day a sale day
+            // with probability 1 / mean independently of the others.
+            SaleTiming::Spaced { mean_gap_days } => {
+                1.0 + (config.sale_multiplier - 1.0) / mean_gap_days.max(1.0)
+            }
+        };
+        Self {
+            shop_distributions: global_data
+                .shop_daily_distributions
+                .iter()
+                .enumerate()
+                .map(|(shop_id, &default_daily_distribution)| {
+                    let mut daily_multipliers =
+                        shop_daily_multipliers(config, shop_id);
+                    for multiplier in &mut daily_multipliers {
+                        *multiplier *= sale_multiplier;
+                    }
                     AnnualOrdersDistribution {
                         daily_multipliers,
                         default_daily_distribution,
@@ -63,97 +194,3616 @@ impl AnnualData {
     }
 }
 
+/**
+ * Draws the sale days of a year according to `sale_timing`.
+ * A day appears once for each sale falling on it.
+ */
+fn sale_days(mut rng: impl Rng, config: &SimConfig) -> Vec<usize> {
+    match config.sale_timing {
+        SaleTiming::Uniform => (0..config.sales_per_year_for_each_shop)
+            .map(|_| (rng.next_u32() as usize) % DAYS_IN_YEAR)
+            .collect(),
+        SaleTiming::Spaced { mean_gap_days } => {
+            // A gap is one day plus the number of failures
+            // before a success of probability 1 / mean.
+            let gaps = Geometric::new(1.0 / mean_gap_days.max(1.0))
+                .expect("the probability is in (0, 1]");
+            let mut days = vec![];
+            let mut day = gaps.sample(&mut rng) as usize;
+            while day < DAYS_IN_YEAR {
+                days.push(day);
+                day = day.saturating_add(1 + gaps.sample(&mut rng) as usize);
+            }
+            days
+        }
+    }
+}
+
+/** Daily multipliers of the shop before the sales are placed. */
+fn shop_daily_multipliers(
+    config: &SimConfig,
+    shop_id: ShopId,
+) -> DailyMultipliers {
+    config
+        .shop_overrides
+        .get(&shop_id)
+        .and_then(|shop_override| shop_override.daily_multipliers)
+        .unwrap_or(config.default_daily_multipliers)
+}
+
+/**
+ * Returns the weekday and the holiday multipliers of the day
+ * with the given index counted from the start of the simulation.
+ */
+fn calendar_multipliers(config: &SimConfig, absolute_day: usize) -> (f64, f64) {
+    let day = absolute_day % DAYS_IN_YEAR;
+    let weekday_multiplier =
+        config.weekday_multipliers[absolute_day % DAYS_IN_WEEK];
+    let holiday_multiplier = config
+        .holidays
+        .iter()
+        .filter(|holiday| holiday.day == day)
+        .map(|holiday| holiday.multiplier)
+        .product();
+    (weekday_multiplier, holiday_multiplier)
+}
+
 pub struct DailyData {
     pub transactions: [Vec<Transaction>; HOURS_IN_DAY],
+    /** Whether a scheduled withdrawal happens during this day. */
     pub withdrawal: bool,
+    /** Hours at the end of which a scheduled withdrawal happens. */
+    pub withdrawal_hours: [bool; HOURS_IN_DAY],
+    /**
+     * Number of shop hours whose orders were clamped
+     * to `max_transactions_per_hour`.
+     */
+    pub clamped_order_counts: usize,
 }
 
 impl DailyData {
+    /**
+     * Generates data for the day with the given index counted
+     * from the start of the simulation, not from the start of the year.
+     *
+     * Transaction amounts are taken from `prices`, which is meant
+     * to live for the whole run, so that the sequence of prices
+     * doesn't depend on how the days are generated.
+     */
     pub fn gen(
         rng: impl Rng,
+        prices: &mut impl Iterator<Item = f64>,
         config: &SimConfig,
         annual_data: &AnnualData,
-        day: usize,
+        absolute_day: usize,
     ) -> Self {
-        let mut prices = config
-            .price_distribution
-            .sample_iter(rng)
-            .filter(|&price| price > 0.0);
+        let mut daily_data = Self {
+            transactions: Default::default(),
+            withdrawal: false,
+            withdrawal_hours: [false; HOURS_IN_DAY],
+            clamped_order_counts: 0,
+        };
+        daily_data.regen_into(rng, prices, config, annual_data, absolute_day);
+        daily_data
+    }
 
-        Self {
-            transactions: std::array::from_fn(|hour| {
-                let mut transactions = Vec::new();
-                for (shop_id, distr) in
-                    annual_data.shop_distributions.iter().enumerate()
-                {
-                    let txs_number = distr.daily_multipliers[day]
-                        * distr.default_daily_distribution[hour];
+    /**
+     * Same as `gen`, but overwrites this day in place,
+     * reusing the already allocated transaction buffers.
+     */
+    pub fn regen_into(
+        &mut self,
+        mut rng: impl Rng,
+        prices: &mut impl Iterator<Item = f64>,
+        config: &SimConfig,
+        annual_data: &AnnualData,
+        absolute_day: usize,
+    ) {
+        for transactions in &mut self.transactions {
+            transactions.clear();
+        }
+        let mut stream = TransactionStream::new(
+            config,
+            annual_data,
+            &mut rng,
+            &mut *prices,
+            absolute_day..absolute_day + 1,
+        );
+        for (_, hour, tx) in &mut stream {
+            self.transactions[hour].push(tx);
+        }
+        self.clamped_order_counts = stream.clamped_order_counts();
+        self.withdrawal_hours = withdrawal_hours(config, absolute_day);
+        self.withdrawal = self.withdrawal_hours.contains(&true);
+    }
+}
 
-                    for _ in 0..txs_number {
-                        let amount = prices.next().unwrap();
-                        transactions.push(Transaction { amount, shop_id });
-                    }
+/**
+ * Returns the hours of the day at the end of which
+ * a scheduled withdrawal happens.
+ */
+fn withdrawal_hours(
+    config: &SimConfig,
+    absolute_day: usize,
+) -> [bool; HOURS_IN_DAY] {
+    let first_hour = absolute_day * HOURS_IN_DAY;
+    // With an offset, the first withdrawal falls on the offset
+    // itself rather than at the end of the first period.
+    let offset = config.withdrawal_offset_days;
+    match config.effective_withdrawal_policy() {
+        WithdrawalPolicy::Periodic { days } => {
+            let mut hours = [false; HOURS_IN_DAY];
+            hours[HOURS_IN_DAY - 1] = match offset {
+                Some(offset) => {
+                    absolute_day >= offset
+                        && (absolute_day - offset).is_multiple_of(days)
                 }
-                transactions
-            }),
-            withdrawal: (day % config.withdrawal_period_in_days) == 0,
+                None => (absolute_day + 1).is_multiple_of(days),
+            };
+            hours
+        }
+        WithdrawalPolicy::Hourly { hours } => std::array::from_fn(|hour| {
+            let hour = first_hour + hour;
+            match offset {
+                Some(offset) => {
+                    let first_hour = offset * HOURS_IN_DAY;
+                    hour >= first_hour
+                        && (hour - first_hour).is_multiple_of(hours)
+                }
+                None => (hour + 1).is_multiple_of(hours),
+            }
+        }),
+        WithdrawalPolicy::Threshold { .. }
+        | WithdrawalPolicy::Combined { .. } => [false; HOURS_IN_DAY],
+    }
+}
+
+/**
+ * Lazily generates the transactions of a range of days as triples
+ * of the day, counted from the start of the simulation, the hour
+ * and the transaction. Draws from `rng` and `prices` in the same
+ * order as generating the days one by one with `DailyData::gen`.
+ */
+pub struct TransactionStream<'a, R, P> {
+    config: &'a SimConfig,
+    annual_data: &'a AnnualData,
+    rng: R,
+    prices: P,
+    end_day: usize,
+    day: usize,
+    hour: usize,
+    /** Next shop whose orders of the current hour are drawn. */
+    next_shop_id: ShopId,
+    /** Shop of the orders left to yield. */
+    shop_id: ShopId,
+    /** Orders of `shop_id` at the current hour left to yield. */
+    pending_orders: usize,
+    weekday_multiplier: f64,
+    holiday_multiplier: f64,
+    clamped_order_counts: usize,
+}
+
+impl<'a, R: Rng, P: Iterator<Item = f64>> TransactionStream<'a, R, P> {
+    pub fn new(
+        config: &'a SimConfig,
+        annual_data: &'a AnnualData,
+        rng: R,
+        prices: P,
+        days: Range<usize>,
+    ) -> Self {
+        let (weekday_multiplier, holiday_multiplier) =
+            calendar_multipliers(config, days.start);
+        Self {
+            config,
+            annual_data,
+            rng,
+            prices,
+            end_day: days.end,
+            day: days.start,
+            hour: 0,
+            next_shop_id: 0,
+            shop_id: 0,
+            pending_orders: 0,
+            weekday_multiplier,
+            holiday_multiplier,
+            clamped_order_counts: 0,
+        }
+    }
+
+    /** Streams all days of the given year of the simulation. */
+    pub fn for_year(
+        config: &'a SimConfig,
+        annual_data: &'a AnnualData,
+        rng: R,
+        prices: P,
+        year: usize,
+    ) -> Self {
+        let first_day = year * DAYS_IN_YEAR;
+        Self::new(
+            config,
+            annual_data,
+            rng,
+            prices,
+            first_day..first_day + DAYS_IN_YEAR,
+        )
+    }
+
+    /**
+     * Number of shop hours so far whose orders were clamped
+     * to `max_transactions_per_hour`.
+     */
+    pub fn clamped_order_counts(&self) -> usize {
+        self.clamped_order_counts
+    }
+
+    /** Draws the number of orders of the shop at the current hour. */
+    fn draw_orders(&mut self, shop_id: ShopId) -> usize {
+        let distr = &self.annual_data.shop_distributions[shop_id];
+        let max_txs_number = self.config.max_transactions_per_hour;
+        let expected_txs_number = distr.daily_multipliers
+            [self.day % DAYS_IN_YEAR]
+            * distr.default_daily_distribution[self.hour]
+            * self.weekday_multiplier
+            * self.holiday_multiplier;
+        let capped_txs_number = expected_txs_number.min(max_txs_number as f64);
+        let txs_number = match self.config.order_count_noise {
+            OrderCountNoise::None => capped_txs_number.round() as usize,
+            OrderCountNoise::Poisson if capped_txs_number > 0.0 => {
+                Poisson::new(capped_txs_number)
+                    .unwrap()
+                    .sample(&mut self.rng) as usize
+            }
+            OrderCountNoise::Poisson => 0,
+        };
+        if expected_txs_number > capped_txs_number
+            || txs_number > max_txs_number
+        {
+            self.clamped_order_counts += 1;
+        }
+        txs_number.min(max_txs_number)
+    }
+}
+
+impl<R: Rng, P: Iterator<Item = f64>> Iterator for TransactionStream<'_, R, P> {
+    type Item = (usize, usize, Transaction);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending_orders == 0 {
+            if self.day >= self.end_day {
+                return None;
+            }
+            if self.next_shop_id < self.annual_data.shop_distributions.len() {
+                self.shop_id = self.next_shop_id;
+                self.next_shop_id += 1;
+                self.pending_orders = self.draw_orders(self.shop_id);
+                continue;
+            }
+            self.next_shop_id = 0;
+            self.hour += 1;
+            if self.hour == HOURS_IN_DAY {
+                self.hour = 0;
+                self.day += 1;
+                (self.weekday_multiplier, self.holiday_multiplier) =
+                    calendar_multipliers(self.config, self.day);
+            }
         }
+        self.pending_orders -= 1;
+        let mut amount =
+            self.prices.next().expect("price samples never run out");
+        // Without refunds nothing is drawn, keeping the input intact.
+        if self.config.refund_rate > 0.0
+            && self.rng.gen_bool(self.config.refund_rate)
+        {
+            amount = -amount;
+        }
+        let tx = Transaction {
+            amount,
+            shop_id: self.shop_id,
+            currency: self.shop_id % self.config.simulated_currencies_number,
+        };
+        Some((self.day, self.hour, tx))
     }
 }
 
+/**
+ * Returns the number of transactions made during the withdrawals
+ * of the day, zero if there were none. Scheduled withdrawals
+ * happen at the end of their hour, the others at the end of the day.
+ * Fails if the pool rejects some of the transactions.
+ *
+ * Before each withdrawal, `before_withdrawal` receives
+ * the snapshot of the pool right before it is drained.
+ *
+ * `days_since_withdrawal` is the clock of the combined policy.
+ * It starts at zero for each pool and is advanced at the end
+ * of each day, unless there is a withdrawal which resets it.
+ */
 pub fn simulate_day(
     daily_data: &DailyData,
-    pool: &mut impl AccountsPool,
+    withdrawal_policy: WithdrawalPolicy,
+    withdrawal_fee: &WithdrawalFee,
+    pool: &mut dyn AccountsPool,
+    pool_stats: &mut PoolStats,
+    days_since_withdrawal: &mut usize,
+    before_withdrawal: Option<&mut dyn FnMut(&PoolSnapshot)>,
+) -> Result<usize, PoolError> {
+    simulate_hours(
+        &daily_data.withdrawal_hours,
+        |hour, pool, active_shops| {
+            let transactions = &daily_data.transactions[hour];
+            active_shops.extend(transactions.iter().map(|tx| tx.shop_id));
+            pool.process_transactions(transactions)
+        },
+        withdrawal_policy,
+        withdrawal_fee,
+        pool,
+        pool_stats,
+        days_since_withdrawal,
+        before_withdrawal,
+    )
+}
+
+/**
+ * Same as `simulate_day`, but `process_hour` feeds the pool
+ * the transactions of each hour, adds their shops to the set
+ * of active shops and returns the number of accounts touched.
+ */
+#[allow(clippy::too_many_arguments)]
+fn simulate_hours(
+    withdrawal_hours: &[bool; HOURS_IN_DAY],
+    mut process_hour: impl FnMut(
+        usize,
+        &mut dyn AccountsPool,
+        &mut HashSet<ShopId>,
+    ) -> Result<usize, PoolError>,
+    withdrawal_policy: WithdrawalPolicy,
+    withdrawal_fee: &WithdrawalFee,
+    pool: &mut dyn AccountsPool,
     pool_stats: &mut PoolStats,
-) {
-    for hour in 0..HOURS_IN_DAY {
-        let transactions = &daily_data.transactions[hour];
-        pool.process_transactions(transactions);
+    days_since_withdrawal: &mut usize,
+    mut before_withdrawal: Option<&mut dyn FnMut(&PoolSnapshot)>,
+) -> Result<usize, PoolError> {
+    let accounts_created = pool.accounts_created();
+    let accounts_freed = pool.accounts_freed();
+    let accounts_created_due_to_cap = pool.accounts_created_due_to_cap();
+    let mut withdrawal_transactions = 0;
+    let mut withdrawn = false;
+    for (hour, &withdrawal_hour) in withdrawal_hours.iter().enumerate() {
+        let process_transactions = pool_stats
+            .timers
+            .as_mut()
+            .map(|timers| &mut timers.process_transactions);
+        let accounts_touched = timed(process_transactions, || {
+            process_hour(hour, &mut *pool, &mut pool_stats.active_shops)
+        })?;
+        pool_stats.peak_parallel_accounts_touched = pool_stats
+            .peak_parallel_accounts_touched
+            .max(accounts_touched);
+        if withdrawal_hour {
+            withdrawal_transactions += withdraw(
+                withdrawal_fee,
+                pool,
+                pool_stats,
+                before_withdrawal.as_deref_mut(),
+            );
+            withdrawn = true;
+        }
     }
 
-    if daily_data.withdrawal {
-        pool_stats.total_number_of_transactions_during_withdrawals +=
-            pool.withdraw_all();
+    let withdrawal_triggered = match withdrawal_policy {
+        WithdrawalPolicy::Periodic { .. } | WithdrawalPolicy::Hourly { .. } => {
+            false
+        }
+        WithdrawalPolicy::Threshold { amount } => pool.total_balance() > amount,
+        WithdrawalPolicy::Combined { days, amount } => {
+            *days_since_withdrawal + 1 >= days || pool.total_balance() > amount
+        }
+    };
+    if withdrawal_triggered {
+        withdrawal_transactions +=
+            withdraw(withdrawal_fee, pool, pool_stats, before_withdrawal);
+        withdrawn = true;
+    }
+    *days_since_withdrawal = if withdrawn {
+        0
+    } else {
+        *days_since_withdrawal + 1
+    };
+
+    pool.end_day();
+    pool_stats.accounts_created += pool.accounts_created() - accounts_created;
+    pool_stats.accounts_freed += pool.accounts_freed() - accounts_freed;
+    pool_stats.accounts_created_due_to_cap +=
+        pool.accounts_created_due_to_cap() - accounts_created_due_to_cap;
+    // Pools of independent years start afresh, so their peaks
+    // are only comparable through the stats.
+    pool_stats.peak_accounts =
+        pool_stats.peak_accounts.max(pool.peak_accounts());
+    Ok(withdrawal_transactions)
+}
+
+/**
+ * Drains the pool and records the withdrawal in `pool_stats`.
+ * Returns the number of transactions it made.
+ */
+fn withdraw(
+    withdrawal_fee: &WithdrawalFee,
+    pool: &mut dyn AccountsPool,
+    pool_stats: &mut PoolStats,
+    before_withdrawal: Option<&mut (dyn FnMut(&PoolSnapshot) + '_)>,
+) -> usize {
+    if let Some(before_withdrawal) = before_withdrawal {
+        before_withdrawal(&pool.snapshot());
+    }
+    pool_stats.last_balance_percentiles = pool.balance_percentiles();
+    pool_stats.total_gini += gini(&pool.account_balances());
+    let withdraw_all = pool_stats
+        .timers
+        .as_mut()
+        .map(|timers| &mut timers.withdraw_all);
+    let withdrawal = timed(withdraw_all, || pool.withdraw_all());
+    debug!(
+        pool = pool.name(),
+        transactions = withdrawal.total_transactions,
+        money = withdrawal.total_money,
+        "Withdrawal"
+    );
+    pool_stats.withdrawal_events += 1;
+    pool_stats.total_number_of_transactions_during_withdrawals +=
+        withdrawal.total_transactions;
+    pool_stats.failed_withdrawal_transactions += withdrawal.failed_transactions;
+    pool_stats.total_money_withdrawn += withdrawal.total_money;
+    pool_stats.total_fees += withdrawal_fee.fee(&withdrawal);
+    pool_stats.total_dust_left += withdrawal.dust_left;
+    pool_stats.total_money_left += withdrawal.money_left;
+    let active_shops = pool_stats.active_shops.len();
+    pool_stats.total_active_shops += active_shops;
+    pool_stats.peak_active_shops =
+        pool_stats.peak_active_shops.max(active_shops);
+    pool_stats.active_shops.clear();
+    withdrawal.total_transactions
+}
+
+/** Wall-clock time spent in one of the pool methods. */
+#[derive(Clone, Copy, Default)]
+struct MethodTimer {
+    calls: usize,
+    total: Duration,
+}
+
+impl MethodTimer {
+    fn add(&mut self, other: MethodTimer) {
+        self.calls += other.calls;
+        self.total += other.total;
+    }
+
+    fn results(self) -> MethodTiming {
+        let total_seconds = self.total.as_secs_f64();
+        MethodTiming {
+            calls: self.calls,
+            total_seconds,
+            average_seconds: if self.calls > 0 {
+                total_seconds / self.calls as f64
+            } else {
+                0.0
+            },
+        }
     }
 }
 
+#[derive(Clone, Copy, Default)]
+struct PoolTimers {
+    process_transactions: MethodTimer,
+    withdraw_all: MethodTimer,
+}
+
+/**
+ * Runs `f`, adding the time it took to `timer` if there is one.
+ * Without a timer the clock isn't read at all.
+ */
+fn timed<T>(timer: Option<&mut MethodTimer>, f: impl FnOnce() -> T) -> T {
+    let Some(timer) = timer else {
+        return f();
+    };
+    let start = Instant::now();
+    let result = f();
+    timer.total += start.elapsed();
+    timer.calls += 1;
+    result
+}
+
 #[derive(Default)]
 pub struct PoolStats {
     total_number_of_transactions_during_withdrawals: usize,
+    withdrawal_events: usize,
+    last_balance_percentiles: [f64; 5],
+    total_gini: f64,
+    peak_parallel_accounts_touched: usize,
+    total_money_withdrawn: f64,
+    total_fees: f64,
+    /** Shops with transactions since the last withdrawal. */
+    active_shops: HashSet<ShopId>,
+    total_active_shops: usize,
+    peak_active_shops: usize,
+    accounts_created: usize,
+    accounts_freed: usize,
+    accounts_created_due_to_cap: usize,
+    peak_accounts: usize,
+    failed_withdrawal_transactions: usize,
+    total_dust_left: f64,
+    total_money_left: f64,
+    /** Only kept when the config asks to `profile` the pools. */
+    timers: Option<PoolTimers>,
 }
 
 impl PoolStats {
-    pub fn results(self, pool: &impl AccountsPool) -> PoolResults {
+    /** Empty stats, timing the pool if the config asks to. */
+    pub fn new(config: &SimConfig) -> Self {
+        Self {
+            timers: config.profile.then(PoolTimers::default),
+            ..Self::default()
+        }
+    }
+
+    /**
+     * Adds the figures of `other`, which must cover the days
+     * right after the ones of `self`. The period left open
+     * at the end of `self` is dropped, as happens when each year
+     * starts with a fresh pool.
+     */
+    pub fn merge(&mut self, other: PoolStats) {
+        self.total_number_of_transactions_during_withdrawals +=
+            other.total_number_of_transactions_during_withdrawals;
+        self.withdrawal_events += other.withdrawal_events;
+        if other.withdrawal_events > 0 {
+            self.last_balance_percentiles = other.last_balance_percentiles;
+        }
+        self.total_gini += other.total_gini;
+        self.peak_parallel_accounts_touched = self
+            .peak_parallel_accounts_touched
+            .max(other.peak_parallel_accounts_touched);
+        self.total_money_withdrawn += other.total_money_withdrawn;
+        self.total_fees += other.total_fees;
+        self.active_shops = other.active_shops;
+        self.total_active_shops += other.total_active_shops;
+        self.peak_active_shops =
+            self.peak_active_shops.max(other.peak_active_shops);
+        self.accounts_created += other.accounts_created;
+        self.accounts_freed += other.accounts_freed;
+        self.accounts_created_due_to_cap += other.accounts_created_due_to_cap;
+        self.peak_accounts = self.peak_accounts.max(other.peak_accounts);
+        self.failed_withdrawal_transactions +=
+            other.failed_withdrawal_transactions;
+        self.total_dust_left += other.total_dust_left;
+        self.total_money_left += other.total_money_left;
+        if let Some(other_timers) = other.timers {
+            let timers = self.timers.get_or_insert_with(PoolTimers::default);
+            timers
+                .process_transactions
+                .add(other_timers.process_transactions);
+            timers.withdraw_all.add(other_timers.withdraw_all);
+        }
+    }
+
+    pub fn results(self, pool: &dyn AccountsPool) -> PoolResults {
         PoolResults {
             total_number_of_transactions_during_withdrawals: self
                 .total_number_of_transactions_during_withdrawals,
+            withdrawal_events: self.withdrawal_events,
+            last_balance_percentiles: self.last_balance_percentiles,
+            average_gini: if self.withdrawal_events > 0 {
+                self.total_gini / self.withdrawal_events as f64
+            } else {
+                0.0
+            },
+            average_active_shops_per_period: if self.withdrawal_events > 0 {
+                self.total_active_shops as f64 / self.withdrawal_events as f64
+            } else {
+                0.0
+            },
+            peak_active_shops_per_period: self.peak_active_shops,
+            accounts_created: self.accounts_created,
+            accounts_freed: self.accounts_freed,
+            average_dust_left: if self.withdrawal_events > 0 {
+                self.total_dust_left / self.withdrawal_events as f64
+            } else {
+                0.0
+            },
+            average_money_left: if self.withdrawal_events > 0 {
+                self.total_money_left / self.withdrawal_events as f64
+            } else {
+                0.0
+            },
             total_number_of_accounts: pool.total_accounts(),
-            pool_name: pool.name(),
+            failed_withdrawal_transactions: self.failed_withdrawal_transactions,
+            peak_accounts: self.peak_accounts,
+            accounts_per_currency: pool.accounts_per_currency(),
+            peak_parallel_accounts_touched: self.peak_parallel_accounts_touched,
+            total_money_withdrawn: self.total_money_withdrawn,
+            total_fees: self.total_fees,
+            total_number_of_queued_transactions: pool.queued_transactions(),
+            extra_accounts_due_to_settlement: pool
+                .extra_accounts_due_to_settlement(),
+            accounts_created_due_to_cap: self.accounts_created_due_to_cap,
+            timings: self.timers.map(|timers| PoolTimings {
+                process_transactions: timers.process_transactions.results(),
+                withdraw_all: timers.withdraw_all.results(),
+            }),
+            pool_name: pool.name().to_string(),
         }
     }
 }
 
-#[derive(Default)]
 pub struct GlobalStats {
     total_number_of_transactions: usize,
     peak_parallel_transactions_number: usize,
+    peak_concurrent_transactions: usize,
+    transaction_duration_minutes: f64,
+    total_number_of_clamped_order_counts: usize,
+    histogram_bin_width: usize,
+    transactions_per_hour_histogram: Vec<usize>,
+    total_transaction_volume: KahanSum,
+    min_amount: f64,
+    max_amount: f64,
+    amounts: Welford,
 }
 
 impl GlobalStats {
-    pub fn results(&self, pool_results: Vec<PoolResults>) -> SimResults {
+    /**
+     * The histogram of transactions per hour gets `histogram_bins`
+     * bins evenly splitting `max_transactions_per_hour`.
+     */
+    pub fn new(config: &SimConfig) -> Self {
+        let bins = config.histogram_bins.max(1);
+        Self {
+            total_number_of_transactions: 0,
+            peak_parallel_transactions_number: 0,
+            peak_concurrent_transactions: 0,
+            transaction_duration_minutes: config.transaction_duration_minutes,
+            total_number_of_clamped_order_counts: 0,
+            histogram_bin_width: config
+                .max_transactions_per_hour
+                .div_ceil(bins)
+                .max(1),
+            transactions_per_hour_histogram: vec![0; bins],
+            total_transaction_volume: KahanSum::default(),
+            min_amount: f64::INFINITY,
+            max_amount: f64::NEG_INFINITY,
+            amounts: Welford::default(),
+        }
+    }
+
+    pub fn results(
+        &self,
+        config: &SimConfig,
+        seed: u64,
+        pool_results: Vec<PoolResults>,
+    ) -> SimResults {
+        let (min_amount, max_amount) = if self.total_number_of_transactions == 0
+        {
+            (0.0, 0.0)
+        } else {
+            (self.min_amount, self.max_amount)
+        };
         SimResults {
+            seed,
             total_number_of_transactions: self.total_number_of_transactions,
             peak_parallel_transactions_number: self
                 .peak_parallel_transactions_number,
+            peak_concurrent_transactions: self.peak_concurrent_transactions,
+            total_number_of_clamped_order_counts: self
+                .total_number_of_clamped_order_counts,
+            transactions_per_hour_bin_width: self.histogram_bin_width,
+            transactions_per_hour_histogram: self
+                .transactions_per_hour_histogram
+                .clone(),
+            total_transaction_volume: self.total_transaction_volume.value(),
+            min_amount,
+            max_amount,
+            mean_amount: self.amounts.mean(),
+            amount_variance: self.amounts.sample_variance(),
+            simulated_years: config.total_days().div_ceil(DAYS_IN_YEAR),
+            simulated_days: config.total_days(),
             pool_results,
+            effective_config: config.clone(),
         }
     }
 
     pub fn update(&mut self, daily_data: &DailyData) {
         for txs in &daily_data.transactions {
-            self.peak_parallel_transactions_number =
-                self.peak_parallel_transactions_number.max(txs.len());
-            self.total_number_of_transactions += txs.len();
+            for tx in txs {
+                self.add_transaction(tx);
+            }
+            self.end_hour(txs.len());
+        }
+        self.total_number_of_clamped_order_counts +=
+            daily_data.clamped_order_counts;
+    }
+
+    fn add_transaction(&mut self, tx: &Transaction) {
+        self.total_transaction_volume += tx.amount;
+        self.min_amount = self.min_amount.min(tx.amount);
+        self.max_amount = self.max_amount.max(tx.amount);
+        self.amounts.add(tx.amount);
+    }
+
+    /** Records the number of transactions of a whole hour. */
+    fn end_hour(&mut self, transactions: usize) {
+        let last_bin = self.transactions_per_hour_histogram.len() - 1;
+        self.peak_parallel_transactions_number =
+            self.peak_parallel_transactions_number.max(transactions);
+        self.peak_concurrent_transactions =
+            self.peak_concurrent_transactions.max(peak_overlap(
+                transactions,
+                self.transaction_duration_minutes,
+            ));
+        self.total_number_of_transactions += transactions;
+        let bin = (transactions / self.histogram_bin_width).min(last_bin);
+        self.transactions_per_hour_histogram[bin] += 1;
+    }
+}
+
+/**
+ * Returns the largest number of overlapping processing windows
+ * of `transactions` spread evenly over an hour, each lasting
+ * `duration_minutes`. A window ends right as the next may start,
+ * so they don't overlap at that instant. The windows of other
+ * hours are not counted.
+ */
+fn peak_overlap(transactions: usize, duration_minutes: f64) -> usize {
+    // In units of the gap between the starts, the transaction `i`
+    // runs from `i` to `i + duration`. The starts and the ends
+    // are both sorted, so a sweep line merges them.
+    let duration = duration_minutes * transactions as f64 / 60.0;
+    let mut ended = 0;
+    let mut peak = 0;
+    for started in 0..transactions {
+        while ended as f64 + duration <= started as f64 {
+            ended += 1;
+        }
+        peak = peak.max(started + 1 - ended);
+    }
+    peak
+}
+
+/**
+ * Accumulates the results of several simulation runs.
+ * Pools are matched by their position in `SimResults::pool_results`.
+ */
+#[derive(Default)]
+pub struct EnsembleStats {
+    runs: Vec<SimResults>,
+}
+
+impl EnsembleStats {
+    /** Fails if the run reports a different number of pools. */
+    pub fn update(&mut self, results: SimResults) -> Result<()> {
+        if let Some(first) = self.runs.first() {
+            ensure!(
+                first.pool_results.len() == results.pool_results.len(),
+                "all runs must report the same pools, got {} and {}",
+                first.pool_results.len(),
+                results.pool_results.len()
+            );
+        }
+        self.runs.push(results);
+        Ok(())
+    }
+
+    pub fn results(&self) -> EnsembleResults {
+        let pool_results = match self.runs.first() {
+            Some(first) => (0..first.pool_results.len())
+                .map(|i| PoolEnsembleResults {
+                    pool_name: first.pool_results[i].pool_name.clone(),
+                    total_number_of_transactions_during_withdrawals: self
+                        .summarize(|run| {
+                            run.pool_results[i]
+                                .total_number_of_transactions_during_withdrawals
+                                as f64
+                        }),
+                    withdrawal_events: self.summarize(|run| {
+                        run.pool_results[i].withdrawal_events as f64
+                    }),
+                    total_number_of_accounts: self.summarize(|run| {
+                        run.pool_results[i].total_number_of_accounts as f64
+                    }),
+                    peak_parallel_accounts_touched: self.summarize(|run| {
+                        run.pool_results[i].peak_parallel_accounts_touched
+                            as f64
+                    }),
+                    total_money_withdrawn: self.summarize(|run| {
+                        run.pool_results[i].total_money_withdrawn
+                    }),
+                    total_number_of_queued_transactions: self.summarize(
+                        |run| {
+                            run.pool_results[i]
+                                .total_number_of_queued_transactions
+                                as f64
+                        },
+                    ),
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        EnsembleResults {
+            runs: self.runs.len(),
+            total_number_of_transactions: self
+                .summarize(|run| run.total_number_of_transactions as f64),
+            peak_parallel_transactions_number: self
+                .summarize(|run| run.peak_parallel_transactions_number as f64),
+            pool_results,
+        }
+    }
+
+    fn summarize(&self, metric: impl Fn(&SimResults) -> f64) -> MetricSummary {
+        let n = self.runs.len();
+        if n == 0 {
+            return MetricSummary::default();
+        }
+
+        let mut samples = Welford::default();
+        for run in &self.runs {
+            samples.add(metric(run));
+        }
+
+        MetricSummary {
+            mean: samples.mean(),
+            std_dev: samples.sample_variance().sqrt(),
+        }
+    }
+}
+
+/**
+ * Compares the results of the run `after` with those of `before`,
+ * metric by metric. Pools are matched by name and listed
+ * in the order of `before`.
+ */
+pub fn diff(before: &SimResults, after: &SimResults) -> ResultsDiff {
+    fn metric_diffs<const N: usize>(
+        before: [(&'static str, f64); N],
+        after: [(&'static str, f64); N],
+    ) -> Vec<MetricDiff> {
+        iter::zip(before, after)
+            .map(|((metric, before), (_, after))| {
+                MetricDiff::new(metric, before, after)
+            })
+            .collect()
+    }
+
+    let find = |results: &SimResults, pool_name: &str| {
+        results
+            .pool_results
+            .iter()
+            .find(|pool_results| pool_results.pool_name == pool_name)
+            .map(pool_metrics)
+    };
+    let mut pool_results = vec![];
+    let mut unmatched_pools = vec![];
+    for pool_before in &before.pool_results {
+        match find(after, &pool_before.pool_name) {
+            Some(pool_after) => pool_results.push(PoolDiff {
+                pool_name: pool_before.pool_name.clone(),
+                metrics: metric_diffs(pool_metrics(pool_before), pool_after),
+            }),
+            None => unmatched_pools.push(pool_before.pool_name.clone()),
+        }
+    }
+    for pool_after in &after.pool_results {
+        if find(before, &pool_after.pool_name).is_none() {
+            unmatched_pools.push(pool_after.pool_name.clone());
+        }
+    }
+
+    ResultsDiff {
+        metrics: metric_diffs(global_metrics(before), global_metrics(after)),
+        pool_results,
+        unmatched_pools,
+    }
+}
+
+/** Global figures of a run compared by `diff`. */
+fn global_metrics(results: &SimResults) -> [(&'static str, f64); 10] {
+    [
+        (
+            "total_number_of_transactions",
+            results.total_number_of_transactions as f64,
+        ),
+        (
+            "peak_parallel_transactions_number",
+            results.peak_parallel_transactions_number as f64,
+        ),
+        (
+            "peak_concurrent_transactions",
+            results.peak_concurrent_transactions as f64,
+        ),
+        (
+            "total_number_of_clamped_order_counts",
+            results.total_number_of_clamped_order_counts as f64,
+        ),
+        ("total_transaction_volume", results.total_transaction_volume),
+        ("min_amount", results.min_amount),
+        ("max_amount", results.max_amount),
+        ("mean_amount", results.mean_amount),
+        ("amount_variance", results.amount_variance),
+        ("simulated_days", results.simulated_days as f64),
+    ]
+}
+
+/** Figures of a pool compared by `diff`. */
+fn pool_metrics(results: &PoolResults) -> [(&'static str, f64); 18] {
+    [
+        (
+            "total_number_of_transactions_during_withdrawals",
+            results.total_number_of_transactions_during_withdrawals as f64,
+        ),
+        ("withdrawal_events", results.withdrawal_events as f64),
+        ("average_gini", results.average_gini),
+        (
+            "total_number_of_accounts",
+            results.total_number_of_accounts as f64,
+        ),
+        ("peak_accounts", results.peak_accounts as f64),
+        (
+            "failed_withdrawal_transactions",
+            results.failed_withdrawal_transactions as f64,
+        ),
+        (
+            "peak_parallel_accounts_touched",
+            results.peak_parallel_accounts_touched as f64,
+        ),
+        ("total_money_withdrawn", results.total_money_withdrawn),
+        ("total_fees", results.total_fees),
+        (
+            "total_number_of_queued_transactions",
+            results.total_number_of_queued_transactions as f64,
+        ),
+        (
+            "extra_accounts_due_to_settlement",
+            results.extra_accounts_due_to_settlement as f64,
+        ),
+        (
+            "accounts_created_due_to_cap",
+            results.accounts_created_due_to_cap as f64,
+        ),
+        (
+            "average_active_shops_per_period",
+            results.average_active_shops_per_period,
+        ),
+        (
+            "peak_active_shops_per_period",
+            results.peak_active_shops_per_period as f64,
+        ),
+        ("accounts_created", results.accounts_created as f64),
+        ("accounts_freed", results.accounts_freed as f64),
+        ("average_dust_left", results.average_dust_left),
+        ("average_money_left", results.average_money_left),
+    ]
+}
+
+/**
+ * Receives progress updates from a running simulation.
+ * The work is counted in days: each generated day
+ * and each day simulated by each pool is one unit.
+ *
+ * Updates may arrive from several threads at once.
+ * `()` ignores all of them.
+ */
+pub trait Progress: Sync {
+    /** Announces `days` more units of work. */
+    fn add_total(&self, _days: u64) {}
+
+    /** Reports that `days` more units of work are done. */
+    fn advance(&self, _days: u64) {}
+}
+
+impl Progress for () {}
+
+/**
+ * Observes a generated transaction together with
+ * the index of its day and its hour.
+ */
+pub type OnTransaction<'a> = &'a mut dyn FnMut(&Transaction, usize, usize);
+
+/**
+ * Optional hooks into a run of `run_simulation_with_options`.
+ * None of them changes the outcome of the simulation.
+ */
+pub struct RunOptions<'a> {
+    /** Receives a CSV row with the figures of each day. */
+    pub trace: Option<&'a mut dyn Write>,
+    /**
+     * Receives a CSV row with the day and the balances of all
+     * accounts right before each withdrawal, as the simulation
+     * goes rather than at the end. Exactly one pool must be
+     * selected to dump its accounts.
+     */
+    pub accounts_dump: Option<&'a mut (dyn Write + Send)>,
+    /**
+     * Receives the number of draws from all the random
+     * number generators of the run.
+     */
+    pub rng_counts: Option<&'a mut RngCounts>,
+    /**
+     * Called with every generated transaction, the day and
+     * the hour, including the warm-up days.
+     */
+    pub on_transaction: Option<OnTransaction<'a>>,
+    /** Receives the progress of the run, ignored by default. */
+    pub progress: &'a dyn Progress,
+}
+
+impl Default for RunOptions<'_> {
+    fn default() -> Self {
+        Self {
+            trace: None,
+            accounts_dump: None,
+            rng_counts: None,
+            on_transaction: None,
+            progress: &(),
+        }
+    }
+}
+
+/**
+ * Runs the whole simulation for the given config and seed
+ * over the selected pools. Results follow the order of `pools`.
+ */
+pub fn run_simulation(
+    config: &SimConfig,
+    seed: u64,
+    pools: &[PoolKind],
+) -> Result<SimResults> {
+    run_simulation_with_options(config, seed, pools, RunOptions::default())
+}
+
+/**
+ * Same as `run_simulation`, with the hooks of `options`.
+ */
+pub fn run_simulation_with_options(
+    config: &SimConfig,
+    seed: u64,
+    pools: &[PoolKind],
+    options: RunOptions,
+) -> Result<SimResults> {
+    run_simulation_with(config, seed, pools, &mut vec![], options)
+}
+
+/**
+ * Same as `run_simulation` without a trace, an accounts dump or
+ * the other hooks, but keeps the pools in `reusable` between runs.
+ * Each run resets and reuses the pools left there by the previous
+ * one instead of creating new ones, so that repeated runs don't
+ * allocate them anew. `reusable` starts empty and must only be
+ * shared between runs with the same config and pool kinds.
+ */
+pub fn run_simulation_reusing(
+    config: &SimConfig,
+    seed: u64,
+    pools: &[PoolKind],
+    reusable: &mut Vec<Box<dyn AccountsPool + Send>>,
+    progress: &dyn Progress,
+) -> Result<SimResults> {
+    run_simulation_with(
+        config,
+        seed,
+        pools,
+        reusable,
+        RunOptions {
+            progress,
+            ..RunOptions::default()
+        },
+    )
+}
+
+/**
+ * Runs the simulation like `run_simulation`, taking the pools
+ * from `reusable` when it holds one for each kind and leaving
+ * the pools of the run there when it is done.
+ */
+fn run_simulation_with(
+    config: &SimConfig,
+    seed: u64,
+    pools: &[PoolKind],
+    reusable: &mut Vec<Box<dyn AccountsPool + Send>>,
+    options: RunOptions,
+) -> Result<SimResults> {
+    let RunOptions {
+        trace,
+        mut accounts_dump,
+        rng_counts,
+        mut on_transaction,
+        progress,
+    } = options;
+    validate_config(config)?;
+    ensure!(
+        accounts_dump.is_none() || pools.len() == 1,
+        "accounts can be dumped for a single pool only"
+    );
+    ensure!(
+        accounts_dump.is_none() || !config.independent_years,
+        "accounts can't be dumped with independent years"
+    );
+
+    let total_days = config.total_days();
+    let total_years = total_days.div_ceil(DAYS_IN_YEAR);
+    progress.add_total(total_days as u64 * (1 + pools.len() as u64));
+
+    let mut world_rng = CountingRng::new(SmallRng::seed_from_u64(
+        config.effective_world_seed(seed),
+    ));
+    let mut rng = CountingRng::new(SmallRng::seed_from_u64(
+        config.effective_transaction_seed(seed),
+    ));
+
+    let global_data = GlobalData::try_gen(&mut world_rng, config)?;
+    let mut price_rng = CountingRng::new(SmallRng::from_rng(&mut rng)?);
+    let mut prices = config.price_distribution.sample_iter(&mut price_rng);
+    let mut global_stats = GlobalStats::new(config);
+
+    let pool_ids: Vec<_> = pools.iter().map(|kind| kind.id()).collect();
+    if let Some(out) = accounts_dump.as_deref_mut() {
+        writeln!(out, "day,account_balances")?;
+    }
+    let accounts_dump = Mutex::new(accounts_dump);
+
+    // All pools must see identical input, so each year is generated
+    // once and then shared between the pool threads. Pools carrying
+    // their state over go through it before the next one is
+    // generated, so that the run can stop once they converge.
+    if reusable.len() != pools.len() {
+        reusable.clear();
+    }
+    let mut reused = reusable.drain(..);
+    let mut dependent_runs: Vec<_> = if config.independent_years {
+        vec![]
+    } else {
+        pools
+            .iter()
+            .map(|kind| -> Result<_> {
+                let mut pool = match reused.next() {
+                    Some(mut pool) => {
+                        pool.reset();
+                        pool
+                    }
+                    None => kind.create(config)?,
+                };
+                pool.set_rng(kind.rng(seed));
+                Ok(DependentRun {
+                    pool,
+                    pool_stats: PoolStats::new(config),
+                    pool_trace: vec![],
+                    days_since_withdrawal: 0,
+                    convergence: config.convergence_epsilon.map(|epsilon| {
+                        Convergence::new(epsilon, config.convergence_window)
+                    }),
+                })
+            })
+            .collect::<Result<_>>()?
+    };
+    let mut simulated_years = total_years;
+    // Independent years are simulated after all of them are generated,
+    // so they keep every day. Otherwise the pools go through a year
+    // before the next one is generated, and its days are refilled
+    // in place instead of being allocated anew.
+    let keep_days = config.independent_years;
+    let mut days: Vec<DailyData> = Vec::with_capacity(if keep_days {
+        total_days
+    } else {
+        total_days.min(DAYS_IN_YEAR)
+    });
+    // Transactions and whether there was a withdrawal on each day,
+    // all the trace needs of the days.
+    let mut day_totals = Vec::with_capacity(total_days);
+    for year in 0..total_years {
+        info!("Generating year {} of {}", year + 1, total_years);
+        let annual_data = AnnualData::gen(&mut world_rng, config, &global_data);
+        let first_day = year * DAYS_IN_YEAR;
+        // With `max_days` the last year may be cut short.
+        let year_end = total_days.min((year + 1) * DAYS_IN_YEAR);
+        let year_start = if keep_days { first_day } else { 0 };
+        days.truncate(year_start + year_end - first_day);
+        for absolute_day in first_day..year_end {
+            let index = year_start + absolute_day - first_day;
+            if let Some(daily_data) = days.get_mut(index) {
+                daily_data.regen_into(
+                    &mut rng,
+                    &mut prices,
+                    config,
+                    &annual_data,
+                    absolute_day,
+                );
+            } else {
+                days.push(DailyData::gen(
+                    &mut rng,
+                    &mut prices,
+                    config,
+                    &annual_data,
+                    absolute_day,
+                ));
+            }
+            let daily_data = &days[index];
+            day_totals.push((
+                daily_data.transactions.iter().map(Vec::len).sum(),
+                daily_data.withdrawal,
+            ));
+            if year >= config.warmup_years {
+                global_stats.update(daily_data);
+            }
+            if let Some(on_transaction) = on_transaction.as_deref_mut() {
+                for (hour, txs) in daily_data.transactions.iter().enumerate() {
+                    for tx in txs {
+                        on_transaction(tx, absolute_day, hour);
+                    }
+                }
+            }
+            progress.advance(1);
+        }
+
+        dependent_runs
+            .par_iter_mut()
+            .try_for_each(|run| -> Result<_> {
+                // Only one pool may dump its accounts, so the lock
+                // is taken without contention.
+                let mut accounts_dump =
+                    accounts_dump.lock().expect("no dump writer panics");
+                let year_trace = simulate_days(
+                    config,
+                    run.pool.as_mut(),
+                    &days[year_start..],
+                    first_day,
+                    &mut run.pool_stats,
+                    &mut run.days_since_withdrawal,
+                    run.convergence.as_mut(),
+                    accounts_dump
+                        .as_mut()
+                        .map(|out| &mut **out as &mut (dyn Write + Send)),
+                    progress,
+                )?;
+                run.pool_trace.extend(year_trace);
+                Ok(())
+            })?;
+        let converged = config.convergence_epsilon.is_some()
+            && dependent_runs.iter().all(|run| {
+                run.convergence.as_ref().is_some_and(Convergence::converged)
+            });
+        if converged && year >= config.warmup_years && year + 1 < total_years {
+            simulated_years = year + 1;
+            info!(
+                "Converged after {} of {} years",
+                simulated_years, total_years
+            );
+            break;
+        }
+    }
+    if let Some(rng_counts) = rng_counts {
+        *rng_counts = world_rng.counts();
+        *rng_counts += rng.counts();
+        *rng_counts += price_rng.counts();
+    }
+
+    let pool_runs: Vec<_> = if config.independent_years {
+        pools
+            .par_iter()
+            .map(|kind| -> Result<_> {
+                let years = days
+                    .par_chunks(DAYS_IN_YEAR)
+                    .enumerate()
+                    .map(|(year, year_days)| -> Result<_> {
+                        let mut pool = kind.create(config)?;
+                        // Years of a pool don't repeat the same draws.
+                        pool.set_rng(kind.rng(seed.wrapping_add(year as u64)));
+                        let mut pool_stats = PoolStats::new(config);
+                        let pool_trace = simulate_days(
+                            config,
+                            pool.as_mut(),
+                            year_days,
+                            year * DAYS_IN_YEAR,
+                            &mut pool_stats,
+                            &mut 0,
+                            None,
+                            None,
+                            progress,
+                        )?;
+                        Ok((pool, pool_stats, pool_trace))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                // The pool of the last year is the one reported.
+                let mut last_pool = None;
+                let mut pool_stats = PoolStats::new(config);
+                let mut pool_trace = Vec::with_capacity(days.len());
+                for (year_pool, year_stats, year_trace) in years {
+                    last_pool = Some(year_pool);
+                    pool_stats.merge(year_stats);
+                    pool_trace.extend(year_trace);
+                }
+                let pool = last_pool.expect("a run has at least one year");
+                Ok((pool, pool_stats, pool_trace))
+            })
+            .collect::<Result<_>>()?
+    } else {
+        dependent_runs
+            .into_iter()
+            .map(|run| (run.pool, run.pool_stats, run.pool_trace))
+            .collect()
+    };
+
+    if let Some(trace) = trace {
+        let pool_traces: Vec<_> = pool_runs
+            .iter()
+            .map(|(_, _, trace)| trace.as_slice())
+            .collect();
+        write_trace(trace, &pool_ids, &day_totals, &pool_traces)?;
+    }
+
+    drop(reused);
+    let pool_results = pool_runs
+        .into_iter()
+        .map(|(pool, pool_stats, _)| {
+            let results = pool_stats.results(pool.as_ref());
+            reusable.push(pool);
+            results
+        })
+        .collect();
+    Ok(SimResults {
+        simulated_years,
+        simulated_days: day_totals.len(),
+        ..global_stats.results(config, seed, pool_results)
+    })
+}
+
+/** State of a pool carried over between the years of a run. */
+struct DependentRun {
+    pool: Box<dyn AccountsPool + Send>,
+    pool_stats: PoolStats,
+    pool_trace: Vec<usize>,
+    days_since_withdrawal: usize,
+    convergence: Option<Convergence>,
+}
+
+/**
+ * Tells whether the number of transactions per withdrawal
+ * has settled: it must change by less than `epsilon` relative
+ * to the previous withdrawal for `window` withdrawals in a row.
+ */
+struct Convergence {
+    epsilon: f64,
+    window: usize,
+    last: Option<f64>,
+    stable: usize,
+}
+
+impl Convergence {
+    fn new(epsilon: f64, window: usize) -> Self {
+        Self {
+            epsilon,
+            window,
+            last: None,
+            stable: 0,
+        }
+    }
+
+    fn add(&mut self, transactions_per_withdrawal: f64) {
+        if let Some(last) = self.last {
+            let change = if last == transactions_per_withdrawal {
+                0.0
+            } else {
+                (transactions_per_withdrawal - last).abs() / last
+            };
+            self.stable = if change < self.epsilon {
+                self.stable + 1
+            } else {
+                0
+            };
+        }
+        self.last = Some(transactions_per_withdrawal);
+    }
+
+    fn converged(&self) -> bool {
+        self.stable >= self.window
+    }
+}
+
+/** Prices drawn by a run, in the order of the transactions. */
+type Prices = DistIter<crate::data::Distribution, SmallRng, f64>;
+
+/**
+ * Same as `run_simulation` without a trace, an accounts dump or
+ * the other hooks, but generates the transactions hour by hour as
+ * the pools consume them instead of keeping all days in memory.
+ * The global figures and each pool regenerate the input from
+ * the seed on their own, trading time for memory.
+ * Independent years are not supported.
+ */
+pub fn run_simulation_streaming(
+    config: &SimConfig,
+    seed: u64,
+    pools: &[PoolKind],
+    progress: &dyn Progress,
+) -> Result<SimResults> {
+    validate_config(config)?;
+    ensure!(
+        !config.independent_years,
+        "independent years can't be streamed"
+    );
+    ensure!(
+        config.convergence_epsilon.is_none(),
+        "convergence can't be checked while streaming"
+    );
+
+    let total_days = config.total_days() as u64;
+    progress.add_total(total_days * (1 + pools.len() as u64));
+    let warmup_days = config.warmup_years * DAYS_IN_YEAR;
+
+    let mut global_stats = GlobalStats::new(config);
+    replay_days(config, seed, |day, _, stream| {
+        progress.advance(1);
+        if day < warmup_days {
+            return Ok(());
+        }
+        let mut transactions_per_hour = [0; HOURS_IN_DAY];
+        for (_, hour, tx) in &mut *stream {
+            global_stats.add_transaction(&tx);
+            transactions_per_hour[hour] += 1;
+        }
+        for transactions in transactions_per_hour {
+            global_stats.end_hour(transactions);
+        }
+        global_stats.total_number_of_clamped_order_counts +=
+            stream.clamped_order_counts();
+        Ok(())
+    })?;
+
+    let pool_results = pools
+        .par_iter()
+        .map(|kind| -> Result<_> {
+            let mut pool = kind.create(config)?;
+            pool.set_rng(kind.rng(seed));
+            let mut pool_stats = PoolStats::new(config);
+            let mut warmup_stats = PoolStats::new(config);
+            let mut days_since_withdrawal = 0;
+            replay_days(config, seed, |day, withdrawal_hours, stream| {
+                let day_stats = if day < warmup_days {
+                    &mut warmup_stats
+                } else {
+                    &mut pool_stats
+                };
+                let mut stream = stream.peekable();
+                simulate_hours(
+                    withdrawal_hours,
+                    |hour, pool, active_shops| {
+                        let mut batch = iter::from_fn(|| {
+                            stream
+                                .next_if(|&(_, tx_hour, _)| tx_hour == hour)
+                                .map(|(_, _, tx)| tx)
+                        })
+                        .inspect(|tx| {
+                            active_shops.insert(tx.shop_id);
+                        });
+                        pool.process_transaction_stream(&mut batch)
+                    },
+                    config.effective_withdrawal_policy(),
+                    &config.withdrawal_fee,
+                    pool.as_mut(),
+                    day_stats,
+                    &mut days_since_withdrawal,
+                    None,
+                )
+                .map_err(|err| {
+                    anyhow!("{} on day {}: {}", pool.name(), day, err)
+                })?;
+                progress.advance(1);
+                Ok(())
+            })?;
+            Ok(pool_stats.results(pool.as_ref()))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(global_stats.results(config, seed, pool_results))
+}
+
+/**
+ * Regenerates the input of the run with the given seed day by day,
+ * drawing the same values as `run_simulation`, and hands each day
+ * to `on_day` with its withdrawal hours and its transactions.
+ * Whatever `on_day` leaves of the transactions is drained.
+ */
+fn replay_days(
+    config: &SimConfig,
+    seed: u64,
+    mut on_day: impl FnMut(
+        usize,
+        &[bool; HOURS_IN_DAY],
+        &mut TransactionStream<'_, &mut SmallRng, &mut Prices>,
+    ) -> Result<()>,
+) -> Result<()> {
+    let mut world_rng =
+        SmallRng::seed_from_u64(config.effective_world_seed(seed));
+    let mut rng =
+        SmallRng::seed_from_u64(config.effective_transaction_seed(seed));
+    let global_data = GlobalData::try_gen(&mut world_rng, config)?;
+    let price_rng = SmallRng::from_rng(&mut rng)?;
+    let mut prices = config.price_distribution.sample_iter(price_rng);
+    let total_days = config.total_days();
+    for year in 0..total_days.div_ceil(DAYS_IN_YEAR) {
+        let annual_data = AnnualData::gen(&mut world_rng, config, &global_data);
+        let year_end = total_days.min((year + 1) * DAYS_IN_YEAR);
+        for absolute_day in year * DAYS_IN_YEAR..year_end {
+            let mut stream = TransactionStream::new(
+                config,
+                &annual_data,
+                &mut rng,
+                &mut prices,
+                absolute_day..absolute_day + 1,
+            );
+            on_day(
+                absolute_day,
+                &withdrawal_hours(config, absolute_day),
+                &mut stream,
+            )?;
+            stream.for_each(drop);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Runs `pool` through consecutive `days`, the first of which
+ * has the index `first_day` in the whole simulation, and returns
+ * the number of withdrawal transactions of each day.
+ * Figures of the days past the warm-up go to `pool_stats`,
+ * and the transactions per withdrawal to `convergence`.
+ */
+#[allow(clippy::too_many_arguments)]
+fn simulate_days(
+    config: &SimConfig,
+    pool: &mut dyn AccountsPool,
+    days: &[DailyData],
+    first_day: usize,
+    pool_stats: &mut PoolStats,
+    days_since_withdrawal: &mut usize,
+    mut convergence: Option<&mut Convergence>,
+    mut accounts_dump: Option<&mut (dyn Write + Send)>,
+    progress: &dyn Progress,
+) -> Result<Vec<usize>> {
+    let warmup_days = config.warmup_years * DAYS_IN_YEAR;
+    // Warm-up days still change the pool state,
+    // but their figures are thrown away.
+    let mut warmup_stats = PoolStats::default();
+    let dumping = accounts_dump.is_some();
+    let mut pool_trace = Vec::with_capacity(days.len());
+    for (day, daily_data) in (first_day..).zip(days) {
+        let day_stats = if day < warmup_days {
+            &mut warmup_stats
+        } else {
+            &mut *pool_stats
+        };
+        let mut dump_result = Ok(());
+        let mut dump_accounts = |snapshot: &PoolSnapshot| {
+            if let (Some(out), Ok(())) =
+                (accounts_dump.as_deref_mut(), &dump_result)
+            {
+                dump_result = write_accounts_row(out, day, snapshot);
+            }
+        };
+        let withdrawal_events = day_stats.withdrawal_events;
+        let withdrawal_transactions = simulate_day(
+            daily_data,
+            config.effective_withdrawal_policy(),
+            &config.withdrawal_fee,
+            pool,
+            day_stats,
+            days_since_withdrawal,
+            dumping.then_some(&mut dump_accounts),
+        )
+        .map_err(|err| anyhow!("{} on day {}: {}", pool.name(), day, err))?;
+        dump_result?;
+        let withdrawal_events = day_stats.withdrawal_events - withdrawal_events;
+        if let (Some(convergence), true) =
+            (convergence.as_deref_mut(), withdrawal_events > 0)
+        {
+            convergence
+                .add(withdrawal_transactions as f64 / withdrawal_events as f64);
+        }
+        pool_trace.push(withdrawal_transactions);
+        progress.advance(1);
+    }
+    Ok(pool_trace)
+}
+
+/**
+ * Writes the day index followed by the account balances
+ * in ascending order.
+ */
+fn write_accounts_row(
+    out: &mut dyn Write,
+    day: usize,
+    snapshot: &PoolSnapshot,
+) -> Result<()> {
+    let mut balances = snapshot.account_balances.clone();
+    balances.sort_by(f64::total_cmp);
+    write!(out, "{}", day)?;
+    for balance in balances {
+        write!(out, ",{}", balance)?;
+    }
+    writeln!(out)?;
+
+    Ok(())
+}
+
+/**
+ * Writes one CSV row per day: the day index, the year,
+ * the number of transactions, whether a withdrawal occurred
+ * and the number of withdrawal transactions of each pool.
+ */
+fn write_trace(
+    out: &mut dyn Write,
+    pool_ids: &[&str],
+    day_totals: &[(usize, bool)],
+    pool_traces: &[&[usize]],
+) -> Result<()> {
+    write!(out, "day,year,transactions,withdrawal")?;
+    for pool_id in pool_ids {
+        write!(out, ",{}", pool_id)?;
+    }
+    writeln!(out)?;
+
+    for (day, &(transactions, withdrawal)) in day_totals.iter().enumerate() {
+        write!(
+            out,
+            "{},{},{},{}",
+            day,
+            day / DAYS_IN_YEAR,
+            transactions,
+            withdrawal
+        )?;
+        for pool_trace in pool_traces {
+            write!(out, ",{}", pool_trace[day])?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Estimates the transactions of a run with the given seed without
+ * generating them or simulating any pool. The shop sizes are those
+ * of the run, while the sales are replaced by their expected effect.
+ *
+ * Clamping and, without noise, rounding are applied to the expected
+ * numbers of orders rather than to the sampled ones, so the estimate
+ * is rougher for shops with about one order per hour.
+ */
+pub fn estimate_transactions(
+    config: &SimConfig,
+    seed: u64,
+) -> Result<TransactionEstimate> {
+    validate_config(config)?;
+    let mut world_rng =
+        SmallRng::seed_from_u64(config.effective_world_seed(seed));
+    let global_data = GlobalData::try_gen(&mut world_rng, config)?;
+    let annual_data = AnnualData::expected(config, &global_data);
+    let config_without_sales = SimConfig {
+        sale_multiplier: 1.0,
+        ..config.clone()
+    };
+    let annual_data_without_sales =
+        AnnualData::expected(&config_without_sales, &global_data);
+
+    let total_days = config.total_days();
+    let warmup_days = config.warmup_years * DAYS_IN_YEAR;
+    let mut generated_transactions = KahanSum::default();
+    let mut expected_transactions = KahanSum::default();
+    let mut expected_sale_transactions = KahanSum::default();
+    for absolute_day in 0..total_days {
+        let transactions =
+            expected_daily_transactions(config, &annual_data, absolute_day);
+        generated_transactions += transactions;
+        if absolute_day >= warmup_days {
+            expected_transactions += transactions;
+            expected_sale_transactions += transactions
+                - expected_daily_transactions(
+                    config,
+                    &annual_data_without_sales,
+                    absolute_day,
+                );
+        }
+    }
+
+    let counted_days = total_days.saturating_sub(warmup_days);
+    Ok(TransactionEstimate {
+        seed,
+        expected_transactions: expected_transactions.value(),
+        expected_sale_transactions: expected_sale_transactions.value(),
+        expected_transactions_per_day: if counted_days == 0 {
+            0.0
+        } else {
+            expected_transactions.value() / counted_days as f64
+        },
+        expected_memory_bytes: (total_days * size_of::<DailyData>()) as f64
+            + generated_transactions.value() * size_of::<Transaction>() as f64,
+    })
+}
+
+/**
+ * Expected number of transactions of the day,
+ * mirroring the order counts of `DailyData::regen_into`.
+ */
+fn expected_daily_transactions(
+    config: &SimConfig,
+    annual_data: &AnnualData,
+    absolute_day: usize,
+) -> f64 {
+    let day = absolute_day % DAYS_IN_YEAR;
+    let (weekday_multiplier, holiday_multiplier) =
+        calendar_multipliers(config, absolute_day);
+    let max_txs_number = config.max_transactions_per_hour as f64;
+    let mut transactions = KahanSum::default();
+    for distr in &annual_data.shop_distributions {
+        for hour in 0..HOURS_IN_DAY {
+            let expected_txs_number = (distr.daily_multipliers[day]
+                * distr.default_daily_distribution[hour]
+                * weekday_multiplier
+                * holiday_multiplier)
+                .min(max_txs_number);
+            transactions += match config.order_count_noise {
+                OrderCountNoise::None => expected_txs_number.round(),
+                OrderCountNoise::Poisson => expected_txs_number,
+            };
+        }
+    }
+    transactions.value()
+}
+
+/**
+ * Runs the simulation `runs` times with seeds derived from `base_seed`
+ * and aggregates the results. Progress of every run
+ * is reported to `progress`.
+ */
+pub fn run_ensemble(
+    config: &SimConfig,
+    base_seed: u64,
+    runs: usize,
+    pools: &[PoolKind],
+    progress: &dyn Progress,
+) -> Result<EnsembleResults> {
+    let mut ensemble_stats = EnsembleStats::default();
+    let mut reusable = vec![];
+    for i in 0..runs {
+        let seed = base_seed.wrapping_add(i as u64);
+        info!("Starting run {} of {} with seed {}", i + 1, runs, seed);
+        ensemble_stats.update(run_simulation_reusing(
+            config,
+            seed,
+            pools,
+            &mut reusable,
+            progress,
+        )?)?;
+    }
+    Ok(ensemble_stats.results())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{
+        DailyOrdersExpr, Distribution, HolidaySpec, ShopOverride,
+        ShopSizeScaling,
+    };
+    use crate::pool::SinglePool;
+    use std::collections::BTreeMap;
+
+    fn test_config(withdrawal_period_in_days: usize) -> SimConfig {
+        SimConfig::builder()
+            .simulated_years_number(2)
+            .shop_size_distribution(Distribution::Normal {
+                mean: 1.0,
+                std: 0.0,
+            })
+            .default_daily_distribution(DailyOrdersExpr::new("0").unwrap())
+            .price_distribution(Distribution::Normal {
+                mean: 1.0,
+                std: 0.0,
+            })
+            .withdrawal_policy(WithdrawalPolicy::Periodic {
+                days: withdrawal_period_in_days,
+            })
+            .capped_pool_max_accounts(1)
+            .max_transactions_per_hour(1000)
+            .build()
+    }
+
+    fn test_prices(config: &SimConfig) -> impl Iterator<Item = f64> {
+        rand_distr::Distribution::sample_iter(
+            config.price_distribution,
+            SmallRng::seed_from_u64(1),
+        )
+    }
+
+    #[test]
+    fn test_withdrawal_cadence_spans_years() {
+        let config = test_config(30);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let mut prices = test_prices(&config);
+
+        let mut withdrawal_days = vec![];
+        for year in 0..config.simulated_years_number {
+            let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+            for day in 0..DAYS_IN_YEAR {
+                let absolute_day = year * DAYS_IN_YEAR + day;
+                let daily_data = DailyData::gen(
+                    &mut rng,
+                    &mut prices,
+                    &config,
+                    &annual_data,
+                    absolute_day,
+                );
+                if daily_data.withdrawal {
+                    withdrawal_days.push(absolute_day);
+                }
+            }
+        }
+
+        let expected: Vec<usize> =
+            (1..=2 * DAYS_IN_YEAR / 30).map(|k| k * 30 - 1).collect();
+        assert_eq!(withdrawal_days, expected);
+        assert!(!withdrawal_days.contains(&0));
+        assert!(!withdrawal_days.contains(&DAYS_IN_YEAR));
+    }
+
+    #[test]
+    fn test_first_withdrawal_lands_on_offset() {
+        let withdrawal_hours = |config: &SimConfig| {
+            let mut rng = SmallRng::seed_from_u64(0);
+            let global_data = GlobalData::try_gen(&mut rng, config).unwrap();
+            let annual_data = AnnualData::gen(&mut rng, config, &global_data);
+            let mut prices = test_prices(config);
+            let mut withdrawal_hours = vec![];
+            for absolute_day in 0..2 * DAYS_IN_YEAR {
+                let daily_data = DailyData::gen(
+                    &mut rng,
+                    &mut prices,
+                    config,
+                    &annual_data,
+                    absolute_day,
+                );
+                for (hour, &withdrawal) in
+                    daily_data.withdrawal_hours.iter().enumerate()
+                {
+                    if withdrawal {
+                        withdrawal_hours.push((absolute_day, hour));
+                    }
+                }
+            }
+            withdrawal_hours
+        };
+
+        let mut config = test_config(30);
+        config.withdrawal_offset_days = Some(10);
+        let hours = withdrawal_hours(&config);
+        assert_eq!(hours[0], (10, HOURS_IN_DAY - 1));
+        assert_eq!(hours[1], (40, HOURS_IN_DAY - 1));
+        assert_eq!(hours.len(), (2 * DAYS_IN_YEAR - 10).div_ceil(30));
+
+        config.withdrawal_policy = None;
+        config.withdrawal_period_in_hours = Some(6);
+        let hours = withdrawal_hours(&config);
+        assert_eq!(&hours[..2], [(10, 0), (10, 6)]);
+    }
+
+    fn sim_results(
+        total_number_of_transactions: usize,
+        withdrawal_transactions: usize,
+    ) -> SimResults {
+        SimResults {
+            seed: 0,
+            total_number_of_transactions,
+            peak_parallel_transactions_number: 1,
+            peak_concurrent_transactions: 1,
+            total_number_of_clamped_order_counts: 0,
+            transactions_per_hour_bin_width: 1,
+            transactions_per_hour_histogram: vec![],
+            total_transaction_volume: 0.0,
+            min_amount: 0.0,
+            max_amount: 0.0,
+            mean_amount: 0.0,
+            amount_variance: 0.0,
+            simulated_years: 1,
+            simulated_days: DAYS_IN_YEAR,
+            pool_results: vec![PoolResults {
+                pool_name: "Test Pool".to_string(),
+                total_number_of_transactions_during_withdrawals:
+                    withdrawal_transactions,
+                withdrawal_events: 1,
+                last_balance_percentiles: [0.0; 5],
+                average_gini: 0.0,
+                total_number_of_accounts: 3,
+                failed_withdrawal_transactions: 0,
+                peak_accounts: 3,
+                accounts_per_currency: BTreeMap::from([(0, 3)]),
+                peak_parallel_accounts_touched: 2,
+                total_money_withdrawn: 0.0,
+                total_fees: 0.0,
+                total_number_of_queued_transactions: 0,
+                extra_accounts_due_to_settlement: 0,
+                accounts_created_due_to_cap: 0,
+                average_active_shops_per_period: 0.0,
+                peak_active_shops_per_period: 0,
+                accounts_created: 3,
+                accounts_freed: 0,
+                average_dust_left: 0.0,
+                average_money_left: 0.0,
+                timings: None,
+            }],
+            effective_config: SimConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_diff_of_hand_constructed_results() {
+        let before = sim_results(10, 4);
+        let mut after = sim_results(15, 0);
+        after.pool_results[0].total_number_of_accounts = 0;
+        let mut other_pool = sim_results(0, 0).pool_results.remove(0);
+        other_pool.pool_name = "Other Pool".to_string();
+        after.pool_results.push(other_pool);
+
+        let results_diff = diff(&before, &after);
+        let transactions = &results_diff.metrics[0];
+        assert_eq!(transactions.metric, "total_number_of_transactions");
+        assert_eq!(
+            (transactions.before, transactions.after, transactions.delta),
+            (10.0, 15.0, 5.0)
+        );
+        assert_eq!(transactions.percent_change, Some(50.0));
+        let clamped = results_diff
+            .metrics
+            .iter()
+            .find(|metric| {
+                metric.metric == "total_number_of_clamped_order_counts"
+            })
+            .unwrap();
+        assert_eq!(clamped.delta, 0.0);
+        assert_eq!(clamped.percent_change, None);
+
+        assert_eq!(results_diff.pool_results.len(), 1);
+        let pool = &results_diff.pool_results[0];
+        assert_eq!(pool.pool_name, "Test Pool");
+        let metric = |name| {
+            pool.metrics
+                .iter()
+                .find(|metric| metric.metric == name)
+                .unwrap()
+        };
+        let withdrawal_transactions =
+            metric("total_number_of_transactions_during_withdrawals");
+        assert_eq!(withdrawal_transactions.delta, -4.0);
+        assert_eq!(withdrawal_transactions.percent_change, Some(-100.0));
+        assert_eq!(metric("total_number_of_accounts").delta, -3.0);
+        assert_eq!(metric("withdrawal_events").percent_change, Some(0.0));
+        assert_eq!(results_diff.unmatched_pools, ["Other Pool"]);
+    }
+
+    #[test]
+    fn test_results_read_back_from_json() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.profile = true;
+        let results = run_simulation(
+            &config,
+            0,
+            &[PoolKind::PoolPerShop, PoolKind::CappedPool],
+        )
+        .unwrap();
+        let json = serde_json::to_value(&results).unwrap();
+        let read_back: SimResults =
+            serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&read_back).unwrap(), json);
+
+        let results_diff = diff(&results, &read_back);
+        assert_eq!(results_diff.pool_results.len(), 2);
+        assert!(results_diff.unmatched_pools.is_empty());
+        for metric in results_diff.metrics.iter().chain(
+            results_diff
+                .pool_results
+                .iter()
+                .flat_map(|pool_diff| &pool_diff.metrics),
+        ) {
+            assert_eq!(metric.delta, 0.0, "{}", metric.metric);
+        }
+    }
+
+    #[test]
+    fn test_ensemble_stats_mean_and_std_dev() {
+        let mut ensemble_stats = EnsembleStats::default();
+        ensemble_stats.update(sim_results(10, 2)).unwrap();
+        ensemble_stats.update(sim_results(20, 4)).unwrap();
+        ensemble_stats.update(sim_results(30, 9)).unwrap();
+
+        let results = ensemble_stats.results();
+        assert_eq!(results.runs, 3);
+        assert_eq!(
+            results.total_number_of_transactions,
+            MetricSummary {
+                mean: 20.0,
+                std_dev: 10.0
+            }
+        );
+        assert_eq!(
+            results.peak_parallel_transactions_number,
+            MetricSummary {
+                mean: 1.0,
+                std_dev: 0.0
+            }
+        );
+
+        let pool = &results.pool_results[0];
+        assert_eq!(pool.pool_name, "Test Pool");
+        assert_eq!(
+            pool.total_number_of_transactions_during_withdrawals.mean,
+            5.0
+        );
+        assert!(
+            (pool.total_number_of_transactions_during_withdrawals.std_dev
+                - 13.0_f64.sqrt())
+            .abs()
+                < 1e-12
+        );
+    }
+
+    #[test]
+    fn test_ensemble_stats_reject_different_pools() {
+        let mut ensemble_stats = EnsembleStats::default();
+        ensemble_stats.update(sim_results(10, 2)).unwrap();
+        let mut results = sim_results(20, 4);
+        results.pool_results.clear();
+        let err = ensemble_stats.update(results).unwrap_err();
+        assert!(err.to_string().contains("same pools"));
+    }
+
+    #[test]
+    fn test_ensemble_stats_single_run() {
+        let mut ensemble_stats = EnsembleStats::default();
+        ensemble_stats.update(sim_results(10, 2)).unwrap();
+
+        let results = ensemble_stats.results();
+        assert_eq!(results.pool_results[0].total_number_of_accounts.mean, 3.0);
+        assert_eq!(
+            results.total_number_of_transactions.std_dev, 0.0,
+            "A single run has no spread."
+        );
+    }
+
+    #[test]
+    fn test_poisson_order_count_mean_converges() {
+        let mut config = test_config(30);
+        config.default_daily_distribution = DailyOrdersExpr::new("5").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let mut prices = test_prices(&config);
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+
+        let days = 100;
+        let mut total_txs = 0;
+        let mut varied = false;
+        for day in 0..days {
+            let daily_data = DailyData::gen(
+                &mut rng,
+                &mut prices,
+                &config,
+                &annual_data,
+                day,
+            );
+            for txs in &daily_data.transactions {
+                total_txs += txs.len();
+                varied |= txs.len() != 5;
+            }
+        }
+
+        let mean = total_txs as f64 / (days * HOURS_IN_DAY) as f64;
+        assert!((mean - 5.0).abs() < 0.2, "sampled mean {} is off", mean);
+        assert!(varied, "Poisson noise should vary the hourly counts.");
+    }
+
+    #[test]
+    fn test_shop_sizes_from_file() {
+        let path = std::env::temp_dir()
+            .join(format!("hex-sim-shop-sizes-{}.txt", std::process::id()));
+        std::fs::write(&path, "2.5\n1\n\n4\n").unwrap();
+
+        let mut config = test_config(30);
+        config.shop_sizes_file = Some(path.clone());
+        for (shops, expected) in [
+            (3, vec![2.5, 1.0, 4.0]),
+            (2, vec![2.5, 1.0]),
+            (5, vec![2.5, 1.0, 4.0, 2.5, 1.0]),
+        ] {
+            config.simulated_shops_number = shops;
+            let rng = SmallRng::seed_from_u64(0);
+            let global_data = GlobalData::try_gen(rng, &config).unwrap();
+            assert_eq!(global_data.shop_sizes, expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        let err = GlobalData::try_gen(SmallRng::seed_from_u64(0), &config)
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("failed to read shop sizes"));
+    }
+
+    #[test]
+    fn test_estimate_matches_short_run() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 20;
+        config.default_daily_distribution = DailyOrdersExpr::new("2").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.sales_per_year_for_each_shop = 10;
+        config.sale_multiplier = 3.0;
+        config.holidays = vec![HolidaySpec {
+            day: 100,
+            multiplier: 4.0,
+        }];
+
+        let estimate = estimate_transactions(&config, 7).unwrap();
+        let results = run_simulation(&config, 7, &[]).unwrap();
+
+        let actual = results.total_number_of_transactions as f64;
+        let relative_error =
+            (estimate.expected_transactions - actual).abs() / actual;
+        assert!(
+            relative_error < 0.02,
+            "{} vs {}",
+            estimate.expected_transactions,
+            actual
+        );
+
+        let without_sales = 20.0 * 2.0 * 24.0 * (DAYS_IN_YEAR + 3) as f64;
+        assert!(
+            (estimate.expected_transactions
+                - estimate.expected_sale_transactions
+                - without_sales)
+                .abs()
+                < 1e-6
+        );
+        assert!(estimate.expected_sale_transactions > 0.0);
+        assert!(
+            estimate.expected_memory_bytes
+                > actual * size_of::<Transaction>() as f64 * 0.9
+        );
+    }
+
+    #[test]
+    fn test_transaction_stream_matches_run() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 5;
+        config.default_daily_distribution = DailyOrdersExpr::new("3").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.sales_per_year_for_each_shop = 5;
+        config.sale_multiplier = 2.0;
+
+        let results = run_simulation(&config, 11, &[]).unwrap();
+
+        let mut world_rng =
+            SmallRng::seed_from_u64(config.effective_world_seed(11));
+        let mut rng =
+            SmallRng::seed_from_u64(config.effective_transaction_seed(11));
+        let global_data = GlobalData::try_gen(&mut world_rng, &config).unwrap();
+        let price_rng = SmallRng::from_rng(&mut rng).unwrap();
+        let prices = rand_distr::Distribution::sample_iter(
+            config.price_distribution,
+            price_rng,
+        );
+        let annual_data =
+            AnnualData::gen(&mut world_rng, &config, &global_data);
+        let stream = TransactionStream::for_year(
+            &config,
+            &annual_data,
+            &mut rng,
+            prices,
+            0,
+        );
+
+        let mut count = 0;
+        let mut last = (0, 0);
+        for (day, hour, _) in stream {
+            assert!(day < DAYS_IN_YEAR && hour < HOURS_IN_DAY);
+            assert!((day, hour) >= last);
+            last = (day, hour);
+            count += 1;
+        }
+        assert!(count > 0);
+        assert_eq!(count, results.total_number_of_transactions);
+    }
+
+    #[test]
+    fn test_run_without_transactions() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 3;
+
+        let results = run_simulation(&config, 0, &PoolKind::ALL).unwrap();
+        assert_eq!(results.total_number_of_transactions, 0);
+        assert_eq!(results.peak_parallel_transactions_number, 0);
+        assert_eq!(results.total_transaction_volume, 0.0);
+        assert_eq!(
+            [
+                results.min_amount,
+                results.max_amount,
+                results.mean_amount,
+                results.amount_variance
+            ],
+            [0.0; 4]
+        );
+        assert_eq!(results.pool_results.len(), PoolKind::ALL.len());
+        for pool_results in &results.pool_results {
+            let name = &pool_results.pool_name;
+            assert_eq!(pool_results.total_number_of_accounts, 0, "{}", name);
+            assert_eq!(pool_results.withdrawal_events, DAYS_IN_YEAR / 30);
+            assert_eq!(
+                pool_results.total_number_of_transactions_during_withdrawals,
+                0
+            );
+            assert_eq!(pool_results.last_balance_percentiles, [0.0; 5]);
+            assert_eq!(pool_results.average_gini, 0.0);
+            assert_eq!(pool_results.average_active_shops_per_period, 0.0);
+            assert_eq!(pool_results.average_dust_left, 0.0);
+            assert_eq!(pool_results.average_money_left, 0.0);
+            assert_eq!(pool_results.total_money_withdrawn, 0.0);
+            assert_eq!(pool_results.total_fees, 0.0);
+        }
+
+        let ensemble =
+            run_ensemble(&config, 0, 2, &PoolKind::ALL, &()).unwrap();
+        assert_eq!(ensemble.total_number_of_transactions.mean, 0.0);
+        assert_eq!(ensemble.total_number_of_transactions.std_dev, 0.0);
+        for pool_results in &ensemble.pool_results {
+            assert_eq!(
+                pool_results.total_number_of_transactions_during_withdrawals,
+                MetricSummary::default()
+            );
+        }
+    }
+
+    #[test]
+    fn test_synchronized_sales_raise_the_peak() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 50;
+        config.default_daily_distribution = DailyOrdersExpr::new("10").unwrap();
+        config.sales_per_year_for_each_shop = 1;
+        config.sale_multiplier = 10.0;
+
+        let peak = |synchronize_sales| {
+            let config = SimConfig {
+                synchronize_sales,
+                ..config.clone()
+            };
+            run_simulation(&config, 5, &[])
+                .unwrap()
+                .peak_parallel_transactions_number
+        };
+
+        let independent = peak(false);
+        let synchronized = peak(true);
+        assert_eq!(synchronized, 50 * 100);
+        assert!(synchronized > 3 * independent, "{}", independent);
+    }
+
+    #[test]
+    fn test_prices_do_not_depend_on_day_batching() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("5").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.price_distribution = Distribution::Normal {
+            mean: 10.0,
+            std: 3.0,
+        };
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+
+        let amounts = |batch_sizes: &[usize]| -> Vec<f64> {
+            let mut prices = test_prices(&config);
+            let mut amounts = vec![];
+            let mut day = 0;
+            for (i, &batch_size) in batch_sizes.iter().enumerate() {
+                let mut rng = SmallRng::seed_from_u64(i as u64);
+                for _ in 0..batch_size {
+                    let daily_data = DailyData::gen(
+                        &mut rng,
+                        &mut prices,
+                        &config,
+                        &annual_data,
+                        day,
+                    );
+                    amounts.extend(
+                        daily_data
+                            .transactions
+                            .iter()
+                            .flatten()
+                            .map(|tx| tx.amount),
+                    );
+                    day += 1;
+                }
+            }
+            amounts
+        };
+
+        let whole = amounts(&[10]);
+        let batched = amounts(&[3, 4, 3]);
+        let len = whole.len().min(batched.len());
+        assert!(len > 0);
+        assert_eq!(whole[..len], batched[..len]);
+        assert_eq!(
+            whole,
+            test_prices(&config).take(whole.len()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_order_counts_are_clamped() {
+        let mut config = test_config(30);
+        config.default_daily_multipliers = [f64::MAX; DAYS_IN_YEAR];
+        config.default_daily_distribution = DailyOrdersExpr::new("3").unwrap();
+        config.max_transactions_per_hour = 10;
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+        let daily_data = DailyData::gen(
+            &mut rng,
+            &mut test_prices(&config),
+            &config,
+            &annual_data,
+            0,
+        );
+
+        assert!(daily_data.transactions.iter().all(|txs| txs.len() == 10));
+        assert_eq!(daily_data.clamped_order_counts, HOURS_IN_DAY);
+    }
+
+    #[test]
+    fn test_daily_orders_expr_may_use_shop_size() {
+        let mut config = test_config(30);
+        config.shop_size_distribution = Distribution::Normal {
+            mean: 3.0,
+            std: 0.0,
+        };
+
+        let hourly_orders = |config: &SimConfig| {
+            let rng = SmallRng::seed_from_u64(0);
+            GlobalData::try_gen(rng, config)
+                .unwrap()
+                .shop_daily_distributions[0]
+        };
+
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("h % 2 + 1").unwrap();
+        assert_eq!(hourly_orders(&config)[..2], [3.0, 6.0]);
+
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("(h % 2 + 1) * shop_size * shop_size")
+                .unwrap();
+        assert_eq!(hourly_orders(&config)[..2], [9.0, 18.0]);
+    }
+
+    #[test]
+    fn test_shop_overrides() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.shop_overrides = BTreeMap::from([
+            (
+                0,
+                ShopOverride {
+                    size: Some(1000.0),
+                    ..Default::default()
+                },
+            ),
+            (
+                2,
+                ShopOverride {
+                    daily_distribution: Some(
+                        DailyOrdersExpr::new("2").unwrap(),
+                    ),
+                    daily_multipliers: Some([3.0; DAYS_IN_YEAR]),
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        assert_eq!(global_data.shop_sizes, [1000.0, 1.0, 1.0]);
+        assert_eq!(global_data.shop_daily_distributions[0][0], 1000.0);
+        assert_eq!(global_data.shop_daily_distributions[1][0], 1.0);
+        assert_eq!(global_data.shop_daily_distributions[2][0], 2.0);
+
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+        let multipliers: Vec<_> = annual_data
+            .shop_distributions
+            .iter()
+            .map(|distribution| distribution.daily_multipliers[0])
+            .collect();
+        assert_eq!(multipliers, [1.0, 1.0, 3.0]);
+    }
+
+    #[test]
+    fn test_fractional_daily_multiplier() {
+        let mut config = test_config(30);
+        config.default_daily_multipliers = [1.5; DAYS_IN_YEAR];
+        config.default_daily_distribution = DailyOrdersExpr::new("2").unwrap();
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+        let daily_data = DailyData::gen(
+            &mut rng,
+            &mut test_prices(&config),
+            &config,
+            &annual_data,
+            0,
+        );
+
+        assert!(daily_data.transactions.iter().all(|txs| txs.len() == 3));
+    }
+
+    #[test]
+    fn test_spaced_sales() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 200;
+        config.default_daily_multipliers = [1.0; DAYS_IN_YEAR];
+        config.sale_multiplier = 2.0;
+        config.sale_timing = SaleTiming::Spaced {
+            mean_gap_days: 10.0,
+        };
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+
+        let mut sales = 0;
+        let mut gaps = Vec::new();
+        for distribution in &annual_data.shop_distributions {
+            // Spaced sales never fall on the same day.
+            assert!(distribution.daily_multipliers.iter().all(|&m| m <= 2.0));
+            let sale_days: Vec<_> = (0..DAYS_IN_YEAR)
+                .filter(|&day| distribution.daily_multipliers[day] == 2.0)
+                .collect();
+            sales += sale_days.len();
+            gaps.extend(sale_days.windows(2).map(|w| w[1] - w[0]));
+        }
+
+        let shops = config.simulated_shops_number as f64;
+        let sales_per_shop = sales as f64 / shops;
+        let expected = DAYS_IN_YEAR as f64 / 10.0;
+        assert!(
+            (sales_per_shop - expected).abs() < 1.0,
+            "{}",
+            sales_per_shop
+        );
+        let mean_gap = gaps.iter().sum::<usize>() as f64 / gaps.len() as f64;
+        assert!((mean_gap - 10.0).abs() < 0.5, "{}", mean_gap);
+    }
+
+    #[test]
+    fn test_regen_into_matches_gen() {
+        let mut config = test_config(7);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("5").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.price_distribution = Distribution::Normal {
+            mean: 10.0,
+            std: 3.0,
+        };
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+
+        let mut reused = DailyData::gen(
+            &mut rng,
+            &mut test_prices(&config),
+            &config,
+            &annual_data,
+            0,
+        );
+        for day in 1..10 {
+            let seed = day as u64;
+            let fresh = DailyData::gen(
+                SmallRng::seed_from_u64(seed),
+                &mut test_prices(&config),
+                &config,
+                &annual_data,
+                day,
+            );
+            reused.regen_into(
+                SmallRng::seed_from_u64(seed),
+                &mut test_prices(&config),
+                &config,
+                &annual_data,
+                day,
+            );
+
+            assert_eq!(reused.transactions, fresh.transactions);
+            assert_eq!(reused.withdrawal, fresh.withdrawal);
+        }
+    }
+
+    #[test]
+    fn test_hourly_withdrawals_happen_within_a_day() {
+        let mut config = test_config(30);
+        config.withdrawal_policy = None;
+        config.withdrawal_period_in_hours = Some(6);
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+        let mut prices = test_prices(&config);
+        let daily_data =
+            DailyData::gen(&mut rng, &mut prices, &config, &annual_data, 0);
+        let withdrawal_hours: Vec<usize> = (0..HOURS_IN_DAY)
+            .filter(|&hour| daily_data.withdrawal_hours[hour])
+            .collect();
+        assert_eq!(withdrawal_hours, [5, 11, 17, 23]);
+
+        let mut pool = SinglePool::new();
+        let mut pool_stats = PoolStats::default();
+        let mut balances = vec![];
+        let withdrawal_transactions = simulate_day(
+            &daily_data,
+            config.effective_withdrawal_policy(),
+            &config.withdrawal_fee,
+            &mut pool,
+            &mut pool_stats,
+            &mut 0,
+            Some(&mut |snapshot: &PoolSnapshot| {
+                balances.push(snapshot.account_balances.iter().sum::<f64>())
+            }),
+        )
+        .unwrap();
+        assert_eq!(withdrawal_transactions, 4);
+        assert_eq!(pool_stats.withdrawal_events, 4);
+        assert_eq!(balances, [6.0; 4]);
+
+        // A period which doesn't divide a day
+        // carries over to the next one.
+        config.withdrawal_period_in_hours = Some(5);
+        let daily_data =
+            DailyData::gen(&mut rng, &mut prices, &config, &annual_data, 1);
+        assert!(daily_data.withdrawal_hours[0]);
+        assert!(daily_data.withdrawal_hours[5]);
+        assert!(!daily_data.withdrawal_hours[23]);
+    }
+
+    #[test]
+    fn test_transactions_per_hour_histogram() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 2;
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("if(h < 12, 1, 3)").unwrap();
+        config.max_transactions_per_hour = 10;
+        config.histogram_bins = 5;
+
+        let results =
+            run_simulation(&config, 0, &[PoolKind::SinglePool]).unwrap();
+
+        // Two shops make 2 transactions an hour in the morning
+        // and 6 in the afternoon, which fall into bins of width 2.
+        let half_of_hours = 2 * DAYS_IN_YEAR * HOURS_IN_DAY / 2;
+        assert_eq!(results.transactions_per_hour_bin_width, 2);
+        assert_eq!(
+            results.transactions_per_hour_histogram,
+            [0, half_of_hours, 0, half_of_hours, 0]
+        );
+    }
+
+    #[test]
+    fn test_histogram_last_bin_takes_busier_hours() {
+        let mut config = test_config(30);
+        config.max_transactions_per_hour = 4;
+        config.histogram_bins = 2;
+        let mut global_stats = GlobalStats::new(&config);
+
+        let mut daily_data = DailyData {
+            transactions: Default::default(),
+            withdrawal: false,
+            withdrawal_hours: [false; HOURS_IN_DAY],
+            clamped_order_counts: 0,
+        };
+        let transaction = Transaction {
+            amount: 1.0,
+            shop_id: 0,
+            currency: 0,
+        };
+        daily_data.transactions[0] = vec![transaction; 100];
+        global_stats.update(&daily_data);
+
+        let results = global_stats.results(&config, 0, vec![]);
+        assert_eq!(results.transactions_per_hour_histogram, [23, 1]);
+    }
+
+    #[test]
+    fn test_transaction_amount_stats() {
+        let config = test_config(30);
+        let mut global_stats = GlobalStats::new(&config);
+
+        let empty = global_stats.results(&config, 0, vec![]);
+        assert_eq!(empty.total_transaction_volume, 0.0);
+        assert_eq!(empty.mean_amount, 0.0);
+
+        let mut daily_data = DailyData {
+            transactions: Default::default(),
+            withdrawal: false,
+            withdrawal_hours: [false; HOURS_IN_DAY],
+            clamped_order_counts: 0,
+        };
+        let transaction = |amount| Transaction {
+            amount,
+            shop_id: 0,
+            currency: 0,
+        };
+        daily_data.transactions[0] = vec![transaction(2.0), transaction(0.5)];
+        daily_data.transactions[5] = vec![transaction(7.5)];
+        global_stats.update(&daily_data);
+
+        let results = global_stats.results(&config, 0, vec![]);
+        assert_eq!(results.total_transaction_volume, 10.0);
+        assert_eq!(results.min_amount, 0.5);
+        assert_eq!(results.max_amount, 7.5);
+        assert!((results.mean_amount - 10.0 / 3.0).abs() < 1e-12);
+        let variance = [2.0, 0.5, 7.5]
+            .iter()
+            .map(|amount: &f64| (amount - 10.0 / 3.0).powi(2))
+            .sum::<f64>()
+            / 2.0;
+        assert!((results.amount_variance - variance).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_on_transaction_sees_every_transaction() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("h % 3").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        let pools = [PoolKind::SinglePool];
+
+        let mut calls = 0;
+        let mut last_day = 0;
+        let mut on_transaction = |_: &Transaction, day: usize, hour: usize| {
+            assert!(day >= last_day && hour < HOURS_IN_DAY);
+            last_day = day;
+            calls += 1;
+        };
+        let mut counts = RngCounts::default();
+        let results = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                rng_counts: Some(&mut counts),
+                on_transaction: Some(&mut on_transaction),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(calls, results.total_number_of_transactions);
+        assert_eq!(last_day, 2 * DAYS_IN_YEAR - 1);
+
+        let mut unobserved_counts = RngCounts::default();
+        let unobserved = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                rng_counts: Some(&mut unobserved_counts),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(counts, unobserved_counts);
+        assert_eq!(
+            serde_json::to_value(&results).unwrap(),
+            serde_json::to_value(&unobserved).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_results_reproduce_the_run() {
+        let mut config = test_config(30);
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("h % 3").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.price_distribution = Distribution::Normal {
+            mean: 10.0,
+            std: 3.0,
+        };
+        let pools = [PoolKind::SinglePool];
+        let results = run_simulation(&config, 42, &pools).unwrap();
+
+        let json = serde_json::to_value(&results).unwrap();
+        let seed = json["seed"].as_u64().unwrap();
+        let effective_config: SimConfig =
+            serde_json::from_value(json["effective_config"].clone()).unwrap();
+        assert_eq!(seed, 42);
+        assert_eq!(effective_config, config);
+
+        let rerun = run_simulation(&effective_config, seed, &pools).unwrap();
+        assert_eq!(serde_json::to_value(&rerun).unwrap(), json);
+    }
+
+    #[test]
+    fn test_accounts_dump_has_a_row_per_withdrawal() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("if(h == 0, 2, 0)").unwrap();
+
+        let mut dump = Vec::new();
+        run_simulation_with_options(
+            &config,
+            0,
+            &[PoolKind::PoolPerShop],
+            RunOptions {
+                accounts_dump: Some(&mut dump),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        let lines: Vec<_> = dump.lines().collect();
+
+        assert_eq!(lines[0], "day,account_balances");
+        assert_eq!(lines.len(), 1 + DAYS_IN_YEAR / 30);
+        // Each of the 30 days brings 2 transactions of 1.0,
+        // which land on the same two accounts of the shop.
+        assert_eq!(lines[1], "29,30,30");
+        assert_eq!(lines[2], "59,30,30");
+
+        let pools = [PoolKind::PoolPerShop, PoolKind::SinglePool];
+        let mut dump = Vec::new();
+        assert!(run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                accounts_dump: Some(&mut dump),
+                ..RunOptions::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_pool_results_follow_selection_order() {
+        let config = test_config(30);
+        let pools = [
+            PoolKind::CappedPool,
+            PoolKind::PoolPerShop,
+            PoolKind::SinglePool,
+        ];
+
+        let results = run_simulation(&config, 0, &pools).unwrap();
+        let names: Vec<_> = results
+            .pool_results
+            .iter()
+            .map(|pool_results| pool_results.pool_name.as_str())
+            .collect();
+        assert_eq!(names, ["Capped Pool", "Pool per Shop", "Single Pool"]);
+    }
+
+    #[test]
+    fn test_global_data_fails_on_negative_shop_sizes() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 10;
+        config.shop_size_distribution = Distribution::Normal {
+            mean: -100.0,
+            std: 1.0,
+        };
+
+        let rng = SmallRng::seed_from_u64(0);
+        assert!(GlobalData::try_gen(rng, &config).is_err());
+    }
+
+    #[test]
+    fn test_runs_reject_invalid_configs() {
+        let mut config = test_config(30);
+        config.price_distribution = Distribution::Normal {
+            mean: -100.0,
+            std: 1.0,
+        };
+
+        let pools = [PoolKind::SinglePool];
+        assert!(run_simulation(&config, 0, &pools).is_err());
+        assert!(run_simulation_streaming(&config, 0, &pools, &()).is_err());
+        assert!(estimate_transactions(&config, 0).is_err());
+    }
+
+    #[test]
+    fn test_trace_has_a_row_per_day() {
+        let config = test_config(30);
+        let pools = [PoolKind::SinglePool, PoolKind::PoolPerShop];
+
+        let mut trace = Vec::new();
+        run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                trace: Some(&mut trace),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        let trace = String::from_utf8(trace).unwrap();
+        let lines: Vec<_> = trace.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "day,year,transactions,withdrawal,single-pool,pool-per-shop"
+        );
+        assert_eq!(lines.len(), 1 + 2 * DAYS_IN_YEAR);
+        assert_eq!(lines[30], "29,0,0,true,0,0");
+        assert_eq!(lines[DAYS_IN_YEAR + 1], "365,1,0,false,0,0");
+    }
+
+    #[test]
+    fn test_warmup_excludes_only_warmup_days() {
+        let mut config = test_config(30);
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        let pools = PoolKind::ALL;
+
+        let mut trace = Vec::new();
+        let full = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                trace: Some(&mut trace),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        config.warmup_years = 1;
+        let warm = run_simulation(&config, 0, &pools).unwrap();
+
+        let trace = String::from_utf8(trace).unwrap();
+        let counted_rows: Vec<Vec<usize>> = trace
+            .lines()
+            .skip(1 + DAYS_IN_YEAR)
+            .map(|line| {
+                line.split(',')
+                    .enumerate()
+                    .filter(|&(i, _)| i != 3)
+                    .map(|(_, value)| value.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+
+        let counted_transactions: usize =
+            counted_rows.iter().map(|row| row[2]).sum();
+        assert_eq!(warm.total_number_of_transactions, counted_transactions);
+        assert_eq!(
+            full.total_number_of_transactions
+                - warm.total_number_of_transactions,
+            DAYS_IN_YEAR * HOURS_IN_DAY
+        );
+
+        for (i, pool_results) in warm.pool_results.iter().enumerate() {
+            let counted_withdrawals: usize =
+                counted_rows.iter().map(|row| row[3 + i]).sum();
+            assert_eq!(
+                pool_results.total_number_of_transactions_during_withdrawals,
+                counted_withdrawals
+            );
+            assert_eq!(
+                pool_results.total_number_of_accounts,
+                full.pool_results[i].total_number_of_accounts
+            );
+            assert_eq!(pool_results.withdrawal_events, 12);
+            assert_eq!(full.pool_results[i].withdrawal_events, 24);
+        }
+    }
+
+    #[test]
+    fn test_weekday_multipliers() {
+        let mut config = test_config(30);
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.weekday_multipliers = [1.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0];
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let mut prices = test_prices(&config);
+        let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+
+        let daily_txs: Vec<usize> = (0..2 * DAYS_IN_WEEK)
+            .map(|day| {
+                DailyData::gen(
+                    &mut rng,
+                    &mut prices,
+                    &config,
+                    &annual_data,
+                    day,
+                )
+                .transactions
+                .iter()
+                .map(|txs| txs.len())
+                .sum()
+            })
+            .collect();
+
+        for (day, &txs) in daily_txs.iter().enumerate() {
+            let expected = match day % DAYS_IN_WEEK {
+                5 | 6 => 2 * HOURS_IN_DAY,
+                _ => HOURS_IN_DAY,
+            };
+            assert_eq!(txs, expected, "day {}", day);
+        }
+    }
+
+    #[test]
+    fn test_holiday_multiplies_demand() {
+        let mut config = test_config(30);
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+
+        let day_txs = |config: &SimConfig, day: usize| -> usize {
+            let mut rng = SmallRng::seed_from_u64(0);
+            let global_data = GlobalData::try_gen(&mut rng, config).unwrap();
+            let annual_data = AnnualData::gen(&mut rng, config, &global_data);
+            DailyData::gen(
+                &mut rng,
+                &mut test_prices(config),
+                config,
+                &annual_data,
+                day,
+            )
+            .transactions
+            .iter()
+            .map(|txs| txs.len())
+            .sum()
+        };
+
+        let baseline = day_txs(&config, 100);
+        config.holidays = vec![HolidaySpec {
+            day: 100,
+            multiplier: 5.0,
+        }];
+
+        assert_eq!(day_txs(&config, 100), 5 * baseline);
+        assert_eq!(day_txs(&config, DAYS_IN_YEAR + 100), 5 * baseline);
+        assert_eq!(day_txs(&config, 101), baseline);
+    }
+
+    #[test]
+    fn test_fees_follow_withdrawal_transactions() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.withdrawal_fee = WithdrawalFee::Flat { per_tx: 0.5 };
+
+        let pools =
+            [PoolKind::PoolPerShop, PoolKind::SinglePoolWithSingleAccount];
+        let results = run_simulation(&config, 0, &pools).unwrap();
+
+        for pool_results in &results.pool_results {
+            assert!(pool_results.total_fees > 0.0);
+            assert_eq!(
+                pool_results.total_fees,
+                pool_results.total_number_of_transactions_during_withdrawals
+                    as f64
+                    * 0.5
+            );
+        }
+    }
+
+    #[test]
+    fn test_threshold_withdrawal_is_pool_specific() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 2;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.withdrawal_policy =
+            Some(WithdrawalPolicy::Threshold { amount: 100.0 });
+
+        let pools = [PoolKind::PoolPerShop, PoolKind::CappedPool];
+        let mut trace = Vec::new();
+        let results = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                trace: Some(&mut trace),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Each day brings 48 units of money, so the threshold
+        // is crossed at the end of every third day.
+        for pool_results in &results.pool_results {
+            assert_eq!(pool_results.withdrawal_events, 2 * DAYS_IN_YEAR / 3);
+        }
+        let trace = String::from_utf8(trace).unwrap();
+        assert!(trace.lines().nth(3).unwrap().starts_with("2,0,48,false,"));
+    }
+
+    #[test]
+    fn test_active_shops_per_period() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 5;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        let idle = ShopOverride {
+            daily_multipliers: Some([0.0; DAYS_IN_YEAR]),
+            ..Default::default()
+        };
+        config.shop_overrides = BTreeMap::from([(1, idle.clone()), (3, idle)]);
+
+        let pools = [PoolKind::PoolPerShop, PoolKind::SinglePool];
+        let results = run_simulation(&config, 0, &pools).unwrap();
+
+        for pool_results in &results.pool_results {
+            assert!(pool_results.withdrawal_events > 0);
+            assert_eq!(pool_results.average_active_shops_per_period, 3.0);
+            assert_eq!(pool_results.peak_active_shops_per_period, 3);
+            assert_eq!(
+                pool_results.accounts_created,
+                pool_results.total_number_of_accounts
+                    + pool_results.accounts_freed
+            );
+        }
+    }
+
+    #[test]
+    fn test_peak_accounts_outlasts_a_smaller_year() {
+        let batch = |size| -> Vec<Transaction> {
+            (0..size)
+                .map(|shop_id| Transaction {
+                    amount: 1.0,
+                    shop_id,
+                    currency: 0,
+                })
+                .collect()
+        };
+        let config = test_config(1);
+        let mut pool_stats = PoolStats::default();
+        let mut pool = PoolKind::SinglePool.create(&config).unwrap();
+        // Each year starts with a fresh pool, here a smaller one.
+        for size in [5, 2] {
+            pool = PoolKind::SinglePool.create(&config).unwrap();
+            pool.process_transactions(&batch(size)).unwrap();
+            pool.withdraw_all();
+            let year_stats = PoolStats {
+                peak_accounts: pool.peak_accounts(),
+                ..PoolStats::default()
+            };
+            pool_stats.merge(year_stats);
+        }
+
+        let results = pool_stats.results(pool.as_ref());
+        assert_eq!(results.total_number_of_accounts, 2);
+        assert_eq!(results.peak_accounts, 5);
+    }
+
+    #[test]
+    fn test_merged_year_stats_match_sequential_years() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 3;
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("h % 3").unwrap();
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut prices = test_prices(&config);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        let mut days = Vec::new();
+        for year in 0..config.simulated_years_number {
+            let annual_data = AnnualData::gen(&mut rng, &config, &global_data);
+            for day in 0..DAYS_IN_YEAR {
+                days.push(DailyData::gen(
+                    &mut rng,
+                    &mut prices,
+                    &config,
+                    &annual_data,
+                    year * DAYS_IN_YEAR + day,
+                ));
+            }
+        }
+
+        for kind in [PoolKind::PoolPerShop, PoolKind::SinglePool] {
+            let mut sequential = PoolStats::default();
+            let mut merged = PoolStats::default();
+            let mut pool = kind.create(&config).unwrap();
+            for (year, year_days) in days.chunks(DAYS_IN_YEAR).enumerate() {
+                let first_day = year * DAYS_IN_YEAR;
+                // Each year starts afresh, including the open period.
+                pool = kind.create(&config).unwrap();
+                sequential.active_shops.clear();
+                simulate_days(
+                    &config,
+                    pool.as_mut(),
+                    year_days,
+                    first_day,
+                    &mut sequential,
+                    &mut 0,
+                    None,
+                    None,
+                    &(),
+                )
+                .unwrap();
+
+                let mut year_pool = kind.create(&config).unwrap();
+                let mut year_stats = PoolStats::default();
+                simulate_days(
+                    &config,
+                    year_pool.as_mut(),
+                    year_days,
+                    first_day,
+                    &mut year_stats,
+                    &mut 0,
+                    None,
+                    None,
+                    &(),
+                )
+                .unwrap();
+                merged.merge(year_stats);
+            }
+
+            assert!(sequential.withdrawal_events > 0);
+            let mut merged = merged.results(pool.as_ref());
+            let mut sequential = sequential.results(pool.as_ref());
+            // Gini coefficients are summed in a different order.
+            assert!(
+                (merged.average_gini - sequential.average_gini).abs() < 1e-12
+            );
+            merged.average_gini = 0.0;
+            sequential.average_gini = 0.0;
+            assert_eq!(
+                serde_json::to_value(merged).unwrap(),
+                serde_json::to_value(sequential).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_independent_years_run() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.independent_years = true;
+
+        let pools = [PoolKind::PoolPerShop];
+        let mut trace = Vec::new();
+        let results = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                trace: Some(&mut trace),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Withdrawals take place every 30 days of the whole run,
+        // so the first year withdraws its days up to 360 and the
+        // second one its days from 365 to 720. The rest is dropped.
+        let pool_results = &results.pool_results[0];
+        assert_eq!(pool_results.withdrawal_events, 24);
+        assert_eq!(
+            pool_results.total_money_withdrawn,
+            ((360 + 355) * 3 * HOURS_IN_DAY) as f64
+        );
+        let trace = String::from_utf8(trace).unwrap();
+        assert_eq!(trace.lines().count(), 1 + 2 * DAYS_IN_YEAR);
+    }
+
+    #[test]
+    fn test_combined_withdrawal_fires_on_either_trigger() {
+        let mut config = test_config(30);
+        config.simulated_shops_number = 2;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+
+        // Each day brings 48 units of money, so a threshold of 100
+        // is crossed at the end of every third day.
+        for (days, expected) in
+            [(2, 2 * DAYS_IN_YEAR / 2), (10, 2 * DAYS_IN_YEAR / 3)]
+        {
+            config.withdrawal_policy = Some(WithdrawalPolicy::Combined {
+                days,
+                amount: 100.0,
+            });
+            let results =
+                run_simulation(&config, 0, &[PoolKind::PoolPerShop]).unwrap();
+            assert_eq!(results.pool_results[0].withdrawal_events, expected);
+        }
+    }
+
+    #[test]
+    fn test_progress_counts_every_day() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        #[derive(Default)]
+        struct Counter {
+            total: AtomicU64,
+            done: AtomicU64,
+        }
+
+        impl Progress for Counter {
+            fn add_total(&self, days: u64) {
+                self.total.fetch_add(days, Ordering::Relaxed);
+            }
+
+            fn advance(&self, days: u64) {
+                self.done.fetch_add(days, Ordering::Relaxed);
+            }
+        }
+
+        let config = test_config(1);
+        let pools = [PoolKind::SinglePool, PoolKind::PoolPerShop];
+        let counter = Counter::default();
+        run_ensemble(&config, 0, 2, &pools, &counter).unwrap();
+
+        let expected = 2 * 2 * DAYS_IN_YEAR as u64 * 3;
+        assert_eq!(counter.total.into_inner(), expected);
+        assert_eq!(counter.done.into_inner(), expected);
+    }
+
+    #[test]
+    fn test_streaming_matches_materialized_run() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 2;
+        config.warmup_years = 1;
+        config.simulated_shops_number = 5;
+        config.default_daily_distribution = DailyOrdersExpr::new("3").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.price_distribution = Distribution::Normal {
+            mean: 1.0,
+            std: 0.0,
+        };
+        let pools = [PoolKind::PoolPerShop, PoolKind::SinglePoolGreedy];
+
+        let materialized = run_simulation(&config, 5, &pools).unwrap();
+        let streamed =
+            run_simulation_streaming(&config, 5, &pools, &()).unwrap();
+        assert!(materialized.total_number_of_transactions > 0);
+        assert_eq!(
+            serde_json::to_value(&streamed).unwrap(),
+            serde_json::to_value(&materialized).unwrap()
+        );
+
+        config.independent_years = true;
+        assert!(run_simulation_streaming(&config, 5, &pools, &()).is_err());
+    }
+
+    #[test]
+    fn test_convergence_stops_at_steady_state() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 10;
+        config.warmup_years = 1;
+        config.simulated_shops_number = 4;
+        config.default_daily_distribution = DailyOrdersExpr::new("2").unwrap();
+        config.convergence_epsilon = Some(0.01);
+        config.convergence_window = 5;
+        let pools = [PoolKind::PoolPerShop, PoolKind::SinglePoolGreedy];
+
+        let converged = run_simulation(&config, 0, &pools).unwrap();
+        // Every week is the same, but the warm-up year is not counted.
+        assert_eq!(converged.simulated_years, 2);
+
+        let mut short_config = config.clone();
+        short_config.simulated_years_number = 2;
+        short_config.convergence_epsilon = None;
+        let short = run_simulation(&short_config, 0, &pools).unwrap();
+        assert_eq!(short.simulated_years, 2);
+        assert_eq!(
+            serde_json::to_value(&converged.pool_results).unwrap(),
+            serde_json::to_value(&short.pool_results).unwrap()
+        );
+        assert_eq!(
+            converged.total_number_of_transactions,
+            short.total_number_of_transactions
+        );
+
+        // With sparse orders the number of shops paid varies weekly.
+        config.simulated_years_number = 3;
+        config.simulated_shops_number = 20;
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("0.01 * shop_size").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        let results = run_simulation(&config, 0, &pools).unwrap();
+        assert_eq!(results.simulated_years, 3);
+    }
+
+    #[test]
+    fn test_refund_rate_makes_transactions_negative() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 5;
+        config.default_daily_distribution = DailyOrdersExpr::new("2").unwrap();
+        config.refund_rate = 0.25;
+
+        let mut refunds = 0;
+        let mut on_transaction = |tx: &Transaction, _: usize, _: usize| {
+            if tx.amount < 0.0 {
+                refunds += 1;
+            }
+        };
+        let results = run_simulation_with_options(
+            &config,
+            0,
+            &PoolKind::ALL,
+            RunOptions {
+                on_transaction: Some(&mut on_transaction),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+
+        let total = results.total_number_of_transactions;
+        let fraction = refunds as f64 / total as f64;
+        assert!((0.2..0.3).contains(&fraction), "{}", fraction);
+        // Every price is 1, so refunds take back what sales brought.
+        assert_eq!(
+            results.total_transaction_volume,
+            (total - 2 * refunds) as f64
+        );
+        assert_eq!([results.min_amount, results.max_amount], [-1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_reused_pools_match_fresh_runs() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 3;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        // Without noise all shops have equal balances, so the order
+        // they are paid in, which follows hashing, doesn't matter.
+
+        let mut reusable = vec![];
+        for seed in 0..2 {
+            let reused = run_simulation_reusing(
+                &config,
+                seed,
+                &PoolKind::ALL,
+                &mut reusable,
+                &(),
+            )
+            .unwrap();
+            assert_eq!(reusable.len(), PoolKind::ALL.len());
+            let fresh = run_simulation(&config, seed, &PoolKind::ALL).unwrap();
+            assert_eq!(
+                serde_json::to_value(&reused).unwrap(),
+                serde_json::to_value(&fresh).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_power_scaling_grows_orders_sub_linearly() {
+        let mut config = test_config(30);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 2;
+        config.default_daily_distribution = DailyOrdersExpr::new("10").unwrap();
+        config.order_count_noise = OrderCountNoise::Poisson;
+        config.shop_overrides = BTreeMap::from([(
+            1,
+            ShopOverride {
+                size: Some(2.0),
+                ..ShopOverride::default()
+            },
+        )]);
+        config.shop_size_scaling = ShopSizeScaling::Power { exponent: 0.5 };
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let global_data = GlobalData::try_gen(&mut rng, &config).unwrap();
+        assert_eq!(global_data.shop_sizes, [1.0, 2.0]);
+        assert_eq!(global_data.shop_daily_distributions[0][0], 10.0);
+        assert_eq!(
+            global_data.shop_daily_distributions[1][0],
+            10.0 * 2f64.sqrt()
+        );
+
+        let mut transactions = [0usize; 2];
+        let mut on_transaction = |tx: &Transaction, _: usize, _: usize| {
+            transactions[tx.shop_id] += 1;
+        };
+        run_simulation_with_options(
+            &config,
+            0,
+            &[],
+            RunOptions {
+                on_transaction: Some(&mut on_transaction),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        let ratio = transactions[1] as f64 / transactions[0] as f64;
+        assert!((ratio - 2f64.sqrt()).abs() < 0.02, "{}", ratio);
+
+        for (scaling, size, scaled) in [
+            (ShopSizeScaling::Linear, 2.0, 2.0),
+            (ShopSizeScaling::Log, 1.0, 1.0),
+            (ShopSizeScaling::Log, 3.0, 2.0),
+            (ShopSizeScaling::Power { exponent: 0.0 }, 5.0, 1.0),
+        ] {
+            assert_eq!(scaling.apply(size), scaled, "{:?}", scaling);
+        }
+    }
+
+    #[test]
+    fn test_profiling_times_pool_methods() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 10;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        let run = |config: &SimConfig| {
+            run_simulation(config, 0, &PoolKind::ALL).unwrap()
+        };
+
+        for pool_results in run(&config).pool_results {
+            assert_eq!(pool_results.timings, None);
+        }
+
+        config.profile = true;
+        for pool_results in run(&config).pool_results {
+            let timings = pool_results.timings.unwrap();
+            let process = timings.process_transactions;
+            assert_eq!(process.calls, DAYS_IN_YEAR * HOURS_IN_DAY);
+            assert!(process.total_seconds > 0.0, "{}", pool_results.pool_name);
+            assert!(process.average_seconds > 0.0);
+            let withdraw = timings.withdraw_all;
+            assert_eq!(withdraw.calls, pool_results.withdrawal_events);
+            assert!(withdraw.total_seconds > 0.0, "{}", pool_results.pool_name);
         }
     }
+
+    #[test]
+    fn test_world_seed_keeps_shop_sizes() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 5;
+        config.shop_size_distribution = Distribution::Normal {
+            mean: 1.0,
+            std: 0.5,
+        };
+        config.price_distribution = Distribution::Normal {
+            mean: 10.0,
+            std: 3.0,
+        };
+        // Without noise the orders of a shop follow from its size.
+        config.default_daily_distribution =
+            DailyOrdersExpr::new("10 * shop_size").unwrap();
+        let run = |config: &SimConfig, seed| {
+            let mut orders = vec![0usize; config.simulated_shops_number];
+            let mut on_transaction = |tx: &Transaction, _: usize, _: usize| {
+                orders[tx.shop_id] += 1;
+            };
+            let results = run_simulation_with_options(
+                config,
+                seed,
+                &[],
+                RunOptions {
+                    on_transaction: Some(&mut on_transaction),
+                    ..RunOptions::default()
+                },
+            )
+            .unwrap();
+            (orders, results.total_transaction_volume)
+        };
+
+        config.world_seed = Some(3);
+        let (orders, volume) = run(&config, 0);
+        let (other_orders, other_volume) = run(&config, 1);
+        assert_eq!(orders, other_orders);
+        assert_ne!(volume, other_volume);
+        assert_eq!(
+            estimate_transactions(&config, 0)
+                .unwrap()
+                .expected_transactions,
+            estimate_transactions(&config, 1)
+                .unwrap()
+                .expected_transactions
+        );
+
+        config.world_seed = None;
+        config.transaction_seed = Some(3);
+        let (orders, _) = run(&config, 0);
+        let (other_orders, _) = run(&config, 1);
+        assert_ne!(orders, other_orders);
+    }
+
+    #[test]
+    fn test_max_days_cuts_the_run_short() {
+        let mut config = test_config(7);
+        config.simulated_shops_number = 2;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        config.max_days = Some(DAYS_IN_YEAR + 10);
+        let pools = [PoolKind::PoolPerShop];
+
+        let mut days = HashSet::new();
+        let mut on_transaction = |_: &Transaction, day: usize, _: usize| {
+            days.insert(day);
+        };
+        let mut trace = Vec::new();
+        let results = run_simulation_with_options(
+            &config,
+            0,
+            &pools,
+            RunOptions {
+                trace: Some(&mut trace),
+                on_transaction: Some(&mut on_transaction),
+                ..RunOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(days.len(), DAYS_IN_YEAR + 10);
+        assert_eq!(days.iter().max(), Some(&(DAYS_IN_YEAR + 9)));
+        assert_eq!(results.simulated_days, DAYS_IN_YEAR + 10);
+        assert_eq!(results.simulated_years, 2);
+        assert_eq!(
+            results.total_number_of_transactions,
+            (DAYS_IN_YEAR + 10) * HOURS_IN_DAY * 2
+        );
+        // A header and a row for each day.
+        let trace = String::from_utf8(trace).unwrap();
+        assert_eq!(trace.lines().count(), DAYS_IN_YEAR + 11);
+
+        let streamed =
+            run_simulation_streaming(&config, 0, &pools, &()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&streamed).unwrap(),
+            serde_json::to_value(&results).unwrap()
+        );
+
+        config.max_days = Some(10 * DAYS_IN_YEAR);
+        let results = run_simulation(&config, 0, &pools).unwrap();
+        assert_eq!(results.simulated_days, 2 * DAYS_IN_YEAR);
+    }
+
+    #[test]
+    fn test_peak_overlap() {
+        // Starts every 15 minutes, so a 20-minute window overlaps
+        // the next one only, while a 15-minute one overlaps none.
+        assert_eq!(peak_overlap(4, 20.0), 2);
+        assert_eq!(peak_overlap(4, 15.0), 1);
+        assert_eq!(peak_overlap(4, 31.0), 3);
+        assert_eq!(peak_overlap(4, 60.0), 4);
+        assert_eq!(peak_overlap(4, 600.0), 4);
+        assert_eq!(peak_overlap(1, 1.0), 1);
+        assert_eq!(peak_overlap(0, 5.0), 0);
+        // Starts every 6 seconds, and 5 minutes span 50 of them.
+        assert_eq!(peak_overlap(600, 5.0), 50);
+        assert_eq!(peak_overlap(600, 5.01), 51);
+    }
+
+    #[test]
+    fn test_peak_concurrent_transactions() {
+        let mut config = test_config(7);
+        config.simulated_years_number = 1;
+        config.simulated_shops_number = 12;
+        config.default_daily_distribution = DailyOrdersExpr::new("1").unwrap();
+        let run = |config: &SimConfig| run_simulation(config, 0, &[]).unwrap();
+
+        let results = run(&config);
+        assert_eq!(results.peak_parallel_transactions_number, 12);
+        assert_eq!(results.peak_concurrent_transactions, 12);
+
+        // A transaction every 5 minutes, each taking 12 minutes.
+        config.transaction_duration_minutes = 12.0;
+        let results = run(&config);
+        assert_eq!(results.peak_parallel_transactions_number, 12);
+        assert_eq!(results.peak_concurrent_transactions, 3);
+    }
 }
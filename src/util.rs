@@ -1,26 +1,52 @@
 use evalexpr::*;
-use serde::{de, Deserialize, Deserializer};
+use rand::RngCore;
+use schemars::Schema;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     cmp::Ordering,
+    error::Error,
+    fmt,
     hash::{Hash, Hasher},
-    ops::Deref,
+    ops::{AddAssign, Deref},
 };
 
 use crate::data::{
-    DailyMultipliers, DailyOrdersDistribution, DAYS_IN_YEAR, HOURS_IN_DAY,
+    DailyMultipliers, DailyOrdersExpr, Distribution, WithdrawalFee,
+    WithdrawalPolicy, DAYS_IN_YEAR,
 };
 
 #[repr(transparent)]
-#[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct F64AsKey(f64);
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NanError;
+
+impl fmt::Display for NanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Can't implement Ord and Eq when NaNs are present.")
+    }
+}
+
+impl Error for NanError {}
+
 impl F64AsKey {
+    /**
+     * Stores -0.0 as 0.0, so that equal keys
+     * always have the same bits and hash alike.
+     */
+    pub fn try_new(value: f64) -> Result<Self, NanError> {
+        if value.is_nan() {
+            return Err(NanError);
+        }
+        Ok(Self(value + 0.0))
+    }
+
+    /**
+     * Panics if the value is NaN.
+     */
     pub fn new(value: f64) -> Self {
-        assert!(
-            !value.is_nan(),
-            "Can't implement Ord and Eq when NaNs are present."
-        );
-        Self(value)
+        Self::try_new(value).unwrap_or_else(|err| panic!("{}", err))
     }
 
     pub fn inner(&self) -> f64 {
@@ -54,59 +80,930 @@ impl Hash for F64AsKey {
 }
 
 impl Eq for F64AsKey {}
+impl PartialOrd for F64AsKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/**
+ * Orders the values with `f64::total_cmp`. `try_new` keeps out NaNs
+ * and turns -0.0 into 0.0, so this is the numeric order.
+ */
 impl Ord for F64AsKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.0.partial_cmp(&other.0).unwrap()
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/**
+ * Running sum of floats which keeps track of the rounding error
+ * of each addition and compensates for it, so that summing
+ * many small amounts doesn't drift away from the exact total.
+ * Uses Neumaier's variant, which also handles terms
+ * larger than the running sum.
+ */
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KahanSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl KahanSum {
+    pub fn value(&self) -> f64 {
+        self.sum + self.compensation
+    }
+}
+
+impl AddAssign<f64> for KahanSum {
+    fn add_assign(&mut self, value: f64) {
+        let sum = self.sum + value;
+        self.compensation += if self.sum.abs() >= value.abs() {
+            (self.sum - sum) + value
+        } else {
+            (value - sum) + self.sum
+        };
+        self.sum = sum;
+    }
+}
+
+impl PartialEq for KahanSum {
+    fn eq(&self, other: &Self) -> bool {
+        self.value() == other.value()
+    }
+}
+
+impl PartialEq<f64> for KahanSum {
+    fn eq(&self, other: &f64) -> bool {
+        self.value() == *other
+    }
+}
+
+/**
+ * Running mean and variance of a stream of values computed
+ * with Welford's algorithm, which, unlike summing the squares,
+ * stays accurate when the variance is small next to the mean.
+ */
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Welford {
+    count: usize,
+    mean: f64,
+    /** Sum of the squared differences from the current mean. */
+    m2: f64,
+}
+
+impl Welford {
+    pub fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /** Returns the mean, zero when there are no values. */
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /** Returns the sample variance, zero when there are less than two values. */
+    pub fn sample_variance(&self) -> f64 {
+        if self.count > 1 {
+            self.m2 / (self.count - 1) as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/** Numbers of calls to each method of an `RngCore`. */
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RngCounts {
+    pub next_u32: u64,
+    pub next_u64: u64,
+    /** Also counts `try_fill_bytes`. */
+    pub fill_bytes: u64,
+}
+
+impl AddAssign for RngCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.next_u32 += other.next_u32;
+        self.next_u64 += other.next_u64;
+        self.fill_bytes += other.fill_bytes;
+    }
+}
+
+/**
+ * Passes all calls through to the wrapped generator
+ * and counts them, so that changes in the number of draws,
+ * which break reproducibility for a given seed, can be spotted.
+ */
+pub struct CountingRng<R> {
+    rng: R,
+    counts: RngCounts,
+}
+
+impl<R: RngCore> CountingRng<R> {
+    pub fn new(rng: R) -> Self {
+        Self {
+            rng,
+            counts: RngCounts::default(),
+        }
+    }
+
+    pub fn counts(&self) -> RngCounts {
+        self.counts
+    }
+}
+
+impl<R: RngCore> RngCore for CountingRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        self.counts.next_u32 += 1;
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.counts.next_u64 += 1;
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.counts.fill_bytes += 1;
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.counts.fill_bytes += 1;
+        self.rng.try_fill_bytes(dest)
     }
 }
 
-pub fn deserialize_daily_orders_distribution<'de, D: Deserializer<'de>>(
+impl From<f64> for KahanSum {
+    fn from(value: f64) -> Self {
+        Self {
+            sum: value,
+            compensation: 0.0,
+        }
+    }
+}
+
+/**
+ * Returns the min, p25, median, p75 and max of the values,
+ * interpolating linearly between the closest ranks.
+ * All zeros when there are no values.
+ */
+pub fn percentiles(mut values: Vec<f64>) -> [f64; 5] {
+    if values.is_empty() {
+        return [0.0; 5];
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+
+    [0.0, 0.25, 0.5, 0.75, 1.0].map(|q| {
+        let rank = q * (values.len() - 1) as f64;
+        let lower = rank.floor() as usize;
+        let upper = rank.ceil() as usize;
+        let fraction = rank - lower as f64;
+        values[lower] + (values[upper] - values[lower]) * fraction
+    })
+}
+
+/**
+ * Returns the Gini coefficient of the values: 0 when all of them
+ * are equal, approaching 1 when a single value holds everything.
+ * Zero when there are no values or they sum up to zero.
+ */
+pub fn gini(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let n = sorted.len() as f64;
+    let total: f64 = sorted.iter().sum();
+    if sorted.is_empty() || total <= 0.0 {
+        return 0.0;
+    }
+
+    let weighted: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (i + 1) as f64 * value)
+        .sum();
+    2.0 * weighted / (n * total) - (n + 1.0) / n
+}
+
+/**
+ * How numbers are written in the text output.
+ * The default writes them as they are,
+ * with monetary amounts rounded to two decimals.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NumberFormat {
+    /** Groups the digits in thousands, as in `123,456,789`. */
+    pub separators: bool,
+    /** Shortens large numbers with SI suffixes, as in `123.5M`. */
+    pub si_suffixes: bool,
+    /** Written in front of monetary amounts. */
+    pub currency_symbol: String,
+}
+
+/** Suffixes of consecutive powers of a thousand. */
+const SI_SUFFIXES: [&str; 7] = ["", "k", "M", "G", "T", "P", "E"];
+
+impl NumberFormat {
+    pub fn count(&self, value: usize) -> String {
+        if self.si_suffixes && value >= 1000 {
+            with_si_suffix(value as f64)
+        } else if self.separators || self.si_suffixes {
+            group_thousands(&value.to_string())
+        } else {
+            value.to_string()
+        }
+    }
+
+    /**
+     * Writes the amount with two decimals after the currency symbol,
+     * and the minus sign in front of both.
+     */
+    pub fn money(&self, value: f64) -> String {
+        // Rounded to cents first, so that an amount printed as
+        // zero has no sign and one printed as 1,000.00 gets a suffix.
+        let value = (value * 100.0).round() / 100.0;
+        let sign = if value < 0.0 { "-" } else { "" };
+        let value = value.abs();
+        let amount = if self.si_suffixes && value >= 1000.0 {
+            with_si_suffix(value)
+        } else if self.separators || self.si_suffixes {
+            group_thousands(&format!("{:.2}", value))
+        } else {
+            format!("{:.2}", value)
+        };
+        format!("{}{}{}", sign, self.currency_symbol, amount)
+    }
+}
+
+/**
+ * Writes a non-negative value with one decimal and the suffix
+ * of the largest power of a thousand not above it,
+ * moving on to the next suffix when rounding reaches a thousand.
+ */
+fn with_si_suffix(value: f64) -> String {
+    let mut scaled = value;
+    let mut power = 0;
+    while power + 1 < SI_SUFFIXES.len()
+        && (scaled * 10.0).round() / 10.0 >= 1000.0
+    {
+        scaled /= 1000.0;
+        power += 1;
+    }
+    format!("{:.1}{}", scaled, SI_SUFFIXES[power])
+}
+
+/** Puts a comma between each group of three digits of the integer part. */
+fn group_thousands(number: &str) -> String {
+    let (integer, fraction) = match number.find('.') {
+        Some(dot) => number.split_at(dot),
+        None => (number, ""),
+    };
+    let mut grouped = String::with_capacity(number.len() + integer.len() / 3);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped.push_str(fraction);
+    grouped
+}
+
+/**
+ * 64-bit FNV-1a hasher. Unlike `DefaultHasher`, its output
+ * is the same across Rust versions and platforms.
+ */
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/**
+ * Turns a description taken from a block doc comment
+ * into plain text by dropping the leading asterisks
+ * and joining the lines.
+ */
+pub fn tidy_schema_description(schema: &mut Schema) {
+    if let Some(serde_json::Value::String(description)) =
+        schema.get_mut("description")
+    {
+        *description = description
+            .lines()
+            .map(|line| line.trim().trim_start_matches('*').trim())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+}
+
+pub fn deserialize_daily_orders_expr<'de, D: Deserializer<'de>>(
     de: D,
-) -> Result<DailyOrdersDistribution, D::Error> {
+) -> Result<DailyOrdersExpr, D::Error> {
     let expr = String::deserialize(de)?;
-    let mut distribution = [0; HOURS_IN_DAY];
-    for hour in 0..HOURS_IN_DAY {
-        distribution[hour] =
-            eval_expr(&expr, "h", hour).map_err(de::Error::custom)?;
-    }
-    Ok(distribution)
+    DailyOrdersExpr::new(expr).map_err(de::Error::custom)
+}
+
+pub fn deserialize_optional_daily_orders_expr<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Option<DailyOrdersExpr>, D::Error> {
+    Option::<String>::deserialize(de)?
+        .map(|expr| DailyOrdersExpr::new(expr).map_err(de::Error::custom))
+        .transpose()
 }
 
+/**
+ * Accepts either an expression of the day `d` or, as written
+ * by `serialize_daily_multipliers`, the multipliers themselves.
+ */
 pub fn deserialize_daily_multipliers<'de, D: Deserializer<'de>>(
     de: D,
 ) -> Result<DailyMultipliers, D::Error> {
-    let expr = String::deserialize(de)?;
-    let mut distribution = [0; DAYS_IN_YEAR];
-    for day in 0..DAYS_IN_YEAR {
-        distribution[day] =
-            eval_expr(&expr, "d", day).map_err(de::Error::custom)?;
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMultipliers {
+        Expr(String),
+        Values(Vec<f64>),
+    }
+
+    let expr = match RawMultipliers::deserialize(de)? {
+        RawMultipliers::Expr(expr) => expr,
+        RawMultipliers::Values(values) => {
+            if values
+                .iter()
+                .any(|value| !value.is_finite() || *value < 0.0)
+            {
+                return Err(de::Error::custom(
+                    "daily multipliers must be non-negative",
+                ));
+            }
+            return values.try_into().map_err(|values: Vec<f64>| {
+                de::Error::invalid_length(
+                    values.len(),
+                    &"a multiplier for each day of a year",
+                )
+            });
+        }
+    };
+    let expr = CompiledExpr::new(expr).map_err(de::Error::custom)?;
+    let mut context = HashMapContext::new();
+    let mut distribution = [0.0; DAYS_IN_YEAR];
+    for (day, multiplier) in distribution.iter_mut().enumerate() {
+        *multiplier = expr
+            .eval_non_negative(&mut context, &[("d", (day as i64).into())])
+            .map_err(de::Error::custom)?;
     }
     Ok(distribution)
 }
 
-fn eval_expr(
+pub fn serialize_daily_multipliers<S: Serializer>(
+    multipliers: &DailyMultipliers,
+    ser: S,
+) -> Result<S::Ok, S::Error> {
+    ser.collect_seq(multipliers)
+}
+
+pub fn deserialize_optional_daily_multipliers<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Option<DailyMultipliers>, D::Error> {
+    #[derive(Deserialize)]
+    struct Multipliers(
+        #[serde(deserialize_with = "deserialize_daily_multipliers")]
+        DailyMultipliers,
+    );
+
+    Ok(Option::<Multipliers>::deserialize(de)?
+        .map(|Multipliers(multipliers)| multipliers))
+}
+
+pub fn serialize_optional_daily_multipliers<S: Serializer>(
+    multipliers: &Option<DailyMultipliers>,
+    ser: S,
+) -> Result<S::Ok, S::Error> {
+    multipliers
+        .as_ref()
+        .map(|multipliers| &multipliers[..])
+        .serialize(ser)
+}
+
+/**
+ * Accepts either a policy or, for backward compatibility,
+ * a bare number of days of a periodic policy.
+ */
+pub fn deserialize_withdrawal_policy<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<WithdrawalPolicy, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawPolicy {
+        Days(usize),
+        Policy(WithdrawalPolicy),
+    }
+
+    let policy = match RawPolicy::deserialize(de)? {
+        RawPolicy::Days(days) => WithdrawalPolicy::Periodic { days },
+        RawPolicy::Policy(policy) => policy,
+    };
+    match policy {
+        WithdrawalPolicy::Periodic { days: 0 }
+        | WithdrawalPolicy::Combined { days: 0, .. } => Err(de::Error::custom(
+            "withdrawal_period_in_days must be positive",
+        )),
+        WithdrawalPolicy::Hourly { hours: 0 } => Err(de::Error::custom(
+            "withdrawal_period_in_hours must be positive",
+        )),
+        WithdrawalPolicy::Threshold { amount }
+        | WithdrawalPolicy::Combined { amount, .. }
+            if amount.is_nan() || amount <= 0.0 =>
+        {
+            Err(de::Error::custom("withdrawal threshold must be positive"))
+        }
+        policy => Ok(policy),
+    }
+}
+
+pub fn deserialize_optional_withdrawal_policy<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Option<WithdrawalPolicy>, D::Error> {
+    deserialize_withdrawal_policy(de).map(Some)
+}
+
+/**
+ * Accepts either a fee or, for backward compatibility,
+ * a bare flat fee per transaction.
+ */
+pub fn deserialize_withdrawal_fee<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<WithdrawalFee, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawFee {
+        PerTransaction(f64),
+        Fee(WithdrawalFee),
+    }
+
+    let fee = match RawFee::deserialize(de)? {
+        RawFee::PerTransaction(per_tx) => WithdrawalFee::Flat { per_tx },
+        RawFee::Fee(fee) => fee,
+    };
+    fee.validate().map_err(de::Error::custom)?;
+    Ok(fee)
+}
+
+pub fn deserialize_simulated_currencies_number<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<usize, D::Error> {
+    match usize::deserialize(de)? {
+        0 => Err(de::Error::custom(
+            "simulated_currencies_number must be positive",
+        )),
+        currencies => Ok(currencies),
+    }
+}
+
+pub fn deserialize_distribution<'de, D: Deserializer<'de>>(
+    de: D,
+) -> Result<Distribution, D::Error> {
+    let distribution = Distribution::deserialize(de)?;
+    distribution.validate().map_err(de::Error::custom)?;
+    Ok(distribution)
+}
+
+/**
+ * Evaluates `expr` once with the given variable bound and rounds
+ * the result. Like the expressions of the config, which are
+ * compiled instead, negative and non-finite values are rejected.
+ */
+pub fn eval_expr(
     expr: &str,
     var_name: &str,
     var_value: usize,
-) -> Result<usize, EvalexprError> {
-    let mut context = HashMapContext::new();
-    context.set_value(var_name.into(), (var_value as i64).into())?;
-
-    let value = eval_number_with_context(expr.into(), &context)?;
+) -> Result<usize, String> {
+    let value = CompiledExpr::new(expr)?.eval_non_negative(
+        &mut HashMapContext::new(),
+        &[(var_name, (var_value as i64).into())],
+    )?;
 
     Ok(value.round() as usize)
 }
 
+/**
+ * An expression parsed once, so that evaluating it
+ * for many values of its variables skips the parsing.
+ */
+#[derive(Clone, PartialEq)]
+pub struct CompiledExpr {
+    expr: String,
+    tree: Node,
+}
+
+impl fmt::Debug for CompiledExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("CompiledExpr").field(&self.expr).finish()
+    }
+}
+
+impl CompiledExpr {
+    pub fn new(expr: impl Into<String>) -> Result<Self, String> {
+        let expr = expr.into();
+        let tree = build_operator_tree(&expr).map_err(|err| err.to_string())?;
+        Ok(Self { expr, tree })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /**
+     * Returns whether the expression references the variable `var_name`.
+     */
+    pub fn uses_variable(&self, var_name: &str) -> bool {
+        self.tree
+            .iter_variable_identifiers()
+            .any(|identifier| identifier == var_name)
+    }
+
+    /**
+     * Binds the variables in `context`, which may be reused
+     * between calls, and evaluates the expression.
+     * Rejects negative or non-finite values.
+     */
+    pub fn eval_non_negative(
+        &self,
+        context: &mut HashMapContext,
+        vars: &[(&str, Value)],
+    ) -> Result<f64, String> {
+        for (name, value) in vars {
+            context
+                .set_value((*name).into(), value.clone())
+                .map_err(|err| err.to_string())?;
+        }
+        let value = self
+            .tree
+            .eval_number_with_context(context)
+            .map_err(|err| err.to_string())?;
+        if !value.is_finite() || value < 0.0 {
+            let vars: Vec<_> = vars
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            return Err(format!(
+                "expression \"{}\" must be non-negative, got {} for {}",
+                self.expr,
+                value,
+                vars.join(", ")
+            ));
+        }
+
+        Ok(value)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_create_eval_expr() {
+        // The result of the original expression is negative,
+        // which is now rejected instead of being cast to zero.
+        assert!(eval_expr("math::exp(x * x) * math::sin(x)", "x", 5).is_err());
+
         let value =
-            eval_expr("math::exp(x * x) * math::sin(x)", "x", 5).unwrap();
+            eval_expr("math::exp(x * x) * math::cos(x)", "x", 5).unwrap();
         let x: f64 = 5.0;
-        let expected_value = x.powi(2).exp() * x.sin();
+        let expected_value = x.powi(2).exp() * x.cos();
         assert_eq!(value, expected_value.round() as usize);
     }
+
+    fn eval_number(
+        expr: &str,
+        vars: &[(&str, Value)],
+    ) -> Result<f64, EvalexprError> {
+        let mut context = HashMapContext::new();
+        for (name, value) in vars {
+            context.set_value((*name).into(), value.clone())?;
+        }
+
+        eval_number_with_context(expr, &context)
+    }
+
+    #[derive(Deserialize)]
+    struct DistributionWrapper {
+        #[serde(deserialize_with = "deserialize_distribution")]
+        distribution: Distribution,
+    }
+
+    fn parse_distribution(json: &str) -> serde_json::Result<Distribution> {
+        let json = format!("{{\"distribution\": {}}}", json);
+        serde_json::from_str::<DistributionWrapper>(&json)
+            .map(|wrapper| wrapper.distribution)
+    }
+
+    #[test]
+    fn test_deserialize_distribution() {
+        assert_eq!(
+            parse_distribution(r#"{"type": "normal", "mean": 1, "std": 2}"#)
+                .unwrap(),
+            Distribution::Normal {
+                mean: 1.0,
+                std: 2.0
+            }
+        );
+        assert_eq!(
+            parse_distribution(
+                r#"{"type": "lognormal", "mu": 0.5, "sigma": 0.1}"#
+            )
+            .unwrap(),
+            Distribution::LogNormal {
+                mu: 0.5,
+                sigma: 0.1
+            }
+        );
+        assert_eq!(
+            parse_distribution(r#"{"type": "uniform", "low": 1, "high": 3}"#)
+                .unwrap(),
+            Distribution::Uniform {
+                low: 1.0,
+                high: 3.0
+            }
+        );
+        assert_eq!(
+            parse_distribution(r#"{"type": "exponential", "lambda": 0.5}"#)
+                .unwrap(),
+            Distribution::Exponential { lambda: 0.5 }
+        );
+        assert!(parse_distribution(
+            r#"{"type": "uniform", "low": 3, "high": 1}"#
+        )
+        .is_err());
+        assert!(
+            parse_distribution(r#"{"type": "exponential", "lambda": 0}"#)
+                .is_err()
+        );
+        assert!(parse_distribution(
+            r#"{"type": "normal", "mean": -1, "std": 2}"#
+        )
+        .is_err());
+        assert!(parse_distribution(
+            r#"{"type": "normal", "mean": 1, "std": 0}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_distribution_samples_are_positive() {
+        use rand::{rngs::SmallRng, SeedableRng};
+        use rand_distr::Distribution as _;
+
+        let distributions = [
+            Distribution::Normal {
+                mean: -1.0,
+                std: 2.0,
+            },
+            Distribution::LogNormal {
+                mu: 0.0,
+                sigma: 1.0,
+            },
+            Distribution::Uniform {
+                low: 0.5,
+                high: 1.0,
+            },
+            Distribution::Exponential { lambda: 2.0 },
+        ];
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        for distribution in distributions {
+            assert!(distribution
+                .sample_iter(&mut rng)
+                .take(1000)
+                .all(|value| value > 0.0));
+        }
+    }
+
+    #[test]
+    fn test_welford_matches_batch_variance() {
+        let values: Vec<f64> = (0..1000)
+            .map(|i| 1e6 + ((i * 37) % 101) as f64 / 7.0)
+            .collect();
+        let mut welford = Welford::default();
+        for &value in &values {
+            welford.add(value);
+        }
+
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance =
+            values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        assert_eq!(welford.count(), values.len());
+        assert!((welford.mean() - mean).abs() < 1e-9);
+        assert!((welford.sample_variance() - variance).abs() < 1e-9);
+
+        let mut single = Welford::default();
+        assert_eq!(single.mean(), 0.0);
+        single.add(3.0);
+        assert_eq!(single.mean(), 3.0);
+        assert_eq!(single.sample_variance(), 0.0);
+    }
+
+    #[test]
+    fn test_eval_expr_rejects_negative() {
+        assert_eq!(eval_expr("x * 2", "x", 3).unwrap(), 6);
+        assert!(eval_expr("-5", "x", 3).is_err());
+        assert!(eval_expr("x - 100", "x", 3).is_err());
+        assert!(eval_expr("math::ln(x)", "x", 0).is_err());
+    }
+
+    #[test]
+    fn test_compiled_expr_matches_string_eval() {
+        let expr = "max(math::exp(-(d - 60)^2 / 1000), \
+                    math::exp(-(d - 200)^2 / 1000)) * 10 + d / 7 + 1";
+        let compiled = CompiledExpr::new(expr).unwrap();
+        let mut context = HashMapContext::new();
+        for day in 0..DAYS_IN_YEAR {
+            let vars = [("d", Value::from(day as i64))];
+            assert_eq!(
+                compiled.eval_non_negative(&mut context, &vars).unwrap(),
+                eval_number(expr, &vars).unwrap(),
+                "day {}",
+                day
+            );
+        }
+    }
+
+    #[test]
+    fn test_f64_as_key_try_new() {
+        assert_eq!(F64AsKey::try_new(1.5).map(|key| key.inner()), Ok(1.5));
+        assert_eq!(F64AsKey::try_new(f64::NAN), Err(NanError));
+    }
+
+    #[test]
+    fn test_f64_as_key_treats_negative_zero_as_zero() {
+        let hash = |key: F64AsKey| {
+            let mut hasher = StableHasher::default();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        let zero = F64AsKey::new(0.0);
+        let negative_zero = F64AsKey::new(-0.0);
+        assert_eq!(zero, negative_zero);
+        assert_eq!(zero.cmp(&negative_zero), Ordering::Equal);
+        assert_eq!(hash(zero), hash(negative_zero));
+        assert_eq!(negative_zero.inner().to_bits(), 0.0f64.to_bits());
+
+        let one = F64AsKey::new(1.0);
+        assert_eq!(one.cmp(&F64AsKey::new(1.0)), Ordering::Equal);
+        assert_eq!(hash(one), hash(F64AsKey::new(1.0)));
+    }
+
+    #[test]
+    fn test_f64_as_key_orders_as_numbers() {
+        let mut keys: Vec<_> = [2.5, -0.0, -1.0, f64::INFINITY, 0.0, -3.5]
+            .into_iter()
+            .map(F64AsKey::new)
+            .collect();
+        keys.sort();
+        let values: Vec<f64> = keys.into_iter().map(f64::from).collect();
+        assert_eq!(values, [-3.5, -1.0, 0.0, 0.0, 2.5, f64::INFINITY]);
+        assert!(F64AsKey::new(-0.0) < F64AsKey::new(f64::MIN_POSITIVE));
+        assert!(F64AsKey::new(-f64::MIN_POSITIVE) < F64AsKey::new(-0.0));
+    }
+
+    #[test]
+    fn test_percentiles() {
+        assert_eq!(
+            percentiles(vec![4.0, 0.0, 2.0, 1.0, 3.0]),
+            [0.0, 1.0, 2.0, 3.0, 4.0]
+        );
+        assert_eq!(percentiles(vec![0.0, 10.0]), [0.0, 2.5, 5.0, 7.5, 10.0]);
+        assert_eq!(percentiles(vec![]), [0.0; 5]);
+    }
+
+    #[test]
+    fn test_stable_hasher() {
+        let mut hasher = StableHasher::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_gini_of_equal_values() {
+        assert!(gini(&[5.0; 100]).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_gini_without_money() {
+        assert_eq!(gini(&[]), 0.0);
+        assert_eq!(gini(&[0.0; 10]), 0.0);
+    }
+
+    #[test]
+    fn test_gini_of_single_holder() {
+        let mut values = vec![0.0; 999];
+        values.push(100.0);
+        assert!((gini(&values) - 0.999).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_kahan_sum_is_closer_to_exact_total() {
+        let steps = 1_000_000;
+        let amount = 1e-4;
+        let exact = 1e6 + steps as f64 * amount;
+
+        let mut naive = 1e6;
+        let mut kahan = KahanSum::from(1e6);
+        for _ in 0..steps {
+            naive += amount;
+            kahan += amount;
+        }
+
+        let naive_error = (naive - exact).abs();
+        let kahan_error = (kahan.value() - exact).abs();
+        assert!(kahan_error < 1e-9, "{}", kahan_error);
+        assert!(kahan_error < naive_error / 1000.0, "{}", naive_error);
+    }
+
+    #[test]
+    fn test_counting_rng_passes_draws_through() {
+        use rand::{rngs::SmallRng, SeedableRng};
+
+        let mut plain = SmallRng::seed_from_u64(0);
+        let mut counting = CountingRng::new(SmallRng::seed_from_u64(0));
+        for _ in 0..3 {
+            assert_eq!(counting.next_u32(), plain.next_u32());
+        }
+        assert_eq!(counting.next_u64(), plain.next_u64());
+        let (mut bytes, mut expected) = ([0; 5], [0; 5]);
+        counting.fill_bytes(&mut bytes);
+        plain.fill_bytes(&mut expected);
+        assert_eq!(bytes, expected);
+
+        assert_eq!(
+            counting.counts(),
+            RngCounts {
+                next_u32: 3,
+                next_u64: 1,
+                fill_bytes: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_number_format() {
+        let plain = NumberFormat::default();
+        assert_eq!(plain.count(123456789), "123456789");
+        assert_eq!(plain.money(1234.5), "1234.50");
+
+        let separated = NumberFormat {
+            separators: true,
+            currency_symbol: "$".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(separated.count(0), "0");
+        assert_eq!(separated.count(999), "999");
+        assert_eq!(separated.count(1000), "1,000");
+        assert_eq!(separated.count(123456789), "123,456,789");
+        assert_eq!(separated.money(0.5), "$0.50");
+        assert_eq!(separated.money(1234567.891), "$1,234,567.89");
+        assert_eq!(separated.money(-1234.5), "-$1,234.50");
+
+        let si = NumberFormat {
+            si_suffixes: true,
+            ..Default::default()
+        };
+        assert_eq!(si.count(999), "999");
+        assert_eq!(si.count(1500), "1.5k");
+        assert_eq!(si.count(123456789), "123.5M");
+        assert_eq!(si.count(999_960), "1.0M");
+        assert_eq!(si.count(7_200_000_000), "7.2G");
+        assert_eq!(si.money(999.99), "999.99");
+        assert_eq!(si.money(999.999), "1.0k");
+        assert_eq!(si.money(1000.0), "1.0k");
+        assert_eq!(plain.money(-0.001), "0.00");
+        assert_eq!(separated.money(-0.004), "$0.00");
+        assert_eq!(si.money(2_500_000.0), "2.5M");
+    }
 }
@@ -0,0 +1,76 @@
+use hex_sim::{
+    run_simulation, run_simulation_streaming, DailyOrdersExpr, PoolKind,
+    SimConfig,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/** Tracks the live and the peak number of heap bytes. */
+struct CountingAllocator;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed)
+                + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/** Peak heap bytes allocated on top of what was live before `f`. */
+fn peak_bytes(f: impl FnOnce()) -> usize {
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+#[test]
+fn test_streaming_bounds_peak_memory() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.json");
+    let mut config: SimConfig =
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    config.simulated_years_number = 1;
+    config.warmup_years = 0;
+    config.simulated_shops_number = 10;
+    config.default_daily_distribution = DailyOrdersExpr::new("5").unwrap();
+    let pools = [PoolKind::PoolPerShop];
+
+    let mut materialized = None;
+    let materialized_peak = peak_bytes(|| {
+        materialized = Some(run_simulation(&config, 3, &pools).unwrap());
+    });
+    let mut streamed = None;
+    let streaming_peak = peak_bytes(|| {
+        streamed =
+            Some(run_simulation_streaming(&config, 3, &pools, &()).unwrap());
+    });
+
+    assert_eq!(
+        streamed.unwrap().total_number_of_transactions,
+        materialized.unwrap().total_number_of_transactions
+    );
+    assert!(
+        streaming_peak * 4 < materialized_peak,
+        "streaming peak {} bytes, materialized peak {} bytes",
+        streaming_peak,
+        materialized_peak
+    );
+}